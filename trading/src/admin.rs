@@ -0,0 +1,239 @@
+/// Author: Tomasz Kulik
+/// 
+///
+
+use crate::config::AdminConfig;
+use crate::ledger::ProductSnapshot;
+use crate::listener::{Peer, Socket};
+use crate::order::{Timestamp, UserId};
+use crate::protocol::Protocol;
+use crate::server::Event;
+use crate::transaction::Product;
+use ring::hmac;
+use serde::Serialize;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::mpsc::Sender;
+use tokio::sync::oneshot;
+use tracing::{info, info_span, warn, Instrument};
+
+/// A command sent by an operator.
+///
+/// The accepted commands are:
+/// - `USERS` - list the connected users with their stats
+/// - `DISCONNECT:<USER_ID>` - close the user's connection
+/// - `CANCEL_ALL:USER:<USER_ID>` - cancel all the user's orders
+/// - `CANCEL_ALL:PRODUCT:<PRODUCT>` - cancel all the orders of a product
+/// - `HALT:<PRODUCT>` - stop trading in the product
+/// - `RESUME:<PRODUCT>` - resume trading in the halted product
+/// - `AUCTION:<PRODUCT>` - only collect the product's orders
+/// - `OPEN:<PRODUCT>` - uncross the auction-only product and
+///   follow the market's phase again
+/// - `SNAPSHOT` - write the state of the ledger to a file
+/// - `STATEMENTS` - list the users' gross, fees and net
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdminCommand {
+    Users,
+    Disconnect(UserId),
+    CancelUserOrders(UserId),
+    CancelProductOrders(Product),
+    Halt(Product),
+    Resume(Product),
+    AuctionOnly(Product),
+    Open(Product),
+    Snapshot,
+    Statements,
+}
+
+impl std::str::FromStr for AdminCommand {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<AdminCommand, String> {
+        let user_id = |id: &str| {
+            id.parse::<UserId>()
+                .map_err(|_| format!("Invalid user ID: {}", id))
+        };
+        let fields: Vec<&str> = input.split(':').collect();
+        match fields[..] {
+            ["USERS"] => Ok(AdminCommand::Users),
+            ["DISCONNECT", id] => Ok(AdminCommand::Disconnect(user_id(id)?)),
+            ["CANCEL_ALL", "USER", id] => Ok(AdminCommand::CancelUserOrders(user_id(id)?)),
+            ["CANCEL_ALL", "PRODUCT", product] => {
+                Ok(AdminCommand::CancelProductOrders(product.parse()?))
+            }
+            ["HALT", product] => Ok(AdminCommand::Halt(product.parse()?)),
+            ["RESUME", product] => Ok(AdminCommand::Resume(product.parse()?)),
+            ["AUCTION", product] => Ok(AdminCommand::AuctionOnly(product.parse()?)),
+            ["OPEN", product] => Ok(AdminCommand::Open(product.parse()?)),
+            ["SNAPSHOT"] => Ok(AdminCommand::Snapshot),
+            ["STATEMENTS"] => Ok(AdminCommand::Statements),
+            _ => Err(format!("Unknown admin command: {}", input)),
+        }
+    }
+}
+
+/// The result of an admin command executed by the event handler.
+#[derive(Debug)]
+pub enum AdminReply {
+    /// Lines sent back to the operator.
+    Lines(Vec<String>),
+    /// The state of the market to be written to a file.
+    Snapshot(Snapshot),
+}
+
+/// The state of the market at a given point in time.
+#[derive(Debug, Serialize)]
+pub struct Snapshot {
+    pub timestamp: Timestamp,
+    pub products: Vec<ProductSnapshot>,
+}
+
+/// Accepts the operators' connections
+///
+/// Every command is executed by the event handler, so
+/// it's ordered consistently with the users' orders.
+pub(crate) async fn admin_handler(
+    config: Option<AdminConfig>,
+    event_notification_sender: Sender<Event>,
+) -> anyhow::Result<()> {
+    let config = match config {
+        Some(config) => config,
+        None => return Ok(()),
+    };
+    let socket = Socket::bind(&config.listen).await?;
+    info!(admin = %config.listen, "listening");
+    let mut connections = 0;
+    loop {
+        let (stream, peer) = socket.accept(&mut connections).await?;
+        spawn_connection(stream, peer, &config, &event_notification_sender);
+    }
+}
+
+fn spawn_connection<S>(
+    stream: S,
+    peer: Peer,
+    config: &AdminConfig,
+    event_notification_sender: &Sender<Event>,
+) where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let span = info_span!("admin", %peer);
+    let config = config.clone();
+    let event_notification_sender = event_notification_sender.clone();
+    tokio::spawn(
+        async move {
+            info!("operator connected");
+            if let Err(e) = handle_connection(stream, config, event_notification_sender).await {
+                warn!(error = %e, "connection error");
+            }
+            info!("operator disconnected");
+        }
+        .instrument(span),
+    );
+}
+
+/// Reads the `AUTH:<TOKEN>` line, limited like the users' lines,
+/// and compares the token in constant time, so its prefix can't be
+/// guessed by the timing of the answers. Both tokens are signed
+/// with the same key, and `hmac::verify` compares the signatures.
+pub(crate) async fn authorized<R>(reader: &mut R, token: &str) -> std::io::Result<bool>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut line = vec![];
+    if !Protocol::Text.read(reader, &mut line).await? {
+        return Ok(false);
+    }
+    Ok(match line.strip_prefix(b"AUTH:") {
+        Some(sent) => {
+            let key = hmac::Key::new(hmac::HMAC_SHA256, &[]);
+            let tag = hmac::sign(&key, token.as_bytes());
+            hmac::verify(&key, sent, tag.as_ref()).is_ok()
+        }
+        None => false,
+    })
+}
+
+/// Handles a single operator's connection
+///
+/// The first line has to be `AUTH:<TOKEN>`. Every command is
+/// answered with zero or more lines followed by `OK`, or by
+/// a single `ERROR:<REASON>` line.
+async fn handle_connection<S>(
+    stream: S,
+    config: AdminConfig,
+    event_notification_sender: Sender<Event>,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader);
+
+    if !authorized(&mut reader, &config.token).await? {
+        warn!("operator not authorized");
+        writer.write_all(b"ERROR:unauthorized\n").await?;
+        return Ok(());
+    }
+    writer.write_all(b"OK\n").await?;
+    let mut lines = reader.lines();
+
+    while let Some(line) = lines.next_line().await? {
+        let result = match line.trim().parse::<AdminCommand>() {
+            Ok(command) => {
+                info!(?command, "admin command");
+                let (reply_sender, reply_receiver) = oneshot::channel();
+                event_notification_sender
+                    .send(Event::Admin(command, reply_sender))
+                    .await?;
+                reply_receiver.await?
+            }
+            Err(e) => Err(e),
+        };
+        let response = match result {
+            Ok(AdminReply::Lines(lines)) => lines
+                .into_iter()
+                .chain(std::iter::once("OK".to_string()))
+                .collect(),
+            Ok(AdminReply::Snapshot(snapshot)) => match write_snapshot(&config, &snapshot) {
+                Ok(path) => vec![format!("SNAPSHOT:{}", path), "OK".to_string()],
+                Err(e) => vec![format!("ERROR:{}", e)],
+            },
+            Err(reason) => vec![format!("ERROR:{}", reason)],
+        };
+        for line in response {
+            writer.write_all(format!("{}\n", line).as_bytes()).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes the snapshot as a JSON file and returns its path.
+fn write_snapshot(config: &AdminConfig, snapshot: &Snapshot) -> anyhow::Result<String> {
+    let path = config
+        .snapshot_dir
+        .join(format!("snapshot-{}.json", snapshot.timestamp));
+    std::fs::write(&path, serde_json::to_string_pretty(snapshot)?)?;
+    info!(path = %path.display(), "snapshot written");
+    Ok(path.display().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::MAX_LINE;
+
+    #[tokio::test]
+    async fn test_authorized() {
+        let check =
+            |input: String| async move { authorized(&mut input.as_bytes(), "secret").await };
+        assert!(check("AUTH:secret\n".to_string()).await.unwrap());
+        assert!(check("AUTH:secret\r\n".to_string()).await.unwrap());
+        assert!(!check("AUTH:secre\n".to_string()).await.unwrap());
+        assert!(!check("AUTH:secrets\n".to_string()).await.unwrap());
+        assert!(!check("secret\n".to_string()).await.unwrap());
+        assert!(!check(String::new()).await.unwrap());
+        assert!(check(format!("AUTH:{}\n", "A".repeat(MAX_LINE)))
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,197 @@
+/// Author: Tomasz Kulik
+/// 
+///
+
+use anyhow::anyhow;
+use ring::digest::{digest, SHA256};
+use std::io::BufRead;
+use std::path::Path;
+
+/// The hash the first record of the journal is chained to.
+pub(crate) const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// The SHA-256 of the journal line, without the line break,
+/// in lowercase hex.
+pub(crate) fn hash(line: &str) -> String {
+    digest(&SHA256, line.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Whether the field is a hash written by `hash`.
+pub(crate) fn is_hash(field: &str) -> bool {
+    field.len() == GENESIS.len()
+        && field
+            .bytes()
+            .all(|byte| byte.is_ascii_digit() || (b'a'..=b'f').contains(&byte))
+}
+
+/// The hash the next record appended to the journal is chained
+/// to, the one of its last line.
+///
+/// The journal which isn't chained all the way, e.g. written
+/// before the records were chained, is not continued, as the
+/// new records wouldn't prove anything about the old ones.
+pub(crate) fn last_hash(path: &Path) -> anyhow::Result<String> {
+    let file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(GENESIS.to_string()),
+        Err(e) => return Err(e.into()),
+    };
+    match verify_audit(std::io::BufReader::new(file), None) {
+        Ok(audit) => Ok(audit.head),
+        Err(e) => Err(anyhow!("Unable to continue {}: {}", path.display(), e)),
+    }
+}
+
+/// The journal verified by `verify_audit`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Audit {
+    pub records: u64,
+    /// The hash of the last line, which the next record is
+    /// chained to.
+    pub head: String,
+}
+
+/// Walks the hash chain of the journal written by the server
+///
+/// Every line ends with the hash of the line before it, the first
+/// one with zeros, so editing, removing or inserting any record
+/// breaks the link of the line that follows it. Returns the number
+/// of the records and the head of the chain, or the error naming
+/// the first broken link.
+///
+/// Removing or rewriting the last records leaves a valid chain
+/// behind, so it's only detected against the `checkpoint`, a head
+/// kept outside of the journal, e.g. the one logged by the server
+/// with `journal checkpoint` or printed by the earlier audit. The
+/// journal has to contain the line hashing to it.
+pub fn verify_audit(input: impl BufRead, checkpoint: Option<&str>) -> anyhow::Result<Audit> {
+    let mut previous = GENESIS.to_string();
+    let mut records = 0;
+    let mut checkpoint = checkpoint.filter(|checkpoint| *checkpoint != GENESIS);
+    for (number, line) in input.lines().enumerate() {
+        let line = line?;
+        match line.rsplit_once(' ') {
+            Some((_, chained)) if chained == previous => {}
+            Some((_, chained)) if is_hash(chained) => {
+                return Err(anyhow!(
+                    "line {}: broken link, the previous record hashes to {}, not {}",
+                    number + 1,
+                    previous,
+                    chained
+                ))
+            }
+            _ => return Err(anyhow!("line {}: the record is not chained", number + 1)),
+        }
+        previous = hash(&line);
+        records += 1;
+        if checkpoint == Some(previous.as_str()) {
+            checkpoint = None;
+        }
+    }
+    match checkpoint {
+        Some(checkpoint) => Err(anyhow!(
+            "the checkpoint {} is missing, the journal was truncated or rewritten",
+            checkpoint
+        )),
+        None => Ok(Audit {
+            records,
+            head: previous,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::journal::{Entry, Journal};
+    use crate::transaction::Product;
+
+    #[test]
+    fn test_hash_chain() {
+        let path = std::env::temp_dir().join(format!("trading-audit-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        // The chain is continued by the next run.
+        for product in [Product::Apple, Product::Pear].iter() {
+            let journal = Journal::open(&path).unwrap();
+            journal.write(1000, Entry::Halt(*product));
+            journal.write(1001, Entry::Resume(*product));
+            journal.sync().blocking_recv().unwrap();
+        }
+        let journal = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = journal.lines().collect();
+        // Every run starts with its own record.
+        assert!(lines[0].ends_with(&format!(" - START {}", GENESIS)));
+        assert_eq!(lines[1], format!("1000 - HALT:APPLE {}", hash(lines[0])));
+        assert_eq!(lines[2], format!("1001 - RESUME:APPLE {}", hash(lines[1])));
+        assert!(lines[3].ends_with(&format!(" - START {}", hash(lines[2]))));
+        assert_eq!(lines[4], format!("1000 - HALT:PEAR {}", hash(lines[3])));
+        let audit = verify_audit(journal.as_bytes(), None).unwrap();
+        assert_eq!(audit.records, 6);
+        assert_eq!(audit.head, hash(lines[5]));
+        assert_eq!(
+            lines[2].parse::<crate::journal::Record>().unwrap().entry,
+            Entry::Resume(Product::Apple)
+        );
+
+        let edited = journal.replacen("RESUME:APPLE", "HALT:APPLE", 1);
+        assert_eq!(
+            verify_audit(edited.as_bytes(), None)
+                .unwrap_err()
+                .to_string(),
+            format!(
+                "line 4: broken link, the previous record hashes to {}, not {}",
+                hash(&lines[2].replacen("RESUME", "HALT", 1)),
+                hash(lines[2])
+            )
+        );
+        let removed = format!("{}\n{}\n{}\n", lines[0], lines[2], lines[3]);
+        assert!(verify_audit(removed.as_bytes(), None)
+            .unwrap_err()
+            .to_string()
+            .starts_with("line 2: broken link"));
+        assert_eq!(
+            verify_audit("1000 - HALT:APPLE\n".as_bytes(), None)
+                .unwrap_err()
+                .to_string(),
+            "line 1: the record is not chained"
+        );
+
+        // The last records are missing or rewritten.
+        let checkpoint = Some(audit.head.as_str());
+        assert_eq!(verify_audit(journal.as_bytes(), checkpoint).unwrap(), audit);
+        let truncated = format!("{}\n", lines[..5].join("\n"));
+        assert_eq!(
+            verify_audit(truncated.as_bytes(), checkpoint)
+                .unwrap_err()
+                .to_string(),
+            format!(
+                "the checkpoint {} is missing, the journal was truncated or rewritten",
+                audit.head
+            )
+        );
+        let rewritten = journal.replacen("RESUME:PEAR", "HALT:PEAR", 1);
+        assert!(verify_audit(rewritten.as_bytes(), checkpoint).is_err());
+        // The earlier checkpoint is still there.
+        assert!(verify_audit(journal.as_bytes(), Some(&hash(lines[2]))).is_ok());
+    }
+
+    #[test]
+    fn test_unchained_journal_is_not_continued() {
+        let path = std::env::temp_dir().join(format!("trading-unchained-{}", std::process::id()));
+        std::fs::write(&path, "1000 - HALT:APPLE\n").unwrap();
+        let error = Journal::open(&path).err().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "Unable to continue {}: line 1: the record is not chained",
+                path.display()
+            )
+        );
+    }
+}
@@ -0,0 +1,357 @@
+/// Author: Tomasz Kulik
+/// 
+///
+
+use clap::Parser;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time::{Instant, MissedTickBehavior};
+
+/// Load generator for the trading market
+///
+/// Opens the simulated clients, sends the random orders at the
+/// target rate and reports the throughput and the latency from
+/// sending an order to its ACK and to its first fill.
+#[derive(Parser, Clone)]
+struct Args {
+    /// The address of the server.
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    address: String,
+
+    /// The number of simulated clients.
+    #[arg(long, default_value_t = 10)]
+    clients: usize,
+
+    /// The target number of orders per second, for all the clients.
+    #[arg(long, default_value_t = 1000)]
+    rate: u64,
+
+    /// How long the orders are sent, e.g. `10s` or `1m`.
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "10s")]
+    duration: Duration,
+
+    /// How long to wait for the outstanding ACKs once the
+    /// orders are not sent anymore.
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "2s")]
+    drain: Duration,
+
+    /// The traded products.
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "APPLE,PEAR,TOMATO,POTATO,ONION"
+    )]
+    products: Vec<String>,
+
+    /// The share of the orders sent without the limit price.
+    #[arg(long, default_value_t = 0.1)]
+    market_ratio: f64,
+
+    /// The limit prices are drawn from `price - spread..=price + spread`.
+    #[arg(long, default_value_t = 100)]
+    price: u64,
+
+    #[arg(long, default_value_t = 5)]
+    spread: u64,
+
+    /// The quantities are drawn from `1..=max_quantity`.
+    #[arg(long, default_value_t = 10)]
+    max_quantity: u64,
+
+    /// The seed of the order mix, every client uses its own
+    /// sequence derived from it.
+    #[arg(long, default_value_t = 1)]
+    seed: u64,
+}
+
+/// The measurements of a single client.
+#[derive(Default)]
+struct Report {
+    sent: u64,
+    acked: u64,
+    errors: u64,
+    /// From sending the order to its ACK, in microseconds.
+    ack_latencies: Vec<u64>,
+    /// From sending the order to its first fill, in microseconds.
+    trade_latencies: Vec<u64>,
+}
+
+/// The orders sent and not acknowledged yet.
+///
+/// The ACKs of a single product come in the order the orders
+/// were sent, the products are matched independently.
+type Pending = Arc<Mutex<HashMap<String, VecDeque<Instant>>>>;
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    if args.clients == 0 || args.rate == 0 || args.products.is_empty() {
+        anyhow::bail!("At least one client, product and order per second is required");
+    }
+    println!(
+        "{} clients sending {} orders/s to {} for {:?}",
+        args.clients, args.rate, args.address, args.duration
+    );
+
+    let start = Instant::now();
+    let mut clients = vec![];
+    for client in 0..args.clients {
+        clients.push(tokio::spawn(run_client(args.clone(), client)));
+    }
+    let mut total = Report::default();
+    for client in clients {
+        let report = client.await??;
+        total.sent += report.sent;
+        total.acked += report.acked;
+        total.errors += report.errors;
+        total.ack_latencies.extend(report.ack_latencies);
+        total.trade_latencies.extend(report.trade_latencies);
+    }
+    let elapsed = start.elapsed().min(args.duration).as_secs_f64();
+
+    println!(
+        "sent {} ({:.0}/s), acked {} ({:.0}/s), errors {}, unacknowledged {}",
+        total.sent,
+        total.sent as f64 / elapsed,
+        total.acked,
+        total.acked as f64 / elapsed,
+        total.errors,
+        total.sent.saturating_sub(total.acked + total.errors)
+    );
+    print_latencies("ACK", &mut total.ack_latencies);
+    print_latencies("TRADE", &mut total.trade_latencies);
+    Ok(())
+}
+
+/// Sends the orders of a single client and measures the responses.
+async fn run_client(args: Args, client: usize) -> anyhow::Result<Report> {
+    let stream = TcpStream::connect(&args.address).await?;
+    stream.set_nodelay(true)?;
+    let (reader, mut writer) = stream.into_split();
+    let pending: Pending = Arc::default();
+
+    let reader = tokio::spawn(read_responses(reader, pending.clone(), args.drain));
+
+    let mut random = Random::new(args.seed.wrapping_add(client as u64));
+    let period = Duration::from_secs_f64(args.clients as f64 / args.rate as f64);
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Burst);
+    let end = Instant::now() + args.duration;
+    let mut sent = 0;
+    while interval.tick().await < end {
+        let (product, order) = random_order(&args, &mut random);
+        pending
+            .lock()
+            .expect("The lock is never poisoned")
+            .entry(product.to_string())
+            .or_default()
+            .push_back(Instant::now());
+        writer.write_all(order.as_bytes()).await?;
+        sent += 1;
+    }
+    // Lets the reader know every order was sent.
+    drop(pending);
+
+    let mut report = reader.await??;
+    report.sent = sent;
+    Ok(report)
+}
+
+/// The next order of the mix, along with its product.
+fn random_order<'a>(args: &'a Args, random: &mut Random) -> (&'a str, String) {
+    let product = &args.products[random.below(args.products.len() as u64) as usize];
+    let side = match random.below(2) {
+        0 => "BUY",
+        _ => "SELL",
+    };
+    let quantity = 1 + random.below(args.max_quantity.max(1));
+    let order = match random.chance(args.market_ratio) {
+        true => format!("{}:{}:{}\n", side, product, quantity),
+        false => {
+            // The prices below the spread are raised to the lowest one.
+            let price = (args.price + random.below(2 * args.spread + 1))
+                .saturating_sub(args.spread)
+                .max(1);
+            format!("{}:{}:{}@{}\n", side, product, quantity, price)
+        }
+    };
+    (product, order)
+}
+
+/// Matches the responses with the orders sent, until the server
+/// stays silent for the drain period once every order was sent.
+async fn read_responses(
+    reader: tokio::net::tcp::OwnedReadHalf,
+    pending: Pending,
+    drain: Duration,
+) -> anyhow::Result<Report> {
+    let mut report = Report::default();
+    let mut lines = BufReader::new(reader).lines();
+    // The orders acknowledged and not filled yet.
+    let mut resting: HashMap<u64, Instant> = HashMap::new();
+    loop {
+        let line = match tokio::time::timeout(drain, lines.next_line()).await {
+            Ok(line) => line?,
+            Err(_) if Arc::strong_count(&pending) == 1 => break,
+            Err(_) => continue,
+        };
+        let line = match line {
+            Some(line) => line,
+            None => break,
+        };
+        let fields: Vec<&str> = line.split(':').collect();
+        match fields[..] {
+            ["ACK", product, order_id] => {
+                let sent = pending
+                    .lock()
+                    .expect("The lock is never poisoned")
+                    .get_mut(product)
+                    .and_then(VecDeque::pop_front);
+                if let (Some(sent), Ok(order_id)) = (sent, order_id.parse()) {
+                    report.acked += 1;
+                    report.ack_latencies.push(micros(sent));
+                    resting.insert(order_id, sent);
+                }
+            }
+            ["FILL", order_id, _] => {
+                let sent = order_id.parse().ok().and_then(|id| resting.remove(&id));
+                if let Some(sent) = sent {
+                    report.trade_latencies.push(micros(sent));
+                }
+            }
+            ["ERROR", ..] => report.errors += 1,
+            _ => {}
+        }
+    }
+    Ok(report)
+}
+
+fn micros(since: Instant) -> u64 {
+    since.elapsed().as_micros() as u64
+}
+
+fn print_latencies(name: &str, latencies: &mut [u64]) {
+    if latencies.is_empty() {
+        println!("{:<5} no samples", name);
+        return;
+    }
+    latencies.sort_unstable();
+    println!(
+        "{:<5} samples {} p50 {}us p99 {}us p999 {}us max {}us",
+        name,
+        latencies.len(),
+        percentile(latencies, 0.5),
+        percentile(latencies, 0.99),
+        percentile(latencies, 0.999),
+        latencies[latencies.len() - 1]
+    );
+}
+
+/// The nearest-rank percentile of the sorted, non-empty samples,
+/// i.e. the smallest one with at least `p` of the samples at or
+/// below it.
+fn percentile(sorted: &[u64], p: f64) -> u64 {
+    let index = ((sorted.len() as f64 * p).ceil() as usize).clamp(1, sorted.len());
+    sorted[index - 1]
+}
+
+/// A simple xorshift generator, good enough for the order mix.
+struct Random(u64);
+
+impl Random {
+    fn new(seed: u64) -> Random {
+        // The state must not be zero.
+        Random(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next() % bound
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        ((self.next() >> 11) as f64) < probability * (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentile() {
+        assert_eq!(percentile(&[7], 0.5), 7);
+        assert_eq!(percentile(&[7], 0.999), 7);
+        let samples = [1, 2, 3, 4];
+        assert_eq!(percentile(&samples, 0.0), 1);
+        assert_eq!(percentile(&samples, 0.25), 1);
+        assert_eq!(percentile(&samples, 0.5), 2);
+        assert_eq!(percentile(&samples, 0.51), 3);
+        assert_eq!(percentile(&samples, 0.99), 4);
+        assert_eq!(percentile(&samples, 1.0), 4);
+        let samples: Vec<u64> = (1..=1000).collect();
+        assert_eq!(percentile(&samples, 0.5), 500);
+        assert_eq!(percentile(&samples, 0.99), 990);
+        assert_eq!(percentile(&samples, 0.999), 999);
+    }
+
+    #[test]
+    fn test_random_orders() {
+        let args = Args::parse_from([
+            "loadgen",
+            "--products",
+            "APPLE,PEAR",
+            "--price",
+            "3",
+            "--spread",
+            "5",
+            "--market-ratio",
+            "0.2",
+        ]);
+        let mut random = Random::new(args.seed);
+        let mut market = 0;
+        for _ in 0..10_000 {
+            let (product, order) = random_order(&args, &mut random);
+            let fields: Vec<&str> = order.trim_end().split(':').collect();
+            assert!(["BUY", "SELL"].contains(&fields[0]), "{}", order);
+            assert_eq!(fields[1], product);
+            assert!(["APPLE", "PEAR"].contains(&product));
+            match fields[2].split_once('@') {
+                Some((quantity, price)) => {
+                    assert!((1..=10).contains(&quantity.parse::<u64>().unwrap()));
+                    // The spread is wider than the price.
+                    assert!((1..=8).contains(&price.parse::<u64>().unwrap()));
+                }
+                None => market += 1,
+            }
+        }
+        assert!((1500..2500).contains(&market), "{}", market);
+
+        // Every client's sequence is repeated with its seed.
+        let orders = |seed| {
+            let mut random = Random::new(seed);
+            (0..10)
+                .map(|_| random_order(&args, &mut random).1)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(orders(1), orders(1));
+        assert_ne!(orders(1), orders(2));
+    }
+
+    #[test]
+    fn test_random() {
+        let mut random = Random::new(0);
+        assert!((0..1000).all(|_| random.below(3) < 3));
+        assert!((0..1000).all(|_| !random.chance(0.0)));
+        assert!((0..1000).all(|_| random.chance(1.0)));
+    }
+}
@@ -0,0 +1,624 @@
+/// Author: Tomasz Kulik
+/// 
+///
+
+use crate::ledger::Phase;
+use crate::message::Message;
+use crate::order::{Order, Price, Side, UserId, Visibility};
+use crate::request::{Cancel, Replace, Request};
+use crate::transaction::Product;
+use std::convert::TryInto;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+// The binary protocol, see `docs/binary-protocol.md`.
+//
+// Every frame starts with its length, followed by the type and
+// the fixed-layout fields, all the integers are little-endian.
+
+const NEW_ORDER: u8 = 0x01;
+const CANCEL: u8 = 0x02;
+const HEARTBEAT: u8 = 0x03;
+const CANCEL_ON_DISCONNECT: u8 = 0x04;
+const REPLACE: u8 = 0x05;
+const BOOK: u8 = 0x06;
+const STOP_ORDER: u8 = 0x07;
+
+const ACK: u8 = 0x81;
+const FILL: u8 = 0x82;
+const TRADE: u8 = 0x83;
+const ERROR: u8 = 0x84;
+const CANCELLED: u8 = 0x85;
+const PHASE: u8 = 0x86;
+const INDICATIVE: u8 = 0x87;
+const SERVER_HEARTBEAT: u8 = 0x88;
+const DEPTH: u8 = 0x89;
+const EXPIRED: u8 = 0x8a;
+const CLEARED: u8 = 0x8b;
+
+/// Read the next frame of the connection into the buffer,
+/// without its length.
+///
+/// Returns false once the connection is closed.
+pub(crate) async fn read_frame<R>(reader: &mut R, buffer: &mut Vec<u8>) -> std::io::Result<bool>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut length = [0; 2];
+    match reader.read_exact(&mut length).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(false),
+        Err(e) => return Err(e),
+    }
+    buffer.resize(u16::from_le_bytes(length) as usize, 0);
+    reader.read_exact(buffer).await?;
+    Ok(true)
+}
+
+/// Parse the request frame sent by the user.
+pub(crate) fn decode_request(user_id: UserId, frame: &[u8]) -> Result<Request, String> {
+    let mut fields = Fields(frame);
+    let request = match fields.u8()? {
+        kind @ (NEW_ORDER | STOP_ORDER) => {
+            let side = match fields.u8()? {
+                0 => Side::Buy,
+                1 => Side::Sell,
+                side => return Err(format!("Unknown side: {}", side)),
+            };
+            let product = fields.product()?;
+            let quantity = fields.u64()?;
+            let limit = fields.price()?;
+            if quantity == 0 {
+                return Err(format!("Invalid quantity: {}", quantity));
+            }
+            if limit == Some(Price::MAX) {
+                return Err(format!("Invalid price: {}", Price::MAX));
+            }
+            let stop = match kind {
+                STOP_ORDER => match fields.price()? {
+                    Some(stop) if stop != Price::MAX => Some(stop),
+                    stop => return Err(format!("Invalid stop price: {}", stop.unwrap_or(0))),
+                },
+                _ => None,
+            };
+            // The peak is optional, zero stands for the hidden order.
+            let visibility = match fields.is_empty() {
+                true => Visibility::Visible,
+                false if stop.is_some() => return Err("Frame too long".to_string()),
+                false if limit.is_none() => {
+                    return Err("Only the limit orders may be hidden".to_string())
+                }
+                false => match fields.u64()? {
+                    0 => Visibility::Hidden,
+                    peak => Visibility::Iceberg { peak, shown: 0 },
+                },
+            };
+            Request::Order(Order {
+                id: 0,
+                timestamp: 0,
+                user_id,
+                side,
+                product,
+                quantity,
+                limit,
+                short_form: false,
+                visibility,
+                stop,
+                expiry: None,
+            })
+        }
+        CANCEL => Request::Cancel(Cancel {
+            user_id,
+            product: fields.product()?,
+            order_id: fields.u64()?,
+        }),
+        HEARTBEAT => Request::Heartbeat,
+        BOOK => Request::Book(fields.product()?),
+        CANCEL_ON_DISCONNECT => Request::CancelOnDisconnect(fields.u8()? != 0),
+        REPLACE => {
+            let order_id = fields.u64()?;
+            let quantity = fields.u64()?;
+            let limit = fields.price()?;
+            if quantity == 0 {
+                return Err(format!("Invalid quantity: {}", quantity));
+            }
+            if limit == Some(Price::MAX) {
+                return Err(format!("Invalid price: {}", Price::MAX));
+            }
+            Request::Replace(Replace {
+                user_id,
+                order_id,
+                quantity,
+                limit,
+                timestamp: 0,
+            })
+        }
+        kind => return Err(format!("Unknown request type: {:#04x}", kind)),
+    };
+    fields.end()?;
+    Ok(request)
+}
+
+/// Append the message's frame, along with its length, to the buffer.
+pub(crate) fn encode_message(message: &Message, buffer: &mut Vec<u8>) {
+    let start = buffer.len();
+    buffer.extend_from_slice(&[0, 0]);
+    match message {
+        Message::Ack { product, order_id } => {
+            buffer.push(ACK);
+            buffer.push(product_code(*product));
+            buffer.extend_from_slice(&order_id.unwrap_or(0).to_le_bytes());
+        }
+        Message::Fill {
+            order_id,
+            quantity,
+            price,
+            fee,
+        } => {
+            buffer.push(FILL);
+            buffer.extend_from_slice(&order_id.to_le_bytes());
+            buffer.extend_from_slice(&quantity.to_le_bytes());
+            buffer.extend_from_slice(&price.unwrap_or(0).to_le_bytes());
+            buffer.extend_from_slice(&fee.unwrap_or(0).to_le_bytes());
+        }
+        Message::Trade {
+            product,
+            quantity,
+            price,
+        } => {
+            buffer.push(TRADE);
+            buffer.push(product_code(*product));
+            buffer.extend_from_slice(&quantity.to_le_bytes());
+            buffer.extend_from_slice(&price.unwrap_or(0).to_le_bytes());
+        }
+        Message::Error(reason) => {
+            buffer.push(ERROR);
+            // The length of the frame must fit into its prefix.
+            let mut end = reason.len().min(u16::MAX as usize - 1);
+            while !reason.is_char_boundary(end) {
+                end -= 1;
+            }
+            buffer.extend_from_slice(&reason.as_bytes()[..end]);
+        }
+        Message::Cancelled { product, order_id } => {
+            buffer.push(CANCELLED);
+            buffer.push(product_code(*product));
+            buffer.extend_from_slice(&order_id.unwrap_or(0).to_le_bytes());
+        }
+        Message::Expired { product, order_id } => {
+            buffer.push(EXPIRED);
+            buffer.push(product_code(*product));
+            buffer.extend_from_slice(&order_id.to_le_bytes());
+        }
+        Message::Phase { product, phase } => {
+            buffer.push(PHASE);
+            buffer.push(product_code(*product));
+            buffer.push(phase_code(*phase));
+        }
+        Message::Indicative {
+            product,
+            volume,
+            price,
+        } => {
+            buffer.push(INDICATIVE);
+            buffer.push(product_code(*product));
+            buffer.extend_from_slice(&volume.to_le_bytes());
+            buffer.extend_from_slice(&price.unwrap_or(0).to_le_bytes());
+        }
+        Message::Cleared {
+            product,
+            volume,
+            price,
+        } => {
+            buffer.push(CLEARED);
+            buffer.push(product_code(*product));
+            buffer.extend_from_slice(&volume.to_le_bytes());
+            buffer.extend_from_slice(&price.unwrap_or(0).to_le_bytes());
+        }
+        Message::Heartbeat => buffer.push(SERVER_HEARTBEAT),
+        Message::Book {
+            product,
+            bids,
+            asks,
+        } => {
+            // The depth is limited to a few levels, so
+            // the counts fit into single bytes.
+            buffer.push(DEPTH);
+            buffer.push(product_code(*product));
+            buffer.push(bids.len() as u8);
+            buffer.push(asks.len() as u8);
+            for (quantity, price) in bids.iter().chain(asks.iter()) {
+                buffer.extend_from_slice(&quantity.to_le_bytes());
+                buffer.extend_from_slice(&price.to_le_bytes());
+            }
+        }
+        // The binary users can't start the session, nor ask
+        // for the statement.
+        Message::Session { .. } | Message::Gap { .. } | Message::Statement(_) => {
+            buffer.truncate(start);
+            return;
+        }
+    }
+    let length = (buffer.len() - start - 2) as u16;
+    buffer[start..start + 2].copy_from_slice(&length.to_le_bytes());
+}
+
+fn product_code(product: Product) -> u8 {
+    Product::ALL
+        .iter()
+        .position(|p| *p == product)
+        .expect("Every product is listed") as u8
+}
+
+fn phase_code(phase: Phase) -> u8 {
+    match phase {
+        Phase::Continuous => 0,
+        Phase::Auction => 1,
+        Phase::Closed => 2,
+        Phase::Halted => 3,
+    }
+}
+
+/// The fields of a frame, read one after another.
+struct Fields<'a>(&'a [u8]);
+
+impl Fields<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        if self.0.len() < N {
+            return Err("Frame too short".to_string());
+        }
+        let (field, rest) = self.0.split_at(N);
+        self.0 = rest;
+        Ok(field.try_into().expect("The length is checked"))
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.take()?))
+    }
+
+    fn product(&mut self) -> Result<Product, String> {
+        let code = self.u8()?;
+        Product::ALL
+            .get(code as usize)
+            .copied()
+            .ok_or_else(|| format!("Unknown product: {}", code))
+    }
+
+    /// The price, zero stands for no price.
+    fn price(&mut self) -> Result<Option<Price>, String> {
+        Ok(Some(self.u64()?).filter(|price| *price != 0))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn end(&self) -> Result<(), String> {
+        match self.0.is_empty() {
+            true => Ok(()),
+            false => Err("Frame too long".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::order::OrderId;
+
+    /// The user's side of the protocol.
+    fn encode_request(request: &Request, buffer: &mut Vec<u8>) {
+        let mut frame = vec![];
+        match request {
+            Request::Order(order) => {
+                frame.push(match order.stop {
+                    Some(_) => STOP_ORDER,
+                    None => NEW_ORDER,
+                });
+                frame.push(match order.side {
+                    Side::Buy => 0,
+                    Side::Sell => 1,
+                });
+                frame.push(product_code(order.product));
+                frame.extend_from_slice(&order.quantity.to_le_bytes());
+                frame.extend_from_slice(&order.limit.unwrap_or(0).to_le_bytes());
+                match order.visibility {
+                    Visibility::Visible => {}
+                    Visibility::Iceberg { peak, .. } => {
+                        frame.extend_from_slice(&peak.to_le_bytes())
+                    }
+                    Visibility::Hidden => frame.extend_from_slice(&0u64.to_le_bytes()),
+                }
+                if let Some(stop) = order.stop {
+                    frame.extend_from_slice(&stop.to_le_bytes());
+                }
+            }
+            Request::Cancel(cancel) => {
+                frame.push(CANCEL);
+                frame.push(product_code(cancel.product));
+                frame.extend_from_slice(&cancel.order_id.to_le_bytes());
+            }
+            Request::Heartbeat => frame.push(HEARTBEAT),
+            Request::Book(product) => frame.extend_from_slice(&[BOOK, product_code(*product)]),
+            Request::CancelOnDisconnect(enabled) => {
+                frame.extend_from_slice(&[CANCEL_ON_DISCONNECT, *enabled as u8])
+            }
+            Request::Replace(replace) => {
+                frame.push(REPLACE);
+                frame.extend_from_slice(&replace.order_id.to_le_bytes());
+                frame.extend_from_slice(&replace.quantity.to_le_bytes());
+                frame.extend_from_slice(&replace.limit.unwrap_or(0).to_le_bytes());
+            }
+            request => panic!("Not supported: {:?}", request),
+        }
+        buffer.extend_from_slice(&(frame.len() as u16).to_le_bytes());
+        buffer.extend_from_slice(&frame);
+    }
+
+    fn decode_message(frame: &[u8]) -> Result<Message, String> {
+        let mut fields = Fields(frame);
+        let id = |id: OrderId| Some(id).filter(|id| *id != 0);
+        let message = match fields.u8()? {
+            ACK => Message::Ack {
+                product: fields.product()?,
+                order_id: id(fields.u64()?),
+            },
+            FILL => Message::Fill {
+                order_id: fields.u64()?,
+                quantity: fields.u64()?,
+                price: fields.price()?,
+                fee: Some(i64::from_le_bytes(fields.take()?)).filter(|fee| *fee != 0),
+            },
+            TRADE => Message::Trade {
+                product: fields.product()?,
+                quantity: fields.u64()?,
+                price: fields.price()?,
+            },
+            ERROR => {
+                let reason = String::from_utf8(fields.0.to_vec()).map_err(|e| e.to_string())?;
+                fields.0 = &[];
+                Message::Error(reason)
+            }
+            CANCELLED => Message::Cancelled {
+                product: fields.product()?,
+                order_id: id(fields.u64()?),
+            },
+            EXPIRED => Message::Expired {
+                product: fields.product()?,
+                order_id: fields.u64()?,
+            },
+            PHASE => Message::Phase {
+                product: fields.product()?,
+                phase: match fields.u8()? {
+                    0 => Phase::Continuous,
+                    1 => Phase::Auction,
+                    2 => Phase::Closed,
+                    _ => Phase::Halted,
+                },
+            },
+            INDICATIVE => Message::Indicative {
+                product: fields.product()?,
+                volume: fields.u64()?,
+                price: fields.price()?,
+            },
+            CLEARED => Message::Cleared {
+                product: fields.product()?,
+                volume: fields.u64()?,
+                price: fields.price()?,
+            },
+            SERVER_HEARTBEAT => Message::Heartbeat,
+            DEPTH => {
+                let product = fields.product()?;
+                let (bids, asks) = (fields.u8()?, fields.u8()?);
+                let mut level = || Ok::<_, String>((fields.u64()?, fields.u64()?));
+                let bids = (0..bids).map(|_| level()).collect::<Result<_, _>>()?;
+                let asks = (0..asks).map(|_| level()).collect::<Result<_, _>>()?;
+                Message::Book {
+                    product,
+                    bids,
+                    asks,
+                }
+            }
+            kind => return Err(format!("Unknown message type: {:#04x}", kind)),
+        };
+        fields.end()?;
+        Ok(message)
+    }
+
+    /// Split the buffer into the frames, checking their lengths.
+    fn frames(mut buffer: &[u8]) -> Vec<&[u8]> {
+        let mut frames = vec![];
+        while !buffer.is_empty() {
+            let length = u16::from_le_bytes([buffer[0], buffer[1]]) as usize;
+            frames.push(&buffer[2..2 + length]);
+            buffer = &buffer[2 + length..];
+        }
+        frames
+    }
+
+    #[test]
+    fn test_request_round_trip() {
+        let order = |side, product, quantity, limit, visibility| Order {
+            id: 0,
+            timestamp: 0,
+            user_id: 4000,
+            side,
+            product,
+            quantity,
+            limit,
+            short_form: false,
+            visibility,
+            stop: None,
+            expiry: None,
+        };
+        let requests = [
+            Request::Order(order(
+                Side::Buy,
+                Product::Apple,
+                5,
+                Some(100),
+                Visibility::Visible,
+            )),
+            Request::Order(order(
+                Side::Sell,
+                Product::Onion,
+                u64::MAX,
+                None,
+                Visibility::Visible,
+            )),
+            Request::Order(order(
+                Side::Sell,
+                Product::Pear,
+                100,
+                Some(90),
+                Visibility::Iceberg { peak: 10, shown: 0 },
+            )),
+            Request::Order(order(
+                Side::Buy,
+                Product::Pear,
+                7,
+                Some(80),
+                Visibility::Hidden,
+            )),
+            Request::Order(Order {
+                stop: Some(95),
+                ..order(Side::Sell, Product::Pear, 10, None, Visibility::Visible)
+            }),
+            Request::Order(Order {
+                stop: Some(105),
+                ..order(Side::Buy, Product::Pear, 10, Some(110), Visibility::Visible)
+            }),
+            Request::Cancel(Cancel {
+                user_id: 4000,
+                product: Product::Potato,
+                order_id: 17,
+            }),
+            Request::Heartbeat,
+            Request::Book(Product::Tomato),
+            Request::CancelOnDisconnect(true),
+            Request::CancelOnDisconnect(false),
+            Request::Replace(Replace {
+                user_id: 4000,
+                order_id: 17,
+                quantity: 3,
+                limit: Some(99),
+                timestamp: 0,
+            }),
+        ];
+        let mut buffer = vec![];
+        for request in requests.iter() {
+            encode_request(request, &mut buffer);
+        }
+        let decoded: Vec<Request> = frames(&buffer)
+            .into_iter()
+            .map(|frame| decode_request(4000, frame).unwrap())
+            .collect();
+        assert_eq!(decoded, requests);
+
+        // BUY APPLE 5 @ 100
+        assert_eq!(
+            &buffer[..21],
+            &[19, 0, 0x01, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0, 100, 0, 0, 0, 0, 0, 0, 0]
+        );
+        assert!(decode_request(4000, &[NEW_ORDER, 0, 0]).is_err());
+        assert!(decode_request(
+            4000,
+            &[NEW_ORDER, 2, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]
+        )
+        .is_err());
+        assert!(decode_request(
+            4000,
+            &[
+                STOP_ORDER, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+                0, 0
+            ]
+        )
+        .is_err());
+        assert!(decode_request(4000, &[CANCEL, 5, 1, 0, 0, 0, 0, 0, 0, 0]).is_err());
+        assert!(decode_request(4000, &[HEARTBEAT, 0]).is_err());
+        assert!(decode_request(4000, &[0x7f]).is_err());
+        assert!(decode_request(4000, &[]).is_err());
+    }
+
+    #[test]
+    fn test_message_round_trip() {
+        let messages = [
+            Message::Ack {
+                product: Product::Apple,
+                order_id: Some(1),
+            },
+            Message::Ack {
+                product: Product::Pear,
+                order_id: None,
+            },
+            Message::Fill {
+                order_id: 1,
+                quantity: 3,
+                price: Some(100),
+                fee: None,
+            },
+            Message::Fill {
+                order_id: 2,
+                quantity: 3,
+                price: Some(100),
+                fee: Some(-3),
+            },
+            Message::Trade {
+                product: Product::Tomato,
+                quantity: 3,
+                price: None,
+            },
+            Message::Error("APPLE market closed".to_string()),
+            Message::Cancelled {
+                product: Product::Onion,
+                order_id: Some(7),
+            },
+            Message::Expired {
+                product: Product::Onion,
+                order_id: 8,
+            },
+            Message::Phase {
+                product: Product::Potato,
+                phase: Phase::Halted,
+            },
+            Message::Indicative {
+                product: Product::Apple,
+                volume: 10,
+                price: Some(99),
+            },
+            Message::Cleared {
+                product: Product::Onion,
+                volume: 12,
+                price: Some(98),
+            },
+            Message::Heartbeat,
+            Message::Book {
+                product: Product::Pear,
+                bids: vec![(5, 100), (3, 99)],
+                asks: vec![(2, 101)],
+            },
+        ];
+        let mut buffer = vec![];
+        for message in messages.iter() {
+            encode_message(message, &mut buffer);
+        }
+        let decoded: Vec<Message> = frames(&buffer)
+            .into_iter()
+            .map(|frame| decode_message(frame).unwrap())
+            .collect();
+        assert_eq!(decoded, messages);
+        // The FILL has the same size with and without the fee.
+        assert_eq!(frames(&buffer)[2].len(), 33);
+        assert_eq!(frames(&buffer)[3].len(), 33);
+
+        let mut buffer = vec![];
+        encode_message(&Message::Gap { from: 1, to: 2 }, &mut buffer);
+        assert!(buffer.is_empty());
+        encode_message(&Message::Error("é".repeat(40000)), &mut buffer);
+        assert_eq!(frames(&buffer).len(), 1);
+        assert!(decode_message(frames(&buffer)[0]).is_ok());
+    }
+}
@@ -0,0 +1,353 @@
+/// Author: Tomasz Kulik
+/// 
+///
+
+pub use crate::ledger::Phase;
+pub use crate::message::Message;
+pub use crate::order::{OrderId, Price, Quantity, Side};
+pub use crate::transaction::Product;
+
+use crate::message::{size, split_seq, SequenceNumber, SessionId};
+use futures::Stream;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+use tracing::{info, warn};
+
+/// The client of the trading market.
+///
+/// The connection is handled by a background task. Once it's
+/// lost, the task reconnects and the requests waiting for the
+/// answer fail, as it's unknown whether the server got them.
+/// The client resumes its session after reconnecting, so the
+/// messages sent meanwhile are still received, unless the server
+/// doesn't retain them anymore and reports the `Gap`.
+/// Dropping the client closes the connection.
+///
+/// ```no_run
+/// # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+/// use futures::StreamExt;
+/// use trading::client::{Client, Product, Side};
+///
+/// let (client, mut events) = Client::connect("127.0.0.1:8080").await?;
+/// let order_id = client.place_order(Side::Buy, Product::Apple, 5, Some(100)).await?;
+/// while let Some(event) = events.next().await {
+///     println!("{}", event);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct Client {
+    commands: UnboundedSender<Command>,
+}
+
+/// The stream of the messages sent by the server.
+pub struct Events {
+    receiver: UnboundedReceiver<Message>,
+}
+
+impl Stream for Events {
+    type Item = Message;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Message>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+/// A request waiting for the server's answer.
+enum Pending {
+    Order(oneshot::Sender<Result<OrderId, String>>),
+    Cancel(OrderId, oneshot::Sender<Result<(), String>>),
+}
+
+impl Pending {
+    fn fail(self, reason: String) {
+        match self {
+            Pending::Order(reply) => {
+                let _ = reply.send(Err(reason));
+            }
+            Pending::Cancel(_, reply) => {
+                let _ = reply.send(Err(reason));
+            }
+        }
+    }
+}
+
+enum Command {
+    Send {
+        product: Product,
+        line: String,
+        pending: Pending,
+    },
+    Subscribe(HashSet<Product>, UnboundedSender<Message>),
+}
+
+/// How long the client waits before reconnecting,
+/// doubled after every failed attempt.
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// How often the client sends the heartbeat, well within
+/// the server's default timeout.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+impl Client {
+    /// Connect to the server.
+    ///
+    /// Returns the client and the stream of every message
+    /// sent by the server to this user.
+    pub async fn connect(address: impl Into<String>) -> std::io::Result<(Client, Events)> {
+        let address = address.into();
+        let stream = TcpStream::connect(&address).await?;
+        let (commands, receiver) = unbounded_channel();
+        let (events, events_receiver) = unbounded_channel();
+        tokio::spawn(connection_task(address, stream, receiver, events));
+        Ok((
+            Client { commands },
+            Events {
+                receiver: events_receiver,
+            },
+        ))
+    }
+
+    /// Send the new order and wait for its ACK.
+    ///
+    /// An order without the limit price accepts any price.
+    pub async fn place_order(
+        &self,
+        side: Side,
+        product: Product,
+        quantity: Quantity,
+        limit: Option<Price>,
+    ) -> Result<OrderId, String> {
+        if quantity == 0 {
+            return Err(format!("Invalid quantity: {}", quantity));
+        }
+        if let Some(price) = limit.filter(|price| *price == 0 || *price == Price::MAX) {
+            return Err(format!("Invalid price: {}", price));
+        }
+        let (reply, answer) = oneshot::channel();
+        self.send(Command::Send {
+            product,
+            line: format!("{}:{}:{}\n", side, product, size(quantity, limit)),
+            pending: Pending::Order(reply),
+        })?;
+        answer
+            .await
+            .map_err(|_| "The client is closed".to_string())?
+    }
+
+    /// Cancel the resting order and wait until it's removed.
+    pub async fn cancel(&self, product: Product, order_id: OrderId) -> Result<(), String> {
+        let (reply, answer) = oneshot::channel();
+        self.send(Command::Send {
+            product,
+            line: format!("CANCEL:{}:{}\n", product, order_id),
+            pending: Pending::Cancel(order_id, reply),
+        })?;
+        answer
+            .await
+            .map_err(|_| "The client is closed".to_string())?
+    }
+
+    /// The stream of the public market data, i.e. the trades,
+    /// the indicative prices and the phases, of the given products.
+    pub fn subscribe(&self, products: &[Product]) -> Events {
+        let (sender, receiver) = unbounded_channel();
+        let _ = self.send(Command::Subscribe(
+            products.iter().copied().collect(),
+            sender,
+        ));
+        Events { receiver }
+    }
+
+    fn send(&self, command: Command) -> Result<(), String> {
+        self.commands
+            .send(command)
+            .map_err(|_| "The client is closed".to_string())
+    }
+}
+
+/// Sends the requests and dispatches the server's messages,
+/// reconnecting once the connection is lost.
+async fn connection_task(
+    address: String,
+    mut stream: TcpStream,
+    mut commands: UnboundedReceiver<Command>,
+    events: UnboundedSender<Message>,
+) {
+    let mut subscribers: Vec<(HashSet<Product>, UnboundedSender<Message>)> = vec![];
+    let mut session: Option<(SessionId, SequenceNumber)> = None;
+    loop {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        let greeting = match session {
+            Some((id, last_seq)) => format!("RESUME:{}:{}\n", id, last_seq),
+            None => "SESSION\n".to_string(),
+        };
+        if writer.write_all(greeting.as_bytes()).await.is_err() {
+            session = None;
+        }
+        // The ACKs and the cancels of a single product come in
+        // the order the requests were sent.
+        let mut pending: HashMap<Product, VecDeque<Pending>> = HashMap::new();
+        let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = heartbeat.tick() => {
+                    if writer.write_all(b"HEARTBEAT\n").await.is_err() {
+                        break;
+                    }
+                }
+                command = commands.recv() => match command {
+                    Some(Command::Send { product, line, pending: request }) => {
+                        if writer.write_all(line.as_bytes()).await.is_err() {
+                            request.fail("Connection lost".to_string());
+                            break;
+                        }
+                        pending.entry(product).or_default().push_back(request);
+                    }
+                    Some(Command::Subscribe(products, sender)) => {
+                        subscribers.push((products, sender));
+                    }
+                    // The client was dropped.
+                    None => return,
+                },
+                line = lines.next_line() => match line {
+                    Ok(Some(line)) => {
+                        let (seq, line) = split_seq(&line);
+                        if let (Some(seq), Some((_, last_seq))) = (seq, &mut session) {
+                            // Already received before reconnecting.
+                            if seq <= *last_seq {
+                                continue;
+                            }
+                            *last_seq = seq;
+                        }
+                        match line.parse::<Message>() {
+                            Ok(Message::Session { session: id, seq }) => {
+                                // Once resumed, the missed messages follow.
+                                let last_seq = match session {
+                                    Some((_, last_seq)) => last_seq,
+                                    None => seq,
+                                };
+                                session = Some((id, last_seq));
+                            }
+                            Ok(Message::Heartbeat) => {}
+                            Ok(Message::Error(reason)) if is_resume_rejected(&reason) => {
+                                // The session has expired, a new one is started.
+                                warn!(%reason, "session expired");
+                                session = None;
+                                if writer.write_all(b"SESSION\n").await.is_err() {
+                                    break;
+                                }
+                            }
+                            Ok(message) => {
+                                answer(&mut pending, &message);
+                                publish(&mut subscribers, &message);
+                                let _ = events.send(message);
+                            }
+                            Err(e) => warn!(error = %e, "invalid message"),
+                        }
+                    }
+                    _ => break,
+                },
+            }
+        }
+        for request in pending.into_values().flatten() {
+            request.fail("Connection lost".to_string());
+        }
+
+        warn!(%address, "connection lost, reconnecting");
+        let mut backoff = MIN_BACKOFF;
+        stream = loop {
+            if commands.is_closed() {
+                return;
+            }
+            match TcpStream::connect(&address).await {
+                Ok(stream) => break stream,
+                Err(e) => {
+                    warn!(%address, error = %e, "unable to reconnect");
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        };
+        info!(%address, "reconnected");
+    }
+}
+
+/// Whether the server refused to resume the session.
+fn is_resume_rejected(reason: &str) -> bool {
+    reason.starts_with("Unknown session") || reason.starts_with("Invalid sequence number")
+}
+
+/// Completes the request answered by the message, if any.
+fn answer(pending: &mut HashMap<Product, VecDeque<Pending>>, message: &Message) {
+    match message {
+        Message::Ack {
+            product,
+            order_id: Some(order_id),
+        } => {
+            let queue = pending.entry(*product).or_default();
+            if let Some(Pending::Order(_)) = queue.front() {
+                if let Some(Pending::Order(reply)) = queue.pop_front() {
+                    let _ = reply.send(Ok(*order_id));
+                }
+            }
+        }
+        Message::Cancelled {
+            product,
+            order_id: Some(order_id),
+        } => {
+            // The order might have been cancelled by the operator.
+            let queue = pending.entry(*product).or_default();
+            if let Some(Pending::Cancel(id, _)) = queue.front() {
+                if id == order_id {
+                    if let Some(Pending::Cancel(_, reply)) = queue.pop_front() {
+                        let _ = reply.send(Ok(()));
+                    }
+                }
+            }
+        }
+        Message::Error(reason) => {
+            // The rejections start with the product.
+            let product = reason
+                .split_whitespace()
+                .next()
+                .and_then(|product| product.parse::<Product>().ok());
+            let queue = match product.and_then(|product| pending.get_mut(&product)) {
+                Some(queue) => queue,
+                None => return,
+            };
+            // The cancel of the order already cancelled by the
+            // operator might be answered out of the order.
+            let position = queue.iter().position(|request| match request {
+                Pending::Cancel(id, _) => reason.ends_with(&format!("unknown order {}", id)),
+                Pending::Order(_) => !reason.contains("unknown order"),
+            });
+            if let Some(request) = position.and_then(|position| queue.remove(position)) {
+                request.fail(reason.clone());
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Sends the public message to the subscribers of its product.
+fn publish(subscribers: &mut Vec<(HashSet<Product>, UnboundedSender<Message>)>, message: &Message) {
+    let product = match message {
+        Message::Trade { product, .. }
+        | Message::Indicative { product, .. }
+        | Message::Cleared { product, .. }
+        | Message::Phase { product, .. } => product,
+        _ => return,
+    };
+    subscribers.retain(|(products, sender)| {
+        !products.contains(product) || sender.send(message.clone()).is_ok()
+    });
+}
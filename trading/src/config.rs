@@ -0,0 +1,429 @@
+/// Author: Tomasz Kulik
+/// 
+///
+
+use crate::fees::Amount;
+use crate::ledger::Phase;
+use crate::order::{Quantity, UserId, MAX_EXPIRY_HORIZON};
+use crate::transaction::Product;
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// The configuration of the trading market.
+///
+/// It's read from a TOML file, every field is optional.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The interface the server listens on.
+    pub interface: String,
+    /// Serves the listener on the `interface` over TLS.
+    /// Disabled by default.
+    pub tls: Option<TlsConfig>,
+    /// The trading phases in the order they take place,
+    /// starting once the server is started. Once the last
+    /// phase is over, the market is closed. Without any
+    /// phases the market trades continuously.
+    pub schedule: Vec<ScheduledPhase>,
+    /// What happens with the orders sent while the product
+    /// is halted.
+    pub halted_orders: HaltedOrders,
+    /// Halts the trading in a product once its price moves
+    /// too far. Disabled by default.
+    pub circuit_breaker: Option<CircuitBreaker>,
+    /// The matching algorithm of the products, e.g.
+    /// `matching = { ONION = "pro_rata" }`. The products
+    /// left out are matched in the time priority.
+    pub matching: BTreeMap<Product, Matching>,
+    /// The products traded in the frequent batch auctions instead
+    /// of the continuous trading, e.g. `batch_auctions = { ONION =
+    /// { interval = "100ms" } }`. Disabled by default.
+    pub batch_auctions: BTreeMap<Product, BatchAuction>,
+    /// The products which only collect the orders, as in the
+    /// auction, until the operator opens them with `OPEN:<PRODUCT>`,
+    /// e.g. `auction_only = ["ONION"]`. The operator may switch
+    /// any other product into the phase with `AUCTION:<PRODUCT>`.
+    pub auction_only: BTreeSet<Product>,
+    /// The fees charged for every unit traded, per product, e.g.
+    /// `fees = { APPLE = { maker = -1, taker = 2 } }` charges the
+    /// taker 2 ticks and pays the maker 1 tick per unit, whatever
+    /// the price. The products left out are traded for free.
+    pub fees: BTreeMap<Product, FeeSchedule>,
+    /// The operators' control channel. Disabled by default.
+    pub admin: Option<AdminConfig>,
+    /// The file every sequenced event is appended to, see
+    /// `trading replay` and `trading verify-audit`. Disabled
+    /// by default.
+    pub journal: Option<PathBuf>,
+    /// The read-only feed of the executions for the risk and
+    /// the back office. Disabled by default.
+    pub drop_copy: Option<DropCopyConfig>,
+    /// How the users' sessions are kept for the reconnecting users.
+    pub session: SessionConfig,
+    /// How the idle connections are kept alive and dropped.
+    /// Disabled by default, the connections are kept until
+    /// they're closed.
+    pub heartbeat: Option<HeartbeatConfig>,
+    /// The listeners accepting the users along with the one
+    /// on the `interface`, e.g. a Unix domain socket for the
+    /// co-located bots. All of them trade in the same books.
+    pub listeners: Vec<ListenerConfig>,
+}
+
+/// A single entry of the trading schedule.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScheduledPhase {
+    pub phase: Phase,
+    /// How long the phase lasts, e.g. `"30s"` or `"8h 30m"`.
+    /// A phase without the duration lasts until the server
+    /// is stopped.
+    #[serde(default, with = "humantime_serde")]
+    pub duration: Option<Duration>,
+}
+
+/// The policy for the orders sent while the product is halted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HaltedOrders {
+    /// The orders are rejected, and so is the rest of the order
+    /// which tripped the circuit breaker.
+    Reject,
+    /// The orders are kept in the ledger without matching.
+    Queue,
+}
+
+/// How the incoming order is allocated across the resting
+/// orders at a price level, see `MatchingPolicy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Matching {
+    /// The oldest order is filled first.
+    #[default]
+    Fifo,
+    /// The orders are filled in proportion to their quantities.
+    ProRata,
+}
+
+/// The frequent batch auction settings of a product.
+///
+/// The orders are collected for the interval, then the book is
+/// cleared at a single price, like at the end of the auction.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BatchAuction {
+    #[serde(with = "humantime_serde")]
+    pub interval: Duration,
+}
+
+/// The maker and taker fees of a product, in ticks per unit.
+///
+/// The maker's order was resting in the book, the taker's one
+/// has matched it. Both sides of the auction are the makers.
+/// The negative fees are the rebates.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FeeSchedule {
+    pub maker: Amount,
+    pub taker: Amount,
+    /// The rates for the users who have traded more, see `FeeTier`.
+    #[serde(default)]
+    pub tiers: Vec<FeeTier>,
+}
+
+/// The rates applied once the user has traded the given
+/// volume of the product since the server was started.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FeeTier {
+    pub volume: Quantity,
+    pub maker: Amount,
+    pub taker: Amount,
+}
+
+/// The circuit breaker settings, common for all the products.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CircuitBreaker {
+    /// The maximum price move, in percents of the reference
+    /// price.
+    pub max_move: f64,
+    /// The reference price is the price of the oldest
+    /// transaction within this window.
+    #[serde(with = "humantime_serde")]
+    pub window: Duration,
+    /// How long the product stays halted.
+    #[serde(with = "humantime_serde")]
+    pub halt: Duration,
+}
+
+/// The admin listener settings.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Either the TCP interface, e.g. `"127.0.0.1:9000"`, or
+    /// the Unix domain socket path, e.g. `"unix:/run/trading.sock"`.
+    pub listen: String,
+    /// The token every admin connection has to start with.
+    pub token: String,
+    /// Where the snapshots of the ledger are written.
+    #[serde(default = "AdminConfig::default_snapshot_dir")]
+    pub snapshot_dir: PathBuf,
+}
+
+impl AdminConfig {
+    fn default_snapshot_dir() -> PathBuf {
+        PathBuf::from(".")
+    }
+}
+
+/// The settings of a single listener of the users.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    /// Either the TCP interface, e.g. `"127.0.0.1:8070"`, or
+    /// the Unix domain socket path, e.g. `"unix:/run/trading.sock"`.
+    pub listen: String,
+    /// The protocol of the listener's connections.
+    #[serde(default)]
+    pub protocol: ListenerProtocol,
+    /// Serves the listener over TLS. Disabled by default.
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+}
+
+/// The protocol of a listener.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ListenerProtocol {
+    /// The text protocol, which may be switched into
+    /// the JSON lines by the user's greeting.
+    #[default]
+    Text,
+    /// The JSON lines.
+    Json,
+    /// The binary protocol, see `docs/binary-protocol.md`.
+    Binary,
+    /// The JSON messages within the WebSocket frames, e.g.
+    /// for the browser dashboards.
+    Websocket,
+}
+
+/// The drop-copy listener settings.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DropCopyConfig {
+    /// Either the TCP interface, e.g. `"127.0.0.1:9100"`, or
+    /// the Unix domain socket path, e.g. `"unix:/run/drop-copy.sock"`.
+    pub listen: String,
+    /// The token every drop-copy connection has to start with.
+    pub token: String,
+}
+
+/// The TLS settings of a listener.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// The PEM file with the server's certificate chain.
+    pub cert: PathBuf,
+    /// The PEM file with the server's private key.
+    pub key: PathBuf,
+    /// The PEM file with the CA certificates the client
+    /// certificates are signed by. Once it's given, every
+    /// user has to present one of the `users` certificates.
+    #[serde(default)]
+    pub client_ca: Option<PathBuf>,
+    /// The users identified by their client certificates,
+    /// only along with the `client_ca`.
+    #[serde(default)]
+    pub users: Vec<TlsUser>,
+}
+
+/// The user identified by its client certificate.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsUser {
+    /// The PEM file with the user's certificate.
+    pub cert: PathBuf,
+    pub user_id: UserId,
+}
+
+/// The users' sessions settings.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    /// How many of the most recent messages are kept for
+    /// every session.
+    pub retention: usize,
+    /// How long the session is kept once its connection drops.
+    #[serde(with = "humantime_serde")]
+    pub timeout: Duration,
+}
+
+impl Default for SessionConfig {
+    fn default() -> SessionConfig {
+        SessionConfig {
+            retention: 10000,
+            timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+/// The heartbeats settings.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeartbeatConfig {
+    /// How long the connection stays idle before the server
+    /// sends the heartbeat.
+    #[serde(with = "humantime_serde")]
+    pub interval: Duration,
+    /// How long the server waits for anything from the user,
+    /// e.g. the heartbeat, before dropping the connection.
+    #[serde(with = "humantime_serde")]
+    pub timeout: Duration,
+    /// Whether the user's orders are cancelled once its
+    /// connection is lost, unless the user has chosen
+    /// otherwise with `CANCEL_ON_DISCONNECT`.
+    pub cancel_on_disconnect: bool,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> HeartbeatConfig {
+        HeartbeatConfig {
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(30),
+            cancel_on_disconnect: false,
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            interface: "127.0.0.1:8080".to_string(),
+            tls: None,
+            schedule: vec![],
+            halted_orders: HaltedOrders::Reject,
+            circuit_breaker: None,
+            matching: BTreeMap::new(),
+            batch_auctions: BTreeMap::new(),
+            auction_only: BTreeSet::new(),
+            fees: BTreeMap::new(),
+            admin: None,
+            journal: None,
+            drop_copy: None,
+            session: SessionConfig::default(),
+            heartbeat: None,
+            listeners: vec![],
+        }
+    }
+}
+
+impl Config {
+    /// Read the configuration from the TOML file.
+    pub fn load(path: &Path) -> anyhow::Result<Config> {
+        let content = std::fs::read_to_string(path)?;
+        let config: Config = toml::from_str(&content)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Rejects the settings that can't work together and the
+    /// durations the timers can't wait for.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if let Some(heartbeat) = &self.heartbeat {
+            if heartbeat.interval.is_zero() {
+                anyhow::bail!("The heartbeat interval must not be zero");
+            }
+            if heartbeat.timeout <= heartbeat.interval {
+                anyhow::bail!("The heartbeat timeout must be longer than its interval");
+            }
+        }
+        let tls = self.tls.iter().chain(
+            self.listeners
+                .iter()
+                .filter_map(|listener| listener.tls.as_ref()),
+        );
+        for tls in tls {
+            if tls.client_ca.is_none() && !tls.users.is_empty() {
+                anyhow::bail!("The TLS users need the client_ca to verify their certificates");
+            }
+        }
+        // The timers can't be set further than the good-till-date
+        // orders can expire.
+        let max = Duration::from_millis(MAX_EXPIRY_HORIZON);
+        for (product, auction) in self.batch_auctions.iter() {
+            if auction.interval.is_zero() || auction.interval > max {
+                anyhow::bail!(
+                    "The batch auction interval of {} must be between 1ms and a year",
+                    product
+                );
+            }
+        }
+        if let Some(circuit_breaker) = &self.circuit_breaker {
+            if circuit_breaker.halt > max {
+                anyhow::bail!("The circuit breaker halt must not be longer than a year");
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_heartbeat_validation() {
+        let parse = |input: &str| {
+            let config: Config = toml::from_str(input).unwrap();
+            config.validate().map(|_| config.heartbeat)
+        };
+        assert!(parse("").unwrap().is_none());
+        let heartbeat = parse("[heartbeat]\ninterval = \"1s\"\ntimeout = \"3s\"\n")
+            .unwrap()
+            .unwrap();
+        assert_eq!(heartbeat.interval, Duration::from_secs(1));
+        assert_eq!(heartbeat.timeout, Duration::from_secs(3));
+        assert!(!heartbeat.cancel_on_disconnect);
+
+        assert!(parse("[heartbeat]\ninterval = \"0s\"\n").is_err());
+        assert!(parse("[heartbeat]\ninterval = \"5s\"\ntimeout = \"5s\"\n").is_err());
+        assert!(parse("[heartbeat]\ninterval = \"1s\"\ntimeout = \"0s\"\n").is_err());
+    }
+
+    #[test]
+    fn test_tls_validation() {
+        let parse = |input: &str| toml::from_str::<Config>(input).unwrap().validate();
+        let tls = "cert = \"cert.pem\"\nkey = \"key.pem\"\n";
+        let user = "[[tls.users]]\ncert = \"user.pem\"\nuser_id = 1\n";
+        assert!(parse(&format!("[tls]\n{}", tls)).is_ok());
+        assert!(parse(&format!("[tls]\n{}client_ca = \"ca.pem\"\n{}", tls, user)).is_ok());
+        assert!(parse(&format!("[tls]\n{}{}", tls, user)).is_err());
+        let listener = format!(
+            "[[listeners]]\nlisten = \"127.0.0.1:8070\"\n[listeners.tls]\n{}{}",
+            tls,
+            user.replace("tls.users", "listeners.tls.users")
+        );
+        assert!(parse(&listener).is_err());
+    }
+
+    #[test]
+    fn test_timer_validation() {
+        let parse = |input: &str| toml::from_str::<Config>(input).unwrap().validate();
+        assert!(parse("batch_auctions = { ONION = { interval = \"500ms\" } }").is_ok());
+        assert!(parse("batch_auctions = { ONION = { interval = \"365days\" } }").is_ok());
+        assert!(parse("batch_auctions = { ONION = { interval = \"0s\" } }").is_err());
+        assert!(parse("batch_auctions = { ONION = { interval = \"366days\" } }").is_err());
+        let circuit_breaker = |halt: &str| {
+            format!(
+                "[circuit_breaker]\nmax_move = 10.0\nwindow = \"1m\"\nhalt = \"{}\"\n",
+                halt
+            )
+        };
+        assert!(parse(&circuit_breaker("5m")).is_ok());
+        assert!(parse(&circuit_breaker("3years")).is_err());
+    }
+}
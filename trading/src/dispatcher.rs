@@ -0,0 +1,581 @@
+/// Author: Tomasz Kulik
+/// 
+///
+
+use crate::config::SessionConfig;
+use crate::drop_copy::{Execution, Subscription};
+use crate::journal::{Entry, Journal};
+use crate::listener::{Peer, UserIds};
+use crate::message::{Message, SequenceNumber, SessionId};
+use crate::order::UserId;
+use crate::protocol::Protocol;
+use crate::server::{timestamp, Event};
+use crate::transaction::Transaction;
+use ring::rand::{self, SystemRandom};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{self, Receiver, Sender, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{oneshot, watch};
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+/// A request handled by the dispatcher.
+#[derive(Debug)]
+pub(crate) enum Dispatch {
+    /// A new user has connected.
+    Login(UserId, User),
+    /// The user's connection from the given peer was closed.
+    Logout(UserId, Peer),
+    /// The user asked for the session, see `Session`.
+    StartSession(UserId),
+    /// The user asked to continue the session after reconnecting.
+    /// Answers the ID of the user the session belongs to.
+    Resume {
+        user_id: UserId,
+        session: SessionId,
+        last_seq: SequenceNumber,
+        reply: oneshot::Sender<Result<UserId, String>>,
+    },
+    /// Set whether the user's orders are cancelled once
+    /// its connection is lost.
+    CancelOnDisconnect(UserId, bool),
+    /// Send the message to a single user.
+    Send(UserId, Message),
+    /// Send the message to every connected user.
+    Broadcast(Message),
+    /// List the connected users.
+    Users(oneshot::Sender<Vec<(UserId, Peer)>>),
+    /// Close the user's connection, answers whether the
+    /// user was connected.
+    Disconnect(UserId, oneshot::Sender<bool>),
+    /// The transaction took place, sent to the drop-copy users.
+    Execution(Transaction),
+    /// A new drop-copy user has subscribed.
+    DropCopy(Subscription),
+}
+
+/// How many messages may wait to be written to a single user.
+///
+/// The user who falls that far behind is disconnected, its
+/// started session is kept, so it may be resumed.
+const QUEUE_SIZE: usize = 10000;
+
+/// A connected user.
+#[derive(Debug)]
+pub(crate) struct User {
+    /// The messages written to the user's connection, along
+    /// with their sequence numbers if the user asked for them.
+    messages: Sender<(Option<SequenceNumber>, Message)>,
+    peer: Peer,
+    /// Dropping it closes the user's connection.
+    _connection: oneshot::Sender<()>,
+}
+
+impl User {
+    /// Create the user writing its messages to the given writer.
+    ///
+    /// The messages are written by a separate task, so a slow
+    /// user does not hold up anyone else, up to `QUEUE_SIZE`
+    /// messages are waiting to be written. The heartbeat, if any, is
+    /// written once nothing else was written for its interval. The
+    /// messages are encoded with the connection's current protocol.
+    pub fn new<W>(
+        writer: W,
+        peer: Peer,
+        connection: oneshot::Sender<()>,
+        heartbeat: Option<Duration>,
+        protocol: watch::Receiver<Protocol>,
+    ) -> User
+    where
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let (messages, receiver) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(writer_task(writer, receiver, heartbeat, protocol));
+        User {
+            messages,
+            peer,
+            _connection: connection,
+        }
+    }
+}
+
+/// Every message sent to the user since the login gets the next
+/// sequence number, and the recent messages are retained.
+///
+/// Once the user starts the session with `SESSION`, the numbers
+/// are sent along with the messages. If the connection drops, the
+/// session outlives it for a while and keeps collecting the messages,
+/// so the user may reconnect with `RESUME:<SESSION>:<LAST_SEQ>` and
+/// receive everything it has missed, the messages sent before the
+/// session was started included.
+struct Session {
+    /// Assigned once the user starts the session.
+    id: Option<SessionId>,
+    connection: Option<User>,
+    last_seq: SequenceNumber,
+    retained: VecDeque<(SequenceNumber, Message)>,
+    disconnected_at: Option<Instant>,
+    cancel_on_disconnect: bool,
+}
+
+impl Session {
+    fn new(connection: User, cancel_on_disconnect: bool) -> Session {
+        Session {
+            id: None,
+            connection: Some(connection),
+            last_seq: 0,
+            retained: VecDeque::new(),
+            disconnected_at: None,
+            cancel_on_disconnect,
+        }
+    }
+
+    /// Numbers the message, retains it and sends it to the user.
+    ///
+    /// Returns false if the user is not reachable anymore, see `write`.
+    fn send(&mut self, message: Message, retention: usize) -> bool {
+        self.last_seq += 1;
+        if retention > 0 {
+            if self.retained.len() == retention {
+                self.retained.pop_front();
+            }
+            self.retained.push_back((self.last_seq, message.clone()));
+        }
+        let seq = self.id.map(|_| self.last_seq);
+        self.write(seq, message)
+    }
+
+    /// Sends the message to the user as it is.
+    ///
+    /// Returns false if the user is not reachable anymore, or it
+    /// has too many messages waiting already.
+    fn write(&mut self, seq: Option<SequenceNumber>, message: Message) -> bool {
+        let connection = match &self.connection {
+            Some(connection) => connection,
+            None => return true,
+        };
+        match connection.messages.try_send((seq, message)) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                warn!(peer = %connection.peer, "too many messages waiting");
+                false
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+}
+
+/// Fans out the messages produced by the shards to the users
+///
+/// Every shard sends its messages through the same channel,
+/// so the messages of a single product reach every user in
+/// the order they were produced.
+///
+/// The event handler is told once a user with cancel-on-disconnect
+/// loses its connection, so its orders are cancelled. The IDs of the
+/// users with the kept sessions are not given to anyone else.
+/// The user connecting again with the same ID, i.e. with the same
+/// client certificate, continues its kept session.
+///
+/// Every execution gets the next sequence number of the drop-copy
+/// feed, following the last one in the journal, and is recorded
+/// in the journal before it's sent to the drop-copy users.
+pub(crate) async fn dispatcher(
+    mut receiver: UnboundedReceiver<Dispatch>,
+    user_ids: Arc<UserIds>,
+    config: SessionConfig,
+    cancel_on_disconnect: bool,
+    events: UnboundedSender<Event>,
+    journal: Option<Journal>,
+    last_execution: SequenceNumber,
+) {
+    let mut dispatcher = Dispatcher {
+        sessions: HashMap::new(),
+        ids: HashMap::new(),
+        user_ids,
+        config,
+        cancel_on_disconnect,
+        events,
+        random: SystemRandom::new(),
+        journal,
+        last_execution,
+        drop_copies: vec![],
+    };
+    let mut expiry = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        tokio::select! {
+            dispatch = receiver.recv() => match dispatch {
+                Some(dispatch) => dispatcher.handle(dispatch),
+                None => return,
+            },
+            _ = expiry.tick() => dispatcher.expire(),
+        }
+    }
+}
+
+struct Dispatcher {
+    sessions: HashMap<UserId, Session>,
+    /// The users the started sessions belong to.
+    ids: HashMap<SessionId, UserId>,
+    user_ids: Arc<UserIds>,
+    config: SessionConfig,
+    /// The default policy of the new users.
+    cancel_on_disconnect: bool,
+    events: UnboundedSender<Event>,
+    /// Makes the session IDs hard to guess.
+    random: SystemRandom,
+    journal: Option<Journal>,
+    last_execution: SequenceNumber,
+    drop_copies: Vec<UnboundedSender<Execution>>,
+}
+
+impl Dispatcher {
+    fn handle(&mut self, dispatch: Dispatch) {
+        match dispatch {
+            Dispatch::Login(user_id, user) => {
+                // The listeners never let two connections share an ID,
+                // but the session may have been resumed elsewhere.
+                let connected = self
+                    .sessions
+                    .get(&user_id)
+                    .is_some_and(|session| session.connection.is_some());
+                if connected {
+                    // Dropping the user closes its connection.
+                    warn!(user_id, "user already connected");
+                    return;
+                }
+                match self.sessions.get_mut(&user_id) {
+                    Some(session) => {
+                        info!(user_id, "user reconnected, session continued");
+                        session.connection = Some(user);
+                        session.disconnected_at = None;
+                    }
+                    None => {
+                        let session = Session::new(user, self.cancel_on_disconnect);
+                        self.sessions.insert(user_id, session);
+                    }
+                }
+            }
+            Dispatch::Logout(user_id, peer) => {
+                let current = self
+                    .sessions
+                    .get(&user_id)
+                    .and_then(|s| s.connection.as_ref());
+                if current.is_some_and(|connection| connection.peer == peer) {
+                    self.disconnect(user_id);
+                }
+            }
+            Dispatch::StartSession(user_id) => self.start_session(user_id),
+            Dispatch::Resume {
+                user_id,
+                session,
+                last_seq,
+                reply,
+            } => {
+                let _ = reply.send(self.resume(user_id, session, last_seq));
+            }
+            Dispatch::CancelOnDisconnect(user_id, enabled) => {
+                if let Some(session) = self.sessions.get_mut(&user_id) {
+                    info!(user_id, enabled, "cancel on disconnect");
+                    session.cancel_on_disconnect = enabled;
+                }
+            }
+            Dispatch::Send(user_id, message) => {
+                let retention = self.config.retention;
+                let unreachable = self
+                    .sessions
+                    .get_mut(&user_id)
+                    .is_some_and(|session| !session.send(message, retention));
+                if unreachable {
+                    self.disconnect(user_id);
+                }
+            }
+            Dispatch::Broadcast(message) => {
+                let retention = self.config.retention;
+                let unreachable: Vec<UserId> = self
+                    .sessions
+                    .iter_mut()
+                    .filter_map(|(user_id, session)| {
+                        match session.send(message.clone(), retention) {
+                            true => None,
+                            false => Some(*user_id),
+                        }
+                    })
+                    .collect();
+                for user_id in unreachable {
+                    self.disconnect(user_id);
+                }
+            }
+            Dispatch::Users(reply) => {
+                let mut list: Vec<_> = self
+                    .sessions
+                    .iter()
+                    .filter_map(|(user_id, session)| {
+                        let connection = session.connection.as_ref()?;
+                        Some((*user_id, connection.peer))
+                    })
+                    .collect();
+                list.sort();
+                let _ = reply.send(list);
+            }
+            Dispatch::Disconnect(user_id, reply) => {
+                let connected = self
+                    .sessions
+                    .get(&user_id)
+                    .is_some_and(|session| session.connection.is_some());
+                if connected {
+                    info!(user_id, "user disconnected by the operator");
+                    self.disconnect(user_id);
+                }
+                let _ = reply.send(connected);
+            }
+            Dispatch::Execution(transaction) => {
+                self.last_execution += 1;
+                let execution = Execution {
+                    seq: self.last_execution,
+                    transaction,
+                };
+                if let Some(journal) = &self.journal {
+                    journal.write(timestamp(), Entry::Execution(execution.clone()));
+                }
+                self.drop_copies
+                    .retain(|drop_copy| drop_copy.send(execution.clone()).is_ok());
+            }
+            Dispatch::DropCopy(subscription) => {
+                let written = self.journal.as_ref().map(Journal::sync);
+                let _ = subscription.reply.send((self.last_execution + 1, written));
+                self.drop_copies.push(subscription.executions);
+            }
+        }
+    }
+
+    /// Assigns the session ID and starts sending the sequence numbers.
+    fn start_session(&mut self, user_id: UserId) {
+        let id = match self.sessions.get(&user_id).and_then(|session| session.id) {
+            Some(id) => id,
+            None => {
+                let id = self.new_session_id();
+                self.ids.insert(id, user_id);
+                id
+            }
+        };
+        if let Some(session) = self.sessions.get_mut(&user_id) {
+            session.id = Some(id);
+            self.user_ids.keep_session(user_id);
+            info!(user_id, session = id, "session started");
+            let seq = session.last_seq;
+            session.write(None, Message::Session { session: id, seq });
+        }
+    }
+
+    /// Moves the user's connection to the resumed session and sends
+    /// the messages retained after `last_seq`.
+    ///
+    /// If some of the missed messages are not retained anymore, or
+    /// do not fit the connection's queue, the user is told about the
+    /// gap before the rest is sent.
+    fn resume(
+        &mut self,
+        user_id: UserId,
+        id: SessionId,
+        last_seq: SequenceNumber,
+    ) -> Result<UserId, String> {
+        let reject = |sessions: &mut HashMap<UserId, Session>, reason: String| {
+            if let Some(session) = sessions.get_mut(&user_id) {
+                session.write(None, Message::Error(reason.clone()));
+            }
+            Err(reason)
+        };
+        let owner = match self.ids.get(&id) {
+            Some(owner) => *owner,
+            None => return reject(&mut self.sessions, format!("Unknown session: {}", id)),
+        };
+        let session_last_seq = self.sessions[&owner].last_seq;
+        if last_seq > session_last_seq {
+            return reject(
+                &mut self.sessions,
+                format!("Invalid sequence number: {}", last_seq),
+            );
+        }
+        if owner != user_id {
+            let connection = self
+                .sessions
+                .remove(&user_id)
+                .and_then(|session| {
+                    if let Some(id) = session.id {
+                        self.ids.remove(&id);
+                        self.user_ids.remove_session(user_id);
+                    }
+                    session.connection
+                })
+                .ok_or_else(|| "The user is not connected".to_string())?;
+            let session = self.sessions.get_mut(&owner).expect("The session exists");
+            session.connection = Some(connection);
+            session.disconnected_at = None;
+        }
+        info!(user_id = owner, session = id, last_seq, "session resumed");
+
+        let session = self.sessions.get_mut(&owner).expect("The session exists");
+        session.write(
+            None,
+            Message::Session {
+                session: id,
+                seq: session.last_seq,
+            },
+        );
+        let mut missed: Vec<_> = session
+            .retained
+            .iter()
+            .filter(|(seq, _)| *seq > last_seq)
+            .cloned()
+            .collect();
+        // The queue holds the gap and the oldest messages are skipped.
+        let room = session
+            .connection
+            .as_ref()
+            .map_or(0, |connection| connection.messages.capacity())
+            .saturating_sub(1);
+        if missed.len() > room {
+            missed.drain(..missed.len() - room);
+        }
+        let first_retained = missed.first().map_or(session.last_seq + 1, |(seq, _)| *seq);
+        if first_retained > last_seq + 1 {
+            warn!(
+                from = last_seq + 1,
+                to = first_retained - 1,
+                "gap not filled"
+            );
+            session.write(
+                None,
+                Message::Gap {
+                    from: last_seq + 1,
+                    to: first_retained - 1,
+                },
+            );
+        }
+        for (seq, message) in missed {
+            session.write(Some(seq), message);
+        }
+        Ok(owner)
+    }
+
+    /// Drops the user's connection, the started session is kept
+    /// until it expires.
+    fn disconnect(&mut self, user_id: UserId) {
+        let session = match self.sessions.get_mut(&user_id) {
+            Some(session) => session,
+            None => return,
+        };
+        if session.connection.take().is_none() {
+            return;
+        }
+        if session.cancel_on_disconnect {
+            let _ = self.events.send(Event::Disconnected(user_id));
+        }
+        match session.id {
+            Some(_) => {
+                session.disconnected_at = Some(Instant::now());
+                info!(user_id, "user disconnected, session kept");
+            }
+            None => {
+                warn!(user_id, "removing user");
+                self.sessions.remove(&user_id);
+            }
+        }
+    }
+
+    /// Removes the sessions nobody has resumed in time.
+    fn expire(&mut self) {
+        let timeout = self.config.timeout;
+        let ids = &mut self.ids;
+        let user_ids = &self.user_ids;
+        self.sessions.retain(|user_id, session| {
+            let expired = session
+                .disconnected_at
+                .is_some_and(|disconnected_at| disconnected_at.elapsed() > timeout);
+            if expired {
+                info!(user_id, "session expired");
+                if let Some(id) = session.id {
+                    ids.remove(&id);
+                    user_ids.remove_session(*user_id);
+                }
+            }
+            !expired
+        });
+    }
+
+    fn new_session_id(&mut self) -> SessionId {
+        loop {
+            let bytes = rand::generate(&self.random).expect("The system random generator failed");
+            let id = SessionId::from_be_bytes(bytes.expose());
+            if id != 0 && !self.ids.contains_key(&id) {
+                return id;
+            }
+        }
+    }
+}
+
+/// Writes the user's messages until the user is removed
+///
+/// The messages waiting in the channel are written together
+/// and flushed at once.
+async fn writer_task<W>(
+    writer: W,
+    mut receiver: Receiver<(Option<SequenceNumber>, Message)>,
+    heartbeat: Option<Duration>,
+    protocol: watch::Receiver<Protocol>,
+) where
+    W: AsyncWrite + Unpin,
+{
+    let mut writer = BufWriter::new(writer);
+    let mut buffer = vec![];
+    loop {
+        let received = match heartbeat {
+            // The heartbeats are not numbered, nor retained.
+            Some(heartbeat) => tokio::time::timeout(heartbeat, receiver.recv())
+                .await
+                .unwrap_or(Some((None, Message::Heartbeat))),
+            None => receiver.recv().await,
+        };
+        let message = match received {
+            Some(message) => message,
+            None => break,
+        };
+        let mut pending = Some(message);
+        while let Some((seq, message)) = pending {
+            buffer.clear();
+            protocol.borrow().encode(seq, &message, &mut buffer);
+            if writer.write_all(&buffer).await.is_err() {
+                return;
+            }
+            pending = receiver.try_recv().ok();
+        }
+        if writer.flush().await.is_err() {
+            return;
+        }
+    }
+    let _ = writer.shutdown().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_slow_user_is_unreachable() {
+        // The user never reads its messages.
+        let (writer, _reader) = tokio::io::duplex(64);
+        let (connection, _disconnected) = oneshot::channel();
+        let (_protocol, protocol) = watch::channel(Protocol::Text);
+        let peer = Peer::Unix(1);
+        let user = User::new(writer, peer, connection, None, protocol);
+        let mut session = Session::new(user, false);
+        let sent = (0..QUEUE_SIZE * 2)
+            .take_while(|_| session.send(Message::Heartbeat, 0))
+            .count();
+        assert!((QUEUE_SIZE..QUEUE_SIZE * 2).contains(&sent), "{}", sent);
+    }
+}
@@ -0,0 +1,269 @@
+/// Author: Tomasz Kulik
+/// 
+///
+
+use crate::admin::authorized;
+use crate::config::DropCopyConfig;
+use crate::journal::{Entry, Record};
+use crate::listener::{Peer, Socket};
+use crate::message::{parse_size, size, SequenceNumber};
+use crate::server::Event;
+use crate::transaction::{Party, Transaction};
+use std::path::PathBuf;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::mpsc::{unbounded_channel, Sender};
+use tokio::sync::oneshot;
+use tracing::{info, info_span, warn, Instrument};
+
+/// A single transaction, as seen by the drop-copy users.
+///
+/// Every execution in the market gets the next sequence number,
+/// which carries on across the restarts of the server, as the
+/// executions are recorded in the journal. It's rendered as
+/// `EXEC:<SEQ>:<PRODUCT>:<BUY_USER_ID>:<BUY_ORDER_ID>:<SELL_USER_ID>:<SELL_ORDER_ID>:<QUANTITY>[@<PRICE>]`,
+/// e.g. `EXEC:7:APPLE:51234:1:4000:2:5@100`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Execution {
+    pub seq: SequenceNumber,
+    pub transaction: Transaction,
+}
+
+impl std::fmt::Display for Execution {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Transaction {
+            product,
+            quantity,
+            price,
+            buy,
+            sell,
+            ..
+        } = &self.transaction;
+        write!(
+            f,
+            "EXEC:{}:{}:{}:{}:{}:{}:{}",
+            self.seq,
+            product,
+            buy.user_id,
+            buy.order_id,
+            sell.user_id,
+            sell.order_id,
+            size(*quantity, *price)
+        )
+    }
+}
+
+impl std::str::FromStr for Execution {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Execution, String> {
+        let invalid = || format!("Invalid execution: {}", input);
+        let party = |user_id: &str, order_id: &str| {
+            Ok::<_, String>(Party {
+                user_id: user_id.parse().map_err(|_| invalid())?,
+                order_id: order_id.parse().map_err(|_| invalid())?,
+                short_form: false,
+            })
+        };
+        let fields: Vec<&str> = input.split(':').collect();
+        match fields[..] {
+            ["EXEC", seq, product, buy_user_id, buy_order_id, sell_user_id, sell_order_id, size] => {
+                let (quantity, price) = parse_size(size).ok_or_else(invalid)?;
+                Ok(Execution {
+                    seq: seq.parse().map_err(|_| invalid())?,
+                    transaction: Transaction {
+                        product: product.parse()?,
+                        quantity,
+                        price,
+                        buy: party(buy_user_id, buy_order_id)?,
+                        sell: party(sell_user_id, sell_order_id)?,
+                        // The drop-copy users are not told about it.
+                        aggressor: None,
+                    },
+                })
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// A drop-copy user asking for the executions.
+#[derive(Debug)]
+pub(crate) struct Subscription {
+    /// Every execution from now on is sent through it.
+    pub executions: tokio::sync::mpsc::UnboundedSender<Execution>,
+    /// Answers the sequence number of the next execution, along
+    /// with the notification that every previous execution is
+    /// written to the journal, if it's enabled.
+    pub reply: oneshot::Sender<(SequenceNumber, Option<oneshot::Receiver<()>>)>,
+}
+
+/// Accepts the drop-copy users, e.g. the risk and back office
+///
+/// The drop-copy users can't trade, they only receive every
+/// execution in the market as it takes place, optionally preceded
+/// by the executions recorded in the journal.
+pub(crate) async fn drop_copy_handler(
+    config: Option<DropCopyConfig>,
+    journal: Option<PathBuf>,
+    event_notification_sender: Sender<Event>,
+) -> anyhow::Result<()> {
+    let config = match config {
+        Some(config) => config,
+        None => return Ok(()),
+    };
+    let socket = Socket::bind(&config.listen).await?;
+    info!(drop_copy = %config.listen, "listening");
+    let mut connections = 0;
+    loop {
+        let (stream, peer) = socket.accept(&mut connections).await?;
+        spawn_connection(stream, peer, &config, &journal, &event_notification_sender);
+    }
+}
+
+fn spawn_connection<S>(
+    stream: S,
+    peer: Peer,
+    config: &DropCopyConfig,
+    journal: &Option<PathBuf>,
+    event_notification_sender: &Sender<Event>,
+) where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let span = info_span!("drop_copy", %peer);
+    let token = config.token.clone();
+    let journal = journal.clone();
+    let event_notification_sender = event_notification_sender.clone();
+    tokio::spawn(
+        async move {
+            info!("drop-copy user connected");
+            if let Err(e) =
+                handle_connection(stream, token, journal, event_notification_sender).await
+            {
+                warn!(error = %e, "connection error");
+            }
+            info!("drop-copy user disconnected");
+        }
+        .instrument(span),
+    );
+}
+
+/// Handles a single drop-copy connection
+///
+/// The first line has to be `AUTH:<TOKEN>`, answered with `OK`.
+/// The second one is either `SUBSCRIBE` for the executions from
+/// now on, or `SUBSCRIBE:<SEQ>` for the executions starting with
+/// the given sequence number, replayed out of the journal, also
+/// answered with `OK`. Every execution is sent as a single line,
+/// see `Execution`. Nothing else is accepted.
+async fn handle_connection<S>(
+    stream: S,
+    token: String,
+    journal: Option<PathBuf>,
+    event_notification_sender: Sender<Event>,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader);
+
+    if !authorized(&mut reader, &token).await? {
+        warn!("drop-copy user not authorized");
+        writer.write_all(b"ERROR:unauthorized\n").await?;
+        return Ok(());
+    }
+    writer.write_all(b"OK\n").await?;
+    let mut lines = reader.lines();
+
+    let from = match lines.next_line().await?.as_deref().map(str::trim) {
+        Some("SUBSCRIBE") => None,
+        Some(line) => match line.strip_prefix("SUBSCRIBE:").map(str::parse) {
+            Some(Ok(from)) => Some(from),
+            _ => {
+                writer.write_all(b"ERROR:expected SUBSCRIBE\n").await?;
+                return Ok(());
+            }
+        },
+        None => return Ok(()),
+    };
+    if from.is_some() && journal.is_none() {
+        writer.write_all(b"ERROR:the journal is disabled\n").await?;
+        return Ok(());
+    }
+
+    let (executions, mut receiver) = unbounded_channel();
+    let (reply, next) = oneshot::channel();
+    let subscription = Subscription { executions, reply };
+    event_notification_sender
+        .send(Event::Connection(crate::dispatcher::Dispatch::DropCopy(
+            subscription,
+        )))
+        .await?;
+    let (next, written) = next.await?;
+    info!(?from, next, "drop-copy subscribed");
+    writer.write_all(b"OK\n").await?;
+
+    // The executions from before the subscription are taken from
+    // the journal, the later ones wait in the channel meanwhile.
+    if let (Some(from), Some(journal)) = (from, journal) {
+        if let Some(written) = written {
+            written.await?;
+        }
+        let file = tokio::fs::File::open(&journal).await?;
+        let mut records = BufReader::new(file).lines();
+        while let Some(line) = records.next_line().await? {
+            if let Ok(Record {
+                entry: Entry::Execution(execution),
+                ..
+            }) = line.parse()
+            {
+                if execution.seq >= from && execution.seq < next {
+                    writer
+                        .write_all(format!("{}\n", execution).as_bytes())
+                        .await?;
+                }
+            }
+        }
+    }
+
+    // The connection is read-only, it's only watched for closing.
+    loop {
+        tokio::select! {
+            execution = receiver.recv() => match execution {
+                Some(execution) => {
+                    if from.is_none_or(|from| execution.seq >= from) {
+                        writer.write_all(format!("{}\n", execution).as_bytes()).await?;
+                    }
+                }
+                None => return Ok(()),
+            },
+            line = lines.next_line() => if line?.is_none() {
+                return Ok(());
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_execution_round_trip() {
+        let lines = [
+            "EXEC:7:APPLE:51234:1:4000:2:5@100",
+            "EXEC:8:PEAR:4000:3:4000:4:10",
+        ];
+        for line in lines.iter() {
+            let execution: Execution = line.parse().unwrap();
+            assert_eq!(execution.to_string(), *line);
+        }
+        let execution: Execution = lines[0].parse().unwrap();
+        assert_eq!(execution.transaction.buy.user_id, 51234);
+        assert_eq!(execution.transaction.sell.order_id, 2);
+        assert!("EXEC:7:APPLE:51234:1:4000:2".parse::<Execution>().is_err());
+        assert!("EXEC:x:APPLE:51234:1:4000:2:5"
+            .parse::<Execution>()
+            .is_err());
+    }
+}
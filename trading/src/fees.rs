@@ -0,0 +1,141 @@
+/// Author: Tomasz Kulik
+/// 
+///
+
+use crate::config::FeeSchedule;
+use crate::order::{Quantity, UserId};
+use std::convert::TryFrom;
+
+/// The amount of money in ticks, e.g. the fee charged to the user.
+/// The negative fees are the rebates paid to the user.
+pub type Amount = i64;
+
+impl FeeSchedule {
+    /// The fee for the quantity traded by the user, who has
+    /// already traded the given volume of the product.
+    ///
+    /// The rates are in ticks per unit traded, whatever the price,
+    /// e.g. the taker rate of 2 charges 10 ticks for 5 units.
+    ///
+    /// The tier with the highest volume reached applies, or
+    /// the base rates if none is reached.
+    pub fn fee(&self, volume: Quantity, maker: bool, quantity: Quantity) -> Amount {
+        let (maker_rate, taker_rate) = self
+            .tiers
+            .iter()
+            .filter(|tier| tier.volume <= volume)
+            .max_by_key(|tier| tier.volume)
+            .map_or((self.maker, self.taker), |tier| (tier.maker, tier.taker));
+        let rate = match maker {
+            true => maker_rate,
+            false => taker_rate,
+        };
+        rate.saturating_mul(Amount::try_from(quantity).unwrap_or(Amount::MAX))
+    }
+}
+
+/// The user's account at the end of the day, or so far, as the
+/// user asks for it with `STATEMENT`.
+///
+/// The gross amount is what the user has received for the
+/// sold units less what it has paid for the bought ones,
+/// the transactions without the price are left out. It's the
+/// only way the users of the short form orders, who get no
+/// fills, learn about their fees.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Statement {
+    pub user_id: UserId,
+    pub gross: Amount,
+    pub fees: Amount,
+}
+
+impl Statement {
+    /// The gross amount less the fees.
+    pub fn net(&self) -> Amount {
+        self.gross.saturating_sub(self.fees)
+    }
+}
+
+impl std::fmt::Display for Statement {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "STATEMENT:{} gross={} fees={} net={}",
+            self.user_id,
+            self.gross,
+            self.fees,
+            self.net()
+        )
+    }
+}
+
+impl std::str::FromStr for Statement {
+    type Err = String;
+
+    /// Parse the statement, the inverse of `Display`. The net
+    /// amount is left out, as it follows from the others.
+    fn from_str(input: &str) -> Result<Statement, String> {
+        let invalid = || format!("Invalid statement: {}", input);
+        let fields: Vec<&str> = input.split(' ').collect();
+        match fields[..] {
+            [user_id, gross, fees, net] if net.starts_with("net=") => Ok(Statement {
+                user_id: user_id
+                    .strip_prefix("STATEMENT:")
+                    .and_then(|user_id| user_id.parse().ok())
+                    .ok_or_else(invalid)?,
+                gross: gross
+                    .strip_prefix("gross=")
+                    .and_then(|gross| gross.parse().ok())
+                    .ok_or_else(invalid)?,
+                fees: fees
+                    .strip_prefix("fees=")
+                    .and_then(|fees| fees.parse().ok())
+                    .ok_or_else(invalid)?,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::FeeTier;
+
+    #[test]
+    fn test_fee_tiers() {
+        let schedule = FeeSchedule {
+            maker: -1,
+            taker: 3,
+            tiers: vec![
+                FeeTier {
+                    volume: 1000,
+                    maker: -3,
+                    taker: 1,
+                },
+                FeeTier {
+                    volume: 100,
+                    maker: -2,
+                    taker: 2,
+                },
+            ],
+        };
+        assert_eq!(schedule.fee(0, true, 10), -10);
+        assert_eq!(schedule.fee(99, false, 10), 30);
+        assert_eq!(schedule.fee(100, false, 10), 20);
+        assert_eq!(schedule.fee(5000, true, 10), -30);
+        assert_eq!(schedule.fee(0, false, u64::MAX), Amount::MAX);
+
+        let statement = Statement {
+            user_id: 7,
+            gross: -500,
+            fees: 15,
+        };
+        assert_eq!(
+            statement.to_string(),
+            "STATEMENT:7 gross=-500 fees=15 net=-515"
+        );
+        assert_eq!(statement.to_string().parse(), Ok(statement));
+        assert!("STATEMENT:7 gross=-500".parse::<Statement>().is_err());
+    }
+}
@@ -0,0 +1,338 @@
+/// Author: Tomasz Kulik
+/// 
+///
+
+use crate::audit::{hash, is_hash, last_hash};
+use crate::drop_copy::Execution;
+use crate::ledger::Phase;
+use crate::message::{size, SequenceNumber};
+use crate::order::{Order, OrderId, Timestamp, UserId};
+use crate::request::{Cancel, Replace, Request};
+use crate::server::timestamp;
+use crate::transaction::Product;
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+use tracing::{error, info};
+
+/// How often the head of the hash chain is logged, so the journal
+/// truncated or rewritten afterwards fails `verify_audit`.
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A single event sequenced by the event handler.
+///
+/// The record is written as a single line:
+/// `<TIMESTAMP> <USER_ID> <REQUEST>` for the users' requests, e.g.
+/// `1700000000000 51234 BUY:APPLE:5@100`, and
+/// `<TIMESTAMP> - <COMMAND>` for the events changing the state
+/// of the market, e.g. `1700000000000 - PHASE:AUCTION`.
+///
+/// The order IDs are not written, they're assigned again in the
+/// order of the records. The executions are written for the
+/// drop-copy users only, they result from the other records.
+///
+/// The server ends every line with the hash of the line before
+/// it, see `verify_audit`. It's optional when the journal is read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub timestamp: Timestamp,
+    pub entry: Entry,
+}
+
+/// The event recorded in the journal.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Entry {
+    /// `START` - the server was started, every following record
+    /// belongs to the new run, starting with the empty books and
+    /// numbering the orders from 1 again
+    Start,
+    /// `<SIDE>:<PRODUCT>[:<QUANTITY>[@<PRICE>]]` - the user's order
+    Order(Order),
+    /// `CANCEL:<PRODUCT>:<ORDER_ID>` - the user's cancel
+    Cancel(Cancel),
+    /// `REPLACE:<ORDER_ID>:<QUANTITY>[@<PRICE>]` - the user's amend
+    Replace(Replace),
+    /// `PHASE:<PHASE>` - the market phase has changed
+    Phase(Phase),
+    /// `HALT:<PRODUCT>` - the operator has halted the product
+    Halt(Product),
+    /// `RESUME:<PRODUCT>` - the halted product was resumed
+    Resume(Product),
+    /// `AUCTION:<PRODUCT>` - the product only collects the orders
+    AuctionOnly(Product),
+    /// `OPEN:<PRODUCT>` - the auction-only product was opened
+    Open(Product),
+    /// `CANCEL_ALL:USER:<USER_ID>` - the user's orders were cancelled
+    CancelUserOrders(UserId),
+    /// `CANCEL_ALL:PRODUCT:<PRODUCT>` - the product's orders were cancelled
+    CancelProductOrders(Product),
+    /// `CLEAR:<PRODUCT>` - the batch auction of the product was cleared
+    ClearBatch(Product),
+    /// `EXPIRE:<PRODUCT>:<ORDER_ID>` - the good-till-date order expired
+    Expire(Product, OrderId),
+    /// `EXEC:<SEQ>:...` - the transaction took place, see `Execution`
+    Execution(Execution),
+}
+
+impl std::fmt::Display for Record {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ", self.timestamp)?;
+        match &self.entry {
+            Entry::Order(order) => {
+                write!(f, "{} {}:{}", order.user_id, order.side, order.product)?;
+                match order.short_form {
+                    true => Ok(()),
+                    false => write!(
+                        f,
+                        ":{}{}",
+                        size(order.quantity, order.limit),
+                        order.suffix()
+                    ),
+                }
+            }
+            Entry::Cancel(cancel) => write!(f, "{} {}", cancel.user_id, cancel),
+            Entry::Replace(replace) => write!(f, "{} {}", replace.user_id, replace),
+            Entry::Start => write!(f, "- START"),
+            Entry::Phase(phase) => write!(f, "- PHASE:{}", phase),
+            Entry::Halt(product) => write!(f, "- HALT:{}", product),
+            Entry::Resume(product) => write!(f, "- RESUME:{}", product),
+            Entry::AuctionOnly(product) => write!(f, "- AUCTION:{}", product),
+            Entry::Open(product) => write!(f, "- OPEN:{}", product),
+            Entry::CancelUserOrders(user_id) => write!(f, "- CANCEL_ALL:USER:{}", user_id),
+            Entry::CancelProductOrders(product) => write!(f, "- CANCEL_ALL:PRODUCT:{}", product),
+            Entry::ClearBatch(product) => write!(f, "- CLEAR:{}", product),
+            Entry::Expire(product, order_id) => write!(f, "- EXPIRE:{}:{}", product, order_id),
+            Entry::Execution(execution) => write!(f, "- {}", execution),
+        }
+    }
+}
+
+impl std::str::FromStr for Record {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Record, String> {
+        let invalid = || format!("Invalid record: {}", input);
+        let mut fields = input.split_whitespace();
+        let (timestamp, user_id, line) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(timestamp), Some(user_id), Some(line))
+                if fields.next().is_none_or(is_hash) && fields.next().is_none() =>
+            {
+                (timestamp, user_id, line)
+            }
+            _ => return Err(invalid()),
+        };
+        let timestamp = timestamp.parse().map_err(|_| invalid())?;
+        let entry = match user_id {
+            "-" if line.starts_with("EXEC:") => Entry::Execution(line.parse()?),
+            "-" => {
+                let fields: Vec<&str> = line.split(':').collect();
+                match fields[..] {
+                    ["START"] => Entry::Start,
+                    ["PHASE", phase] => Entry::Phase(phase.parse()?),
+                    ["HALT", product] => Entry::Halt(product.parse()?),
+                    ["RESUME", product] => Entry::Resume(product.parse()?),
+                    ["AUCTION", product] => Entry::AuctionOnly(product.parse()?),
+                    ["OPEN", product] => Entry::Open(product.parse()?),
+                    ["CANCEL_ALL", "USER", user_id] => {
+                        Entry::CancelUserOrders(user_id.parse().map_err(|_| invalid())?)
+                    }
+                    ["CANCEL_ALL", "PRODUCT", product] => {
+                        Entry::CancelProductOrders(product.parse()?)
+                    }
+                    ["CLEAR", product] => Entry::ClearBatch(product.parse()?),
+                    ["EXPIRE", product, order_id] => {
+                        Entry::Expire(product.parse()?, order_id.parse().map_err(|_| invalid())?)
+                    }
+                    _ => return Err(invalid()),
+                }
+            }
+            user_id => {
+                let user_id = user_id.parse().map_err(|_| invalid())?;
+                match Request::new_from_str(user_id, line)? {
+                    Request::Order(mut order) => {
+                        order.timestamp = timestamp;
+                        Entry::Order(order)
+                    }
+                    Request::Cancel(cancel) => Entry::Cancel(cancel),
+                    Request::Replace(mut replace) => {
+                        replace.timestamp = timestamp;
+                        Entry::Replace(replace)
+                    }
+                    _ => return Err(invalid()),
+                }
+            }
+        };
+        Ok(Record { timestamp, entry })
+    }
+}
+
+/// Appends the records to the journal file
+///
+/// The file is written by a separate thread, so the event
+/// handler never waits for the disk.
+#[derive(Debug, Clone)]
+pub(crate) struct Journal {
+    records: UnboundedSender<Pending>,
+}
+
+enum Pending {
+    Record(Record),
+    /// Answered once every previous record is written.
+    Sync(oneshot::Sender<()>),
+}
+
+impl Journal {
+    /// Open the journal, the records are appended to the
+    /// existing file and chained to its last line, following
+    /// the `START` of the new run.
+    pub fn open(path: &Path) -> anyhow::Result<Journal> {
+        let previous = last_hash(path)?;
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        let (records, receiver) = unbounded_channel();
+        std::thread::spawn(move || writer_thread(file, receiver, previous));
+        let journal = Journal { records };
+        journal.write(timestamp(), Entry::Start);
+        Ok(journal)
+    }
+
+    pub fn write(&self, timestamp: Timestamp, entry: Entry) {
+        let record = Record { timestamp, entry };
+        if self.records.send(Pending::Record(record)).is_err() {
+            error!("The journal is not written anymore");
+        }
+    }
+
+    /// Notifies once every record written so far is in the file.
+    pub fn sync(&self) -> oneshot::Receiver<()> {
+        let (sender, receiver) = oneshot::channel();
+        let _ = self.records.send(Pending::Sync(sender));
+        receiver
+    }
+}
+
+/// The sequence number of the last execution recorded in the
+/// journal, zero if there is none yet.
+pub(crate) fn last_execution(path: &Path) -> anyhow::Result<SequenceNumber> {
+    let file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+    let mut last = 0;
+    for line in std::io::BufRead::lines(std::io::BufReader::new(file)) {
+        if let Ok(Record {
+            entry: Entry::Execution(execution),
+            ..
+        }) = line?.parse()
+        {
+            last = execution.seq;
+        }
+    }
+    Ok(last)
+}
+
+/// Writes the records waiting in the channel together
+/// and flushes them at once. The head of the chain is logged
+/// as the `journal checkpoint` at most once every
+/// `CHECKPOINT_INTERVAL`, and once the journal is closed.
+fn writer_thread(
+    file: std::fs::File,
+    mut receiver: UnboundedReceiver<Pending>,
+    mut previous: String,
+) {
+    let mut writer = std::io::BufWriter::new(file);
+    let mut checkpoint = Instant::now();
+    while let Some(pending) = receiver.blocking_recv() {
+        let mut pending = Some(pending);
+        let mut synced = vec![];
+        while let Some(next) = pending {
+            match next {
+                Pending::Record(record) => {
+                    let line = format!("{} {}", record, previous);
+                    if let Err(e) = writeln!(writer, "{}", line) {
+                        error!(error = %e, "unable to write the journal");
+                        return;
+                    }
+                    previous = hash(&line);
+                }
+                Pending::Sync(sender) => synced.push(sender),
+            }
+            pending = receiver.try_recv().ok();
+        }
+        if let Err(e) = writer.flush() {
+            error!(error = %e, "unable to write the journal");
+            return;
+        }
+        for sender in synced {
+            let _ = sender.send(());
+        }
+        if checkpoint.elapsed() >= CHECKPOINT_INTERVAL {
+            info!(head = %previous, "journal checkpoint");
+            checkpoint = Instant::now();
+        }
+    }
+    info!(head = %previous, "journal checkpoint");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_round_trip() {
+        let lines = [
+            "1000 51234 BUY:APPLE",
+            "1001 51234 SELL:PEAR:10",
+            "1002 4000 BUY:ONION:5@120",
+            "1002 4000 SELL:ONION:50@121:ICEBERG:5",
+            "1002 4000 SELL:ONION:9@122:HIDDEN",
+            "1002 4000 SELL:ONION:4:STOP:118",
+            "1002 4000 BUY:ONION:4@125:STOP:124",
+            "1002 4000 BUY:ONION:3@119:ICEBERG:1:GTD:1500",
+            "1002 4000 CANCEL:ONION:3",
+            "1002 4000 REPLACE:3:2@119",
+            "1003 - START",
+            "1003 - PHASE:AUCTION",
+            "1004 - HALT:TOMATO",
+            "1005 - RESUME:TOMATO",
+            "1005 - AUCTION:PEAR",
+            "1005 - OPEN:PEAR",
+            "1006 - CANCEL_ALL:USER:51234",
+            "1007 - CANCEL_ALL:PRODUCT:POTATO",
+            "1007 - EXPIRE:ONION:6",
+            "1007 - CLEAR:ONION",
+            "1008 - EXEC:1:APPLE:51234:1:4000:2:5@100",
+        ];
+        for line in lines.iter() {
+            let record: Record = line.parse().unwrap();
+            assert_eq!(record.to_string(), *line);
+        }
+        let record: Record = "1002 4000 BUY:ONION:5@120".parse().unwrap();
+        match record.entry {
+            Entry::Order(order) => {
+                assert_eq!(order.user_id, 4000);
+                assert_eq!(order.timestamp, 1002);
+                assert_eq!((order.quantity, order.limit), (5, Some(120)));
+            }
+            entry => panic!("Unexpected entry: {:?}", entry),
+        }
+        let chained = format!("1009 - HALT:TOMATO {}", hash("1008 - HALT:POTATO"));
+        assert_eq!(
+            chained.parse::<Record>().unwrap().entry,
+            Entry::Halt(Product::Tomato)
+        );
+        assert!("1009 - HALT:TOMATO 12ab".parse::<Record>().is_err());
+        assert!("1008 - SNAPSHOT".parse::<Record>().is_err());
+        assert!("BUY:APPLE".parse::<Record>().is_err());
+        assert!("1009 4000 BUY:APPLE:5:HIDDEN".parse::<Record>().is_err());
+        assert!("1009 4000 BUY:APPLE:5:STOP:0".parse::<Record>().is_err());
+        assert!("1009 4000 BUY:APPLE:5@100:ICEBERG:0"
+            .parse::<Record>()
+            .is_err());
+    }
+}
@@ -0,0 +1,460 @@
+/// Author: Tomasz Kulik
+/// 
+///
+
+use crate::message::{Message, SequenceNumber, SessionId};
+use crate::order::{Order, OrderId, Price, Quantity, Side, Timestamp, UserId, Visibility};
+use crate::request::{Cancel, Replace, Request};
+use crate::transaction::Product;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+/// A request of the JSON protocol.
+///
+/// Every request is an object with its `type`, e.g.
+/// `{"type": "order", "side": "BUY", "product": "APPLE", "quantity": 5, "price": 100}`
+/// or `{"type": "cancel", "product": "APPLE", "order_id": 1}`. The
+/// iceberg orders have the `"iceberg"` peak and the hidden ones
+/// are marked with `"hidden": true`. The stop orders have the
+/// `"stop"` price and the good-till-date ones have the `"expiry"`
+/// timestamp.
+/// It's decoded into the same requests as the lines of the text
+/// protocol, and checked the same way.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum JsonRequest {
+    Order {
+        side: Side,
+        product: Product,
+        quantity: Option<Quantity>,
+        price: Option<Price>,
+        iceberg: Option<Quantity>,
+        #[serde(default)]
+        hidden: bool,
+        stop: Option<Price>,
+        expiry: Option<Timestamp>,
+    },
+    Cancel {
+        product: Product,
+        order_id: OrderId,
+    },
+    Replace {
+        order_id: OrderId,
+        quantity: Quantity,
+        price: Option<Price>,
+    },
+    Book {
+        product: Product,
+    },
+    Session,
+    Resume {
+        session: SessionId,
+        last_seq: SequenceNumber,
+    },
+    Heartbeat,
+    CancelOnDisconnect {
+        enabled: bool,
+    },
+    Statement,
+}
+
+/// Decode the user's JSON request.
+pub(crate) fn decode_request(user_id: UserId, input: &str) -> Result<Request, String> {
+    let request: JsonRequest =
+        serde_json::from_str(input).map_err(|e| format!("Invalid request: {}", e))?;
+    Ok(match request {
+        JsonRequest::Order {
+            side,
+            product,
+            quantity: None,
+            price: None,
+            iceberg: None,
+            hidden: false,
+            stop: None,
+            expiry: None,
+        } => Request::Order(Order {
+            id: 0,
+            timestamp: 0,
+            user_id,
+            side,
+            product,
+            quantity: 1,
+            limit: None,
+            short_form: true,
+            visibility: Visibility::Visible,
+            stop: None,
+            expiry: None,
+        }),
+        JsonRequest::Order {
+            side,
+            product,
+            quantity: Some(quantity),
+            price,
+            iceberg,
+            hidden,
+            stop,
+            expiry,
+        } => {
+            let (quantity, limit) = size(quantity, price)?;
+            let visibility = match (iceberg, hidden) {
+                (None, false) => Visibility::Visible,
+                (Some(peak), false) => Visibility::Iceberg {
+                    peak: number("iceberg", peak)?,
+                    shown: 0,
+                },
+                (None, true) => Visibility::Hidden,
+                (Some(_), true) => return Err("Invalid order: hidden iceberg".into()),
+            };
+            let stop = stop.map(|stop| number("stop", stop)).transpose()?;
+            match (visibility, stop) {
+                (Visibility::Visible, _) => {}
+                (Visibility::Hidden, Some(_)) => return Err("Invalid order: hidden stop".into()),
+                (Visibility::Iceberg { .. }, Some(_)) => {
+                    return Err("Invalid order: iceberg stop".into())
+                }
+                (_, None) if limit.is_none() => {
+                    return Err("Invalid order: only the limit orders may be hidden".into())
+                }
+                (_, None) => {}
+            }
+            Request::Order(Order {
+                id: 0,
+                timestamp: 0,
+                user_id,
+                side,
+                product,
+                quantity,
+                limit,
+                short_form: false,
+                visibility,
+                stop,
+                expiry: expiry.map(|expiry| number("expiry", expiry)).transpose()?,
+            })
+        }
+        JsonRequest::Order { .. } => return Err("Invalid order: options without quantity".into()),
+        JsonRequest::Cancel { product, order_id } => Request::Cancel(Cancel {
+            user_id,
+            product,
+            order_id,
+        }),
+        JsonRequest::Replace {
+            order_id,
+            quantity,
+            price,
+        } => {
+            let (quantity, limit) = size(quantity, price)?;
+            Request::Replace(Replace {
+                user_id,
+                order_id,
+                quantity,
+                limit,
+                timestamp: 0,
+            })
+        }
+        JsonRequest::Book { product } => Request::Book(product),
+        JsonRequest::Session => Request::Session,
+        JsonRequest::Resume { session, last_seq } => Request::Resume { session, last_seq },
+        JsonRequest::Heartbeat => Request::Heartbeat,
+        JsonRequest::CancelOnDisconnect { enabled } => Request::CancelOnDisconnect(enabled),
+        JsonRequest::Statement => Request::Statement,
+    })
+}
+
+/// Checks the quantity and the price like `parse_size` does.
+fn size(quantity: Quantity, price: Option<Price>) -> Result<(Quantity, Option<Price>), String> {
+    if quantity == 0 {
+        return Err(format!("Invalid quantity: {}", quantity));
+    }
+    match price {
+        Some(price) if price == 0 || price == Price::MAX => {
+            Err(format!("Invalid price: {}", price))
+        }
+        price => Ok((quantity, price)),
+    }
+}
+
+/// Checks the order's option like `Order::new_order_form_str` does.
+fn number(name: &str, value: u64) -> Result<u64, String> {
+    match value {
+        0 | u64::MAX => Err(format!("Invalid {}: {}", name, value)),
+        value => Ok(value),
+    }
+}
+
+/// Render the message as the JSON object, along with its
+/// sequence number if it has one.
+///
+/// The fields are named after the fields of `Message`, the
+/// missing ones are left out, e.g.
+/// `{"type": "fill", "order_id": 1, "quantity": 3, "price": 100, "seq": 7}`.
+pub(crate) fn message_to_json(seq: Option<SequenceNumber>, message: &Message) -> String {
+    let mut value = match message {
+        Message::Ack { product, order_id } => json!({
+            "type": "ack",
+            "product": product.to_string(),
+            "order_id": order_id,
+        }),
+        Message::Error(reason) => json!({"type": "error", "reason": reason}),
+        Message::Fill {
+            order_id,
+            quantity,
+            price,
+            fee,
+        } => json!({
+            "type": "fill",
+            "order_id": order_id,
+            "quantity": quantity,
+            "price": price,
+            "fee": fee,
+        }),
+        Message::Trade {
+            product,
+            quantity,
+            price,
+        } => json!({
+            "type": "trade",
+            "product": product.to_string(),
+            "quantity": quantity,
+            "price": price,
+        }),
+        Message::Indicative {
+            product,
+            volume,
+            price,
+        } => json!({
+            "type": "indicative",
+            "product": product.to_string(),
+            "volume": volume,
+            "price": price,
+        }),
+        Message::Cleared {
+            product,
+            volume,
+            price,
+        } => json!({
+            "type": "cleared",
+            "product": product.to_string(),
+            "volume": volume,
+            "price": price,
+        }),
+        Message::Phase { product, phase } => json!({
+            "type": "phase",
+            "product": product.to_string(),
+            "phase": phase.to_string(),
+        }),
+        Message::Cancelled { product, order_id } => json!({
+            "type": "cancelled",
+            "product": product.to_string(),
+            "order_id": order_id,
+        }),
+        Message::Expired { product, order_id } => json!({
+            "type": "expired",
+            "product": product.to_string(),
+            "order_id": order_id,
+        }),
+        Message::Session { session, seq } => {
+            json!({"type": "session", "session": session, "seq": seq})
+        }
+        Message::Gap { from, to } => json!({"type": "gap", "from": from, "to": to}),
+        Message::Heartbeat => json!({"type": "heartbeat"}),
+        Message::Statement(statement) => json!({
+            "type": "statement",
+            "user_id": statement.user_id,
+            "gross": statement.gross,
+            "fees": statement.fees,
+            "net": statement.net(),
+        }),
+        Message::Book {
+            product,
+            bids,
+            asks,
+        } => {
+            let levels = |levels: &[(Quantity, Price)]| {
+                levels
+                    .iter()
+                    .map(|(quantity, price)| json!({"quantity": quantity, "price": price}))
+                    .collect::<Vec<_>>()
+            };
+            json!({
+                "type": "book",
+                "product": product.to_string(),
+                "bids": levels(bids),
+                "asks": levels(asks),
+            })
+        }
+    };
+    if let Value::Object(fields) = &mut value {
+        fields.retain(|_, value| !value.is_null());
+        if let Some(seq) = seq {
+            fields.insert("seq".to_string(), seq.into());
+        }
+    }
+    value.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ledger::Phase;
+    use crate::transaction::Product;
+
+    #[test]
+    fn test_decode_request() {
+        let requests = [
+            (
+                r#"{"type":"order","side":"BUY","product":"APPLE"}"#,
+                "BUY:APPLE",
+            ),
+            (
+                r#"{"type":"order","side":"SELL","product":"PEAR","quantity":10}"#,
+                "SELL:PEAR:10",
+            ),
+            (
+                r#"{"type":"order","side":"BUY","product":"ONION","quantity":5,"price":120}"#,
+                "BUY:ONION:5@120",
+            ),
+            (
+                r#"{"type":"cancel","product":"ONION","order_id":3}"#,
+                "CANCEL:ONION:3",
+            ),
+            (
+                r#"{"type":"order","side":"SELL","product":"PEAR","quantity":100,"price":90,"iceberg":10}"#,
+                "SELL:PEAR:100@90:ICEBERG:10",
+            ),
+            (
+                r#"{"type":"order","side":"BUY","product":"PEAR","quantity":7,"price":80,"hidden":true}"#,
+                "BUY:PEAR:7@80:HIDDEN",
+            ),
+            (
+                r#"{"type":"order","side":"SELL","product":"PEAR","quantity":10,"stop":95}"#,
+                "SELL:PEAR:10:STOP:95",
+            ),
+            (
+                r#"{"type":"order","side":"BUY","product":"PEAR","quantity":7,"price":80,"hidden":true,"expiry":1700000000000}"#,
+                "BUY:PEAR:7@80:HIDDEN:GTD:1700000000000",
+            ),
+            (r#"{"type":"book","product":"PEAR"}"#, "BOOK:PEAR"),
+            (
+                r#"{"type":"replace","order_id":3,"quantity":2,"price":119}"#,
+                "REPLACE:3:2@119",
+            ),
+            (r#"{"type":"session"}"#, "SESSION"),
+            (
+                r#"{"type":"resume","session":42,"last_seq":7}"#,
+                "RESUME:42:7",
+            ),
+            (r#"{"type":"heartbeat"}"#, "HEARTBEAT"),
+            (
+                r#"{"type":"cancel_on_disconnect","enabled":true}"#,
+                "CANCEL_ON_DISCONNECT:ON",
+            ),
+        ];
+        // Both protocols accept exactly the same requests.
+        for (request, line) in requests.iter() {
+            assert_eq!(
+                decode_request(1, request),
+                Request::new_from_str(1, line),
+                "{}",
+                line
+            );
+        }
+        let invalid = [
+            (
+                r#"{"type":"order","side":"BUY","product":"APPLE","price":5}"#,
+                "Invalid order: options without quantity",
+            ),
+            (
+                r#"{"type":"order","side":"BUY","product":"PEAR","quantity":7,"price":80,"hidden":true,"stop":90}"#,
+                "Invalid order: hidden stop",
+            ),
+            (
+                r#"{"type":"order","side":"BUY","product":"PEAR","quantity":7,"price":80,"iceberg":2,"stop":90}"#,
+                "Invalid order: iceberg stop",
+            ),
+            (
+                r#"{"type":"order","side":"BUY","product":"PEAR","quantity":7,"iceberg":2}"#,
+                "Invalid order: only the limit orders may be hidden",
+            ),
+            (
+                r#"{"type":"order","side":"BUY","product":"PEAR","quantity":0}"#,
+                "Invalid quantity: 0",
+            ),
+            (
+                r#"{"type":"order","side":"BUY","product":"PEAR","quantity":1,"stop":0}"#,
+                "Invalid stop: 0",
+            ),
+            (
+                r#"{"type":"replace","order_id":3,"quantity":2,"price":0}"#,
+                "Invalid price: 0",
+            ),
+        ];
+        for (request, reason) in invalid.iter() {
+            assert_eq!(decode_request(1, request), Err(reason.to_string()));
+        }
+        assert!(decode_request(1, r#"{"type":"snapshot"}"#).is_err());
+        assert!(decode_request(1, "BUY:APPLE").is_err());
+    }
+
+    #[test]
+    fn test_message_to_json() {
+        let messages = [
+            (
+                None,
+                Message::Ack {
+                    product: Product::Apple,
+                    order_id: None,
+                },
+                json!({"type": "ack", "product": "APPLE"}),
+            ),
+            (
+                Some(7),
+                Message::Fill {
+                    order_id: 1,
+                    quantity: 3,
+                    price: Some(100),
+                    fee: Some(6),
+                },
+                json!({"type": "fill", "order_id": 1, "quantity": 3, "price": 100, "fee": 6, "seq": 7}),
+            ),
+            (
+                None,
+                Message::Phase {
+                    product: Product::Pear,
+                    phase: Phase::Auction,
+                },
+                json!({"type": "phase", "product": "PEAR", "phase": "AUCTION"}),
+            ),
+            (
+                None,
+                Message::Error("APPLE market closed".to_string()),
+                json!({"type": "error", "reason": "APPLE market closed"}),
+            ),
+            (
+                Some(8),
+                Message::Expired {
+                    product: Product::Onion,
+                    order_id: 3,
+                },
+                json!({"type": "expired", "product": "ONION", "order_id": 3, "seq": 8}),
+            ),
+            (
+                None,
+                Message::Book {
+                    product: Product::Pear,
+                    bids: vec![(5, 100), (3, 99)],
+                    asks: vec![],
+                },
+                json!({
+                    "type": "book",
+                    "product": "PEAR",
+                    "bids": [{"quantity": 5, "price": 100}, {"quantity": 3, "price": 99}],
+                    "asks": [],
+                }),
+            ),
+        ];
+        for (seq, message, expected) in messages.iter() {
+            let actual: Value = serde_json::from_str(&message_to_json(*seq, message)).unwrap();
+            assert_eq!(&actual, expected);
+        }
+    }
+}
@@ -2,130 +2,1366 @@
 /// 
 ///
 
-use crate::order::Order;
+use crate::config::{CircuitBreaker, Config, HaltedOrders};
+use crate::matching::MatchingPolicy;
+use crate::order::{Order, Price, Quantity, Side, Timestamp, Visibility, MAX_EXPIRY_HORIZON};
+use crate::request::Replace;
 ///
 /// This module implements the bussiness logic of the system.
 ///
 use crate::transaction::{Product, Transaction};
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+
+/// The trading phase of a product.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    /// Every incoming order is matched right away.
+    Continuous,
+    /// Orders are collected without matching. Once the
+    /// phase ends, the book is uncrossed at a single price.
+    Auction,
+    /// No orders are accepted.
+    Closed,
+    /// Trading in the product is stopped. The orders are
+    /// either rejected or queued, see `HaltedOrders`. Once
+    /// the product is resumed, the queue is uncrossed like
+    /// after the auction.
+    Halted,
+}
+
+impl std::fmt::Display for Phase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Phase::Continuous => write!(f, "CONTINUOUS"),
+            Phase::Auction => write!(f, "AUCTION"),
+            Phase::Closed => write!(f, "CLOSED"),
+            Phase::Halted => write!(f, "HALTED"),
+        }
+    }
+}
+
+impl std::str::FromStr for Phase {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Phase, String> {
+        match input {
+            "CONTINUOUS" => Ok(Phase::Continuous),
+            "AUCTION" => Ok(Phase::Auction),
+            "CLOSED" => Ok(Phase::Closed),
+            "HALTED" => Ok(Phase::Halted),
+            _ => Err(format!("Unknown phase: {}", input)),
+        }
+    }
+}
+
+/// The number of the price levels of each side in the depth.
+pub const DEPTH_LEVELS: usize = 10;
+
+/// The quantities shown at the price levels of a side of the
+/// book, as `(quantity, price)`, best first.
+pub type Depth = Vec<(Quantity, Price)>;
+
+/// Orders resting at a single price, in the time priority.
+type Level = VecDeque<Order>;
 
 /// A Ledger of a given Product.
 ///
-/// Since the system does not track the authors
-/// of the orders, it's sufficient to keep only the
-/// current balance of the given product.
-/// Positive value means that there's more people
-/// wanting to sell some product, accordingly negative
-/// number indicates people waiting to buy the product.
-pub type ProductLedger = i64;
+/// It's an order book: the buy and the sell orders that
+/// did not match yet, grouped by the price. The orders
+/// without the limit price are kept at the most aggressive
+/// price of their side, i.e. `Price::MAX` for buys and
+/// `0` for sells.
+///
+/// The stop orders are held aside, out of the book, until
+/// a transaction triggers them. The triggered orders enter the
+/// book one by one, the earliest accepted first, and the trades
+/// they make may trigger the next ones.
+pub struct ProductLedger {
+    product: Product,
+    phase: Phase,
+    /// The phase of the whole market, set by the schedule.
+    /// The product returns to it once it's resumed.
+    market_phase: Phase,
+    bids: BTreeMap<Price, Level>,
+    asks: BTreeMap<Price, Level>,
+    /// The stop orders waiting for their trigger, in the order
+    /// of arrival.
+    stops: Vec<Order>,
+    /// The time of the most recent order. The stop orders
+    /// are triggered at it.
+    clock: Timestamp,
+    /// The price of the most recent transaction.
+    last_price: Option<Price>,
+    /// The transactions within the circuit breaker's window.
+    recent_prices: VecDeque<(Timestamp, Price)>,
+    circuit_breaker: Option<CircuitBreaker>,
+    halted_orders: HaltedOrders,
+    /// The rest of the order which tripped the circuit breaker,
+    /// not queued while the halted product rejects the orders.
+    cancelled: Vec<Order>,
+    policy: Box<dyn MatchingPolicy>,
+    /// The continuous trading is replaced by the frequent batch
+    /// auctions, see `BatchAuction`.
+    batch: bool,
+    /// The product collects the orders while the market trades,
+    /// until it's opened, see `set_auction_only`.
+    auction_only: bool,
+}
+
+impl ProductLedger {
+    /// Create a new empty ledger in the continuous phase, or
+    /// collecting the first batch or the auction-only orders.
+    pub fn new(product: Product, config: &Config) -> ProductLedger {
+        let batch = config.batch_auctions.contains_key(&product);
+        let auction_only = config.auction_only.contains(&product);
+        ProductLedger {
+            product,
+            phase: match batch || auction_only {
+                true => Phase::Auction,
+                false => Phase::Continuous,
+            },
+            market_phase: Phase::Continuous,
+            bids: BTreeMap::new(),
+            asks: BTreeMap::new(),
+            stops: vec![],
+            clock: 0,
+            last_price: None,
+            recent_prices: VecDeque::new(),
+            circuit_breaker: config.circuit_breaker.clone(),
+            halted_orders: config.halted_orders,
+            cancelled: vec![],
+            policy: config
+                .matching
+                .get(&product)
+                .copied()
+                .unwrap_or_default()
+                .policy(),
+            batch,
+            auction_only,
+        }
+    }
+
+    pub fn product(&self) -> Product {
+        self.product
+    }
+
+    pub fn phase(&self) -> Phase {
+        self.phase
+    }
+
+    /// Take the orders cancelled by the circuit breaker.
+    pub fn take_cancelled(&mut self) -> Vec<Order> {
+        std::mem::take(&mut self.cancelled)
+    }
+
+    /// Apply a new user's order and return the resulting
+    /// transactions.
+    ///
+    /// The stop order is held until it's triggered, unless the last
+    /// transaction price has already reached its stop price.
+    /// The order is rejected as described by `check`.
+    pub fn handle_user_order(&mut self, mut order: Order) -> Result<Vec<Transaction>, String> {
+        self.check(&order)?;
+        self.clock = self.clock.max(order.timestamp);
+        if order.stop.is_some() {
+            match self.phase {
+                _ if self.trading()
+                    && self.last_price.is_some_and(|last| order.triggered(last)) =>
+                {
+                    order.stop = None;
+                }
+                _ => {
+                    self.stops.push(order);
+                    return Ok(vec![]);
+                }
+            }
+        }
+        match self.phase {
+            Phase::Continuous => {
+                let mut transactions = self.match_order(order);
+                self.trigger_stops(&mut transactions);
+                Ok(transactions)
+            }
+            // The order is checked, so the halted product queues it.
+            Phase::Auction | Phase::Halted => {
+                self.insert(order);
+                Ok(vec![])
+            }
+            Phase::Closed => Err(format!("{} market closed", self.product)),
+        }
+    }
+
+    /// Whether the order may be accepted in the current phase
+    ///
+    /// The good-till-date order is rejected once it has expired,
+    /// or if it expires beyond `MAX_EXPIRY_HORIZON`.
+    fn check(&self, order: &Order) -> Result<(), String> {
+        match order.expiry {
+            Some(expiry) if expiry <= order.timestamp => {
+                return Err(format!("{} order expired", self.product))
+            }
+            Some(expiry) if expiry - order.timestamp > MAX_EXPIRY_HORIZON => {
+                return Err(format!("{} order expiry too far", self.product))
+            }
+            _ => {}
+        }
+        match self.phase {
+            Phase::Closed => Err(format!("{} market closed", self.product)),
+            Phase::Halted if self.halted_orders == HaltedOrders::Reject => {
+                Err(format!("{} trading halted", self.product))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Follow the phase of the whole market.
+    ///
+    /// The halted product stays halted unless the market
+    /// is closed. Ending the auction is the only other action
+    /// that may result in new transactions.
+    pub fn set_market_phase(&mut self, phase: Phase) -> Vec<Transaction> {
+        self.market_phase = phase;
+        match self.phase {
+            Phase::Halted if phase != Phase::Closed => vec![],
+            _ => self.set_phase(phase),
+        }
+    }
+
+    /// Stop trading in the product.
+    pub fn halt(&mut self) {
+        self.set_phase(Phase::Halted);
+    }
+
+    /// Bring the halted product back to the phase of the market.
+    pub fn resume(&mut self) -> Vec<Transaction> {
+        match self.phase {
+            Phase::Halted => self.set_phase(self.market_phase),
+            _ => vec![],
+        }
+    }
+
+    pub fn auction_only(&self) -> bool {
+        self.auction_only
+    }
+
+    /// Switch the product into or out of the auction-only phase.
+    ///
+    /// The auction-only product collects the orders like in the
+    /// auction, whatever the phase of the market, and the book is
+    /// uncrossed only once the product is opened again, or the market
+    /// is closed. The halted product enters the phase once resumed.
+    pub fn set_auction_only(&mut self, auction_only: bool) -> Vec<Transaction> {
+        self.auction_only = auction_only;
+        match self.phase {
+            Phase::Halted => vec![],
+            _ => self.set_phase(self.market_phase),
+        }
+    }
+
+    /// Switch to the given phase.
+    ///
+    /// If the auction or the halt is over, the book is
+    /// uncrossed and the resulting transactions are returned.
+    fn set_phase(&mut self, phase: Phase) -> Vec<Transaction> {
+        // The auction-only product keeps collecting the orders.
+        let phase = match phase {
+            Phase::Continuous if self.auction_only => Phase::Auction,
+            phase => phase,
+        };
+        let mut transactions = match (self.phase, phase) {
+            (Phase::Auction, Phase::Continuous) | (Phase::Auction, Phase::Closed) => self.uncross(),
+            (Phase::Halted, Phase::Continuous) => self.uncross(),
+            _ => vec![],
+        };
+        // The batches are collected like the auction.
+        self.phase = match phase {
+            Phase::Continuous if self.batch => Phase::Auction,
+            phase => phase,
+        };
+        self.trigger_stops(&mut transactions);
+        transactions
+    }
+
+    /// Clear the batch auction at the single price and return the
+    /// resulting transactions. Nothing happens unless the product
+    /// collects the batch, e.g. the scheduled auction is cleared
+    /// only once it's over.
+    pub fn clear_batch(&mut self) -> Vec<Transaction> {
+        if !self.batching() {
+            return vec![];
+        }
+        let mut transactions = self.uncross();
+        self.trigger_stops(&mut transactions);
+        transactions
+    }
+
+    /// Whether the product collects the orders for the next batch.
+    fn batching(&self) -> bool {
+        self.batch
+            && !self.auction_only
+            && self.phase == Phase::Auction
+            && self.market_phase == Phase::Continuous
+    }
+
+    /// Whether the product is traded, either continuously or
+    /// in the batches.
+    fn trading(&self) -> bool {
+        self.phase == Phase::Continuous || self.batching()
+    }
+
+    /// Enter the stop orders triggered by the last transaction
+    /// price into the book, one by one, the earliest accepted first.
+    /// Each of them may move the price and trigger the next ones,
+    /// until none is left or the trading stops. In the batch
+    /// auctions they wait for the next clearing.
+    fn trigger_stops(&mut self, transactions: &mut Vec<Transaction>) {
+        while self.trading() {
+            let last_price = match self.last_price {
+                Some(last_price) => last_price,
+                None => return,
+            };
+            let index = match self
+                .stops
+                .iter()
+                .position(|order| order.triggered(last_price))
+            {
+                Some(index) => index,
+                None => return,
+            };
+            let mut order = self.stops.remove(index);
+            order.stop = None;
+            order.timestamp = self.clock;
+            match self.phase {
+                Phase::Continuous => transactions.extend(self.match_order(order)),
+                _ => self.insert(order),
+            }
+        }
+    }
+
+    /// Remove all the orders matching the predicate from the
+    /// book and return them in the order of arrival.
+    pub fn cancel_orders(&mut self, predicate: impl Fn(&Order) -> bool) -> Vec<Order> {
+        let mut cancelled = vec![];
+        for book in [&mut self.bids, &mut self.asks].iter_mut() {
+            book.retain(|_, level| {
+                level.retain(|order| match predicate(order) {
+                    true => {
+                        cancelled.push(order.clone());
+                        false
+                    }
+                    false => true,
+                });
+                !level.is_empty()
+            });
+        }
+        self.stops.retain(|order| match predicate(order) {
+            true => {
+                cancelled.push(order.clone());
+                false
+            }
+            false => true,
+        });
+        cancelled.sort_by_key(|order| order.id);
+        cancelled
+    }
+
+    /// Amend the user's resting order and return the resulting
+    /// transactions.
+    ///
+    /// Decreasing the quantity keeps the order's time priority.
+    /// Changing the price or increasing the quantity sends the order
+    /// to the back of the queue, as if it was a new order, so it may
+    /// match right away. The order keeps its limit if the request
+    /// has none. The request is rejected as a whole, e.g. once the
+    /// order is filled, and then the book is left untouched.
+    pub fn replace(&mut self, replace: Replace) -> Result<Vec<Transaction>, String> {
+        let owned =
+            |order: &Order| order.id == replace.order_id && order.user_id == replace.user_id;
+        let amend = |order: &Order| Order {
+            quantity: replace.quantity,
+            limit: replace.limit.or(order.limit),
+            timestamp: replace.timestamp,
+            ..order.clone()
+        };
+        if let Some(index) = self.stops.iter().position(owned) {
+            // The stop order keeps waiting for its trigger.
+            let amended = amend(&self.stops[index]);
+            self.check(&amended)?;
+            let order = &mut self.stops[index];
+            order.quantity = amended.quantity;
+            order.limit = amended.limit;
+            return Ok(vec![]);
+        }
+        let found = [Side::Buy, Side::Sell].iter().find_map(|side| {
+            self.side(*side).iter().find_map(|(price, level)| {
+                level
+                    .iter()
+                    .position(owned)
+                    .map(|index| (*side, *price, index))
+            })
+        });
+        let (side, price, index) = match found {
+            Some(found) => found,
+            None => {
+                return Err(format!(
+                    "{} unknown order {}",
+                    self.product, replace.order_id
+                ))
+            }
+        };
+        let order = &self.side(side)[&price][index];
+        let amended = amend(order);
+        self.check(&amended)?;
+        let book = self.side_mut(side);
+        let level = book.get_mut(&price).expect("The order was found");
+        let order = &mut level[index];
+        if order.limit == amended.limit && amended.quantity <= order.quantity {
+            order.quantity = amended.quantity;
+            if let Visibility::Iceberg { shown, .. } = &mut order.visibility {
+                *shown = (*shown).min(amended.quantity);
+            }
+            return Ok(vec![]);
+        }
+        level.remove(index);
+        if level.is_empty() {
+            book.remove(&price);
+        }
+        self.handle_user_order(amended)
+    }
+
+    /// The state of the product at this point in time.
+    pub fn snapshot(&self) -> ProductSnapshot {
+        ProductSnapshot {
+            product: self.product,
+            phase: self.phase,
+            last_price: self.last_price,
+            bids: self.bids.values().rev().flatten().cloned().collect(),
+            asks: self.asks.values().flatten().cloned().collect(),
+            stops: self.stops.clone(),
+        }
+    }
+
+    /// The quantities shown at the best price levels of both sides.
+    ///
+    /// Neither the hidden orders nor the reserves of the iceberg
+    /// orders are shown. The orders without the limit price are
+    /// not shown either, they have no price level.
+    pub fn depth(&self) -> (Depth, Depth) {
+        let shown = |(price, level): (&Price, &Level)| {
+            let quantity: Quantity = level.iter().map(Order::shown).sum();
+            Some((quantity, *price)).filter(|(quantity, _)| *quantity > 0)
+        };
+        let bids = self
+            .bids
+            .range(1..Price::MAX)
+            .rev()
+            .filter_map(shown)
+            .take(DEPTH_LEVELS)
+            .collect();
+        let asks = self
+            .asks
+            .range(1..)
+            .filter_map(shown)
+            .take(DEPTH_LEVELS)
+            .collect();
+        (bids, asks)
+    }
+
+    /// The volume and the price at which the book would be
+    /// uncrossed right now.
+    ///
+    /// The price maximizes the executed volume. The ties are
+    /// resolved by the smallest imbalance between the buy and
+    /// sell volume at the price, then by the distance from the
+    /// last transaction price and finally by the lower price.
+    pub fn indicative(&self) -> (Quantity, Option<Price>) {
+        let volume_at = |price: Price| {
+            let demand: Quantity = self
+                .bids
+                .range(price..)
+                .flat_map(|(_, l)| l)
+                .map(|o| o.quantity)
+                .sum();
+            let supply: Quantity = self
+                .asks
+                .range(..=price)
+                .flat_map(|(_, l)| l)
+                .map(|o| o.quantity)
+                .sum();
+            (demand.min(supply), demand.max(supply) - demand.min(supply))
+        };
+        let distance = |price: Price| match self.last_price {
+            Some(last) => (price as i128 - last as i128).abs(),
+            None => 0,
+        };
+        let limits = self
+            .bids
+            .keys()
+            .filter(|price| **price != Price::MAX)
+            .chain(self.asks.keys().filter(|price| **price != 0));
+        let best = limits
+            .map(|price| {
+                let (volume, imbalance) = volume_at(*price);
+                (volume, imbalance, distance(*price), *price)
+            })
+            .min_by_key(|(volume, imbalance, distance, price)| {
+                (std::cmp::Reverse(*volume), *imbalance, *distance, *price)
+            });
+        match best {
+            Some((0, ..)) => (0, None),
+            Some((volume, _, _, price)) => (volume, Some(price)),
+            // Only the orders without the limit price are present.
+            None => match volume_at(Price::MAX / 2).0 {
+                0 => (0, None),
+                volume => (volume, self.last_price),
+            },
+        }
+    }
+
+    /// Match the order against the opposite side of the book
+    /// and keep the rest of it in the book.
+    ///
+    /// The order is allocated across the resting orders at the best
+    /// price level by the product's `MatchingPolicy`, one level after
+    /// another, until it's filled or the price is not acceptable.
+    fn match_order(&mut self, mut order: Order) -> Vec<Transaction> {
+        let mut transactions = vec![];
+        while order.quantity > 0 {
+            let best = match order.side {
+                Side::Buy => self.asks.keys().next().copied(),
+                Side::Sell => self.bids.keys().next_back().copied(),
+            };
+            let best = match (best, order.limit) {
+                (Some(best), None) => best,
+                (Some(best), Some(limit)) if order.side == Side::Buy && best <= limit => best,
+                (Some(best), Some(limit)) if order.side == Side::Sell && best >= limit => best,
+                _ => break,
+            };
+            let side = order.side.opposite();
+            let price = self.front_mut(side, best).limit.or(order.limit);
+            if self.breaks_circuit(price, order.timestamp) {
+                // The rest of the order waits for the product
+                // to be resumed, unless the halted product
+                // rejects the orders.
+                self.phase = Phase::Halted;
+                if self.halted_orders == HaltedOrders::Reject {
+                    self.cancelled.push(order);
+                    return transactions;
+                }
+                break;
+            }
+            let level = match side {
+                Side::Buy => &self.bids[&best],
+                Side::Sell => &self.asks[&best],
+            };
+            let allocation = self.policy.allocate(level, order.quantity);
+            let level = self
+                .side_mut(side)
+                .get_mut(&best)
+                .expect("Price levels are never empty");
+            let allocated = order.quantity;
+            for (resting, quantity) in level.iter_mut().zip(allocation) {
+                if quantity > 0 {
+                    transactions.push(Transaction::new(&order, resting, quantity, price));
+                    resting.fill(quantity);
+                    order.quantity -= quantity;
+                }
+            }
+            assert!(
+                order.quantity < allocated,
+                "The matching policy has to allocate the order"
+            );
+            self.remove_filled(side, best);
+            if let Some(price) = price {
+                self.record_price(price, order.timestamp);
+            }
+        }
+        if order.quantity > 0 {
+            self.insert(order);
+        }
+        transactions
+    }
+
+    /// Execute the orders collected during the auction
+    /// at the single, indicative price.
+    fn uncross(&mut self) -> Vec<Transaction> {
+        let (mut volume, price) = self.indicative();
+        let mut transactions = vec![];
+        while volume > 0 {
+            let bid = *self.bids.keys().next_back().expect("The volume is matched");
+            let ask = *self.asks.keys().next().expect("The volume is matched");
+            let buy = self.front_mut(Side::Buy, bid).clone();
+            let sell = self.front_mut(Side::Sell, ask);
+            let quantity = buy.quantity.min(sell.quantity).min(volume);
+            transactions.push(Transaction {
+                aggressor: None,
+                ..Transaction::new(&buy, sell, quantity, price)
+            });
+            sell.fill(quantity);
+            self.front_mut(Side::Buy, bid).fill(quantity);
+            self.remove_filled(Side::Buy, bid);
+            self.remove_filled(Side::Sell, ask);
+            volume -= quantity;
+        }
+        if price.is_some() && !transactions.is_empty() {
+            // The uncrossing price is the new reference price.
+            self.last_price = price;
+            self.recent_prices.clear();
+        }
+        transactions
+    }
+
+    /// Check whether the transaction would move the price too far
+    /// from the reference price, i.e. the price of the oldest
+    /// transaction within the circuit breaker's window.
+    fn breaks_circuit(&mut self, price: Option<Price>, now: Timestamp) -> bool {
+        let (circuit_breaker, price) = match (&self.circuit_breaker, price) {
+            (Some(circuit_breaker), Some(price)) => (circuit_breaker, price),
+            _ => return false,
+        };
+        let window_start = now.saturating_sub(circuit_breaker.window.as_millis() as Timestamp);
+        while self.recent_prices.len() > 1
+            && self
+                .recent_prices
+                .front()
+                .is_some_and(|(time, _)| *time < window_start)
+        {
+            self.recent_prices.pop_front();
+        }
+        let reference = match self.recent_prices.front() {
+            Some((_, reference)) => *reference as f64,
+            None => match self.last_price {
+                Some(reference) => reference as f64,
+                None => return false,
+            },
+        };
+        (price as f64 - reference).abs() * 100.0 > circuit_breaker.max_move * reference
+    }
+
+    fn record_price(&mut self, price: Price, now: Timestamp) {
+        self.last_price = Some(price);
+        if self.circuit_breaker.is_some() {
+            self.recent_prices.push_back((now, price));
+        }
+    }
+
+    fn side(&self, side: Side) -> &BTreeMap<Price, Level> {
+        match side {
+            Side::Buy => &self.bids,
+            Side::Sell => &self.asks,
+        }
+    }
+
+    fn side_mut(&mut self, side: Side) -> &mut BTreeMap<Price, Level> {
+        match side {
+            Side::Buy => &mut self.bids,
+            Side::Sell => &mut self.asks,
+        }
+    }
+
+    /// The oldest order at the given price level.
+    fn front_mut(&mut self, side: Side, price: Price) -> &mut Order {
+        self.side_mut(side)
+            .get_mut(&price)
+            .and_then(VecDeque::front_mut)
+            .expect("Price levels are never empty")
+    }
+
+    /// Drop the filled orders at the given level. The iceberg orders
+    /// with their peaks filled show the next ones and go to the back
+    /// of the level instead, in their time priority.
+    fn remove_filled(&mut self, side: Side, price: Price) {
+        let book = self.side_mut(side);
+        if let Some(level) = book.get_mut(&price) {
+            let (exhausted, rest): (Vec<Order>, Vec<Order>) = std::mem::take(level)
+                .into_iter()
+                .filter(|order| order.quantity > 0)
+                .partition(Order::exhausted);
+            level.extend(rest);
+            for mut order in exhausted {
+                order.refresh();
+                level.push_back(order);
+            }
+            if level.is_empty() {
+                book.remove(&price);
+            }
+        }
+    }
+
+    fn insert(&mut self, mut order: Order) {
+        order.refresh();
+        let price = match (order.side, order.limit) {
+            (_, Some(limit)) => limit,
+            (Side::Buy, None) => Price::MAX,
+            (Side::Sell, None) => 0,
+        };
+        self.side_mut(order.side)
+            .entry(price)
+            .or_default()
+            .push_back(order);
+    }
+}
+
+/// The state of the ledger of a given product.
+#[derive(Debug, Serialize)]
+pub struct ProductSnapshot {
+    pub product: Product,
+    pub phase: Phase,
+    pub last_price: Option<Price>,
+    /// The buy orders, the best ones first.
+    pub bids: Vec<Order>,
+    /// The sell orders, the best ones first.
+    pub asks: Vec<Order>,
+    /// The stop orders waiting for their trigger.
+    pub stops: Vec<Order>,
+}
 
 /// This is a structure containing all the ledgers that
 /// are present in the trading market.
 pub struct Ledger {
-    apples: ProductLedger,
-    pears: ProductLedger,
-    tomatoes: ProductLedger,
-    potatoes: ProductLedger,
-    onions: ProductLedger,
+    products: BTreeMap<Product, ProductLedger>,
 }
 
 /// Main ledger in the system.
 ///
-/// It stores the current state of the market, i.e.
-/// ledgers of a given products. It also keeps the
-/// state up-to-date by updating the ledgers with the
-/// users' orders. As a result it generates transactions
-/// if any pair of orders matches.
+/// It stores the initial state of the market, i.e.
+/// ledgers of a given products. Once the server starts,
+/// every product's ledger is moved to its own shard,
+/// which keeps it up-to-date with the users' orders.
 impl Ledger {
     /// Create a new empty Ledger.
-    pub fn new() -> Ledger {
+    pub fn new(config: &Config) -> Ledger {
         Ledger {
-            apples: 0,
-            pears: 0,
-            tomatoes: 0,
-            potatoes: 0,
-            onions: 0,
+            products: Product::ALL
+                .iter()
+                .map(|product| (*product, ProductLedger::new(*product, config)))
+                .collect(),
         }
     }
 
-    /// Apply a new user's order - update the proper ledger
-    /// and return a new transaction if applicable.
-    ///
-    /// NOTE: We do not want to use anything like "Transaction
-    /// Observer" here. The only way the transaction may occure is by
-    /// applying some user action, so it's sufficient to
-    /// check for any new transaction right after a user's
-    /// new order. If there is a new transaction
-    /// - handle it right away.
-    ///
-    /// This approach **reduces the coupling** of the system's components.
-    ///
-    pub fn handle_user_order(&mut self, order: Order) -> Option<Transaction> {
-        match order {
-            Order::Buy(_, product) => match product {
-                Product::Apple => {
-                    self.apples -= 1;
-                    if self.apples >= 0 {
-                        return Some(Transaction(Product::Apple));
-                    }
-                }
-                Product::Pear => {
-                    self.pears -= 1;
-                    if self.pears >= 0 {
-                        return Some(Transaction(Product::Pear));
-                    }
-                }
-                Product::Tomato => {
-                    self.tomatoes -= 1;
-                    if self.tomatoes >= 0 {
-                        return Some(Transaction(Product::Tomato));
-                    }
-                }
-                Product::Potato => {
-                    self.potatoes -= 1;
-                    if self.potatoes >= 0 {
-                        return Some(Transaction(Product::Potato));
-                    }
-                }
-                Product::Onion => {
-                    self.onions -= 1;
-                    if self.onions >= 0 {
-                        return Some(Transaction(Product::Onion));
-                    }
-                }
-            },
-            Order::Sell(_, product) => match product {
-                Product::Apple => {
-                    self.apples += 1;
-                    if self.apples <= 0 {
-                        return Some(Transaction(Product::Apple));
-                    }
-                }
-                Product::Pear => {
-                    self.pears += 1;
-                    if self.pears <= 0 {
-                        return Some(Transaction(Product::Pear));
-                    }
-                }
-                Product::Tomato => {
-                    self.tomatoes += 1;
-                    if self.tomatoes <= 0 {
-                        return Some(Transaction(Product::Tomato));
-                    }
-                }
-                Product::Potato => {
-                    self.potatoes += 1;
-                    if self.potatoes <= 0 {
-                        return Some(Transaction(Product::Potato));
-                    }
-                }
-                Product::Onion => {
-                    self.onions += 1;
-                    if self.onions <= 0 {
-                        return Some(Transaction(Product::Onion));
-                    }
-                }
-            },
+    /// Split the ledger into the ledgers of every product.
+    pub fn into_products(self) -> impl Iterator<Item = ProductLedger> {
+        self.products.into_values()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::order::UserId;
+
+    fn order(
+        id: u64,
+        user_id: UserId,
+        side: Side,
+        quantity: Quantity,
+        limit: Option<Price>,
+    ) -> Order {
+        Order {
+            id,
+            timestamp: id * 1000,
+            user_id,
+            side,
+            product: Product::Apple,
+            quantity,
+            limit,
+            short_form: false,
+            visibility: Visibility::Visible,
+            stop: None,
+            expiry: None,
+        }
+    }
+
+    fn summary(transactions: &[Transaction]) -> Vec<(u64, u64, Quantity, Option<Price>)> {
+        transactions
+            .iter()
+            .map(|t| (t.buy.order_id, t.sell.order_id, t.quantity, t.price))
+            .collect()
+    }
+
+    #[test]
+    fn test_continuous_price_time_priority() {
+        let mut ledger = ProductLedger::new(Product::Apple, &Config::default());
+        ledger
+            .handle_user_order(order(1, 1, Side::Sell, 5, Some(101)))
+            .unwrap();
+        ledger
+            .handle_user_order(order(2, 1, Side::Sell, 5, Some(100)))
+            .unwrap();
+        ledger
+            .handle_user_order(order(3, 2, Side::Sell, 5, Some(100)))
+            .unwrap();
+
+        let transactions = ledger
+            .handle_user_order(order(4, 3, Side::Buy, 12, Some(101)))
+            .unwrap();
+        assert_eq!(
+            summary(&transactions),
+            vec![
+                (4, 2, 5, Some(100)),
+                (4, 3, 5, Some(100)),
+                (4, 1, 2, Some(101))
+            ]
+        );
+        assert_eq!(ledger.last_price, Some(101));
+
+        // The rest of the first order is still in the book.
+        let transactions = ledger
+            .handle_user_order(order(5, 3, Side::Buy, 10, None))
+            .unwrap();
+        assert_eq!(summary(&transactions), vec![(5, 1, 3, Some(101))]);
+    }
+
+    #[test]
+    fn test_orders_without_price() {
+        let mut ledger = ProductLedger::new(Product::Apple, &Config::default());
+        assert!(ledger
+            .handle_user_order(order(1, 1, Side::Buy, 1, None))
+            .unwrap()
+            .is_empty());
+        assert!(ledger
+            .handle_user_order(order(2, 1, Side::Buy, 1, Some(90)))
+            .unwrap()
+            .is_empty());
+
+        let transactions = ledger
+            .handle_user_order(order(3, 2, Side::Sell, 1, None))
+            .unwrap();
+        assert_eq!(summary(&transactions), vec![(1, 3, 1, None)]);
+        let transactions = ledger
+            .handle_user_order(order(4, 2, Side::Sell, 1, None))
+            .unwrap();
+        assert_eq!(summary(&transactions), vec![(2, 4, 1, Some(90))]);
+    }
+
+    #[test]
+    fn test_auction_uncross_maximizes_volume() {
+        let mut ledger = ProductLedger::new(Product::Apple, &Config::default());
+        ledger.set_market_phase(Phase::Auction);
+        ledger
+            .handle_user_order(order(1, 1, Side::Buy, 10, Some(103)))
+            .unwrap();
+        ledger
+            .handle_user_order(order(2, 1, Side::Buy, 10, Some(101)))
+            .unwrap();
+        ledger
+            .handle_user_order(order(3, 2, Side::Sell, 5, Some(99)))
+            .unwrap();
+        ledger
+            .handle_user_order(order(4, 2, Side::Sell, 10, Some(101)))
+            .unwrap();
+        assert!(ledger
+            .handle_user_order(order(5, 2, Side::Sell, 10, Some(104)))
+            .unwrap()
+            .is_empty());
+
+        // At 101 there's 20 demanded and 15 supplied,
+        // at 103 it's only 10 demanded.
+        assert_eq!(ledger.indicative(), (15, Some(101)));
+
+        let transactions = ledger.set_market_phase(Phase::Continuous);
+        assert_eq!(
+            summary(&transactions),
+            vec![
+                (1, 3, 5, Some(101)),
+                (1, 4, 5, Some(101)),
+                (2, 4, 5, Some(101))
+            ]
+        );
+        assert_eq!(ledger.last_price, Some(101));
+        assert_eq!(ledger.indicative(), (0, None));
+    }
+
+    #[test]
+    fn test_circuit_breaker_halts_product() {
+        let config = Config {
+            halted_orders: HaltedOrders::Queue,
+            circuit_breaker: Some(CircuitBreaker {
+                max_move: 10.0,
+                window: std::time::Duration::from_secs(60),
+                halt: std::time::Duration::from_secs(60),
+            }),
+            ..Default::default()
         };
-        None
+        let mut ledger = ProductLedger::new(Product::Apple, &config);
+        ledger
+            .handle_user_order(order(1, 1, Side::Sell, 1, Some(100)))
+            .unwrap();
+        ledger
+            .handle_user_order(order(2, 2, Side::Buy, 1, Some(100)))
+            .unwrap();
+        ledger
+            .handle_user_order(order(3, 1, Side::Sell, 1, Some(120)))
+            .unwrap();
+
+        // The price would move by 20%.
+        let transactions = ledger
+            .handle_user_order(order(4, 2, Side::Buy, 1, Some(120)))
+            .unwrap();
+        assert!(transactions.is_empty());
+        assert_eq!(ledger.phase(), Phase::Halted);
+
+        // The orders are queued until the product is resumed.
+        assert!(ledger
+            .handle_user_order(order(5, 2, Side::Buy, 1, Some(121)))
+            .unwrap()
+            .is_empty());
+        let transactions = ledger.resume();
+        assert_eq!(summary(&transactions), vec![(5, 3, 1, Some(121))]);
+        assert_eq!(ledger.phase(), Phase::Continuous);
+    }
+
+    #[test]
+    fn test_circuit_breaker_cancels_rejected_order() {
+        let config = Config {
+            circuit_breaker: Some(CircuitBreaker {
+                max_move: 10.0,
+                window: std::time::Duration::from_secs(60),
+                halt: std::time::Duration::from_secs(60),
+            }),
+            ..Default::default()
+        };
+        let mut ledger = ProductLedger::new(Product::Apple, &config);
+        ledger
+            .handle_user_order(order(1, 1, Side::Sell, 1, Some(100)))
+            .unwrap();
+        ledger
+            .handle_user_order(order(2, 1, Side::Sell, 1, Some(120)))
+            .unwrap();
+
+        // The first unit trades, the second one would move the
+        // price by 20%, so the rest of the order is cancelled.
+        let transactions = ledger
+            .handle_user_order(order(3, 2, Side::Buy, 3, Some(120)))
+            .unwrap();
+        assert_eq!(summary(&transactions), vec![(3, 1, 1, Some(100))]);
+        assert_eq!(ledger.phase(), Phase::Halted);
+        let cancelled = ledger.take_cancelled();
+        assert_eq!(cancelled.len(), 1);
+        assert_eq!((cancelled[0].id, cancelled[0].quantity), (3, 2));
+        assert!(ledger.take_cancelled().is_empty());
+        assert!(ledger.bids.is_empty());
+    }
+
+    #[test]
+    fn test_halted_product_rejects_orders() {
+        let mut ledger = ProductLedger::new(Product::Apple, &Config::default());
+        ledger.halt();
+        assert!(ledger
+            .handle_user_order(order(1, 1, Side::Buy, 1, None))
+            .is_err());
+
+        // Scheduled phase changes do not resume the product.
+        ledger.set_market_phase(Phase::Auction);
+        assert_eq!(ledger.phase(), Phase::Halted);
+        ledger.resume();
+        assert_eq!(ledger.phase(), Phase::Auction);
+    }
+
+    #[test]
+    fn test_closed_market_rejects_orders() {
+        let mut ledger = ProductLedger::new(Product::Apple, &Config::default());
+        ledger.halt();
+        ledger.set_market_phase(Phase::Closed);
+        assert_eq!(ledger.phase(), Phase::Closed);
+        assert!(ledger
+            .handle_user_order(order(1, 1, Side::Buy, 1, None))
+            .is_err());
+    }
+
+    #[test]
+    fn test_replace_keeps_priority_for_decreases_only() {
+        let mut ledger = ProductLedger::new(Product::Apple, &Config::default());
+        for (id, user_id) in [(1, 1), (2, 2), (3, 3)].iter() {
+            ledger
+                .handle_user_order(order(*id, *user_id, Side::Sell, 5, Some(100)))
+                .unwrap();
+        }
+        let replace = |order_id, user_id, quantity, limit| Replace {
+            user_id,
+            order_id,
+            quantity,
+            limit,
+            timestamp: 10_000,
+        };
+
+        // A decrease keeps the first order in front.
+        assert_eq!(ledger.replace(replace(1, 1, 2, Some(100))), Ok(vec![]));
+        // An increase sends the second order to the back.
+        assert_eq!(ledger.replace(replace(2, 2, 6, Some(100))), Ok(vec![]));
+        // Only the owner may amend its order.
+        assert!(ledger.replace(replace(3, 1, 1, Some(100))).is_err());
+
+        let transactions = ledger
+            .handle_user_order(order(4, 4, Side::Buy, 8, Some(100)))
+            .unwrap();
+        assert_eq!(
+            summary(&transactions),
+            vec![
+                (4, 1, 2, Some(100)),
+                (4, 3, 5, Some(100)),
+                (4, 2, 1, Some(100))
+            ]
+        );
+
+        // A new price may cross the book right away.
+        ledger
+            .handle_user_order(order(5, 5, Side::Buy, 5, Some(98)))
+            .unwrap();
+        let transactions = ledger.replace(replace(2, 2, 5, Some(98))).unwrap();
+        assert_eq!(summary(&transactions), vec![(5, 2, 5, Some(98))]);
+
+        // The filled order can't be amended anymore.
+        assert_eq!(
+            ledger.replace(replace(2, 2, 1, Some(98))),
+            Err("APPLE unknown order 2".to_string())
+        );
+        assert!(ledger.snapshot().asks.is_empty());
+        assert!(ledger.snapshot().bids.is_empty());
+    }
+
+    #[test]
+    fn test_rejected_replace_leaves_the_order() {
+        let mut ledger = ProductLedger::new(Product::Apple, &Config::default());
+        ledger
+            .handle_user_order(order(1, 1, Side::Sell, 5, Some(100)))
+            .unwrap();
+        let stop = Order {
+            stop: Some(90),
+            ..order(2, 1, Side::Sell, 5, Some(89))
+        };
+        ledger.handle_user_order(stop).unwrap();
+        let replace = |order_id, quantity, limit| Replace {
+            user_id: 1,
+            order_id,
+            quantity,
+            limit,
+            timestamp: 10_000,
+        };
+
+        // The order is left as it was by the rejected request.
+        ledger.halt();
+        assert_eq!(
+            ledger.replace(replace(1, 6, Some(101))),
+            Err("APPLE trading halted".to_string())
+        );
+        assert_eq!(
+            ledger.replace(replace(2, 6, Some(88))),
+            Err("APPLE trading halted".to_string())
+        );
+        let snapshot = ledger.snapshot();
+        assert_eq!(snapshot.asks, vec![order(1, 1, Side::Sell, 5, Some(100))]);
+        assert_eq!(
+            (snapshot.stops[0].quantity, snapshot.stops[0].limit),
+            (5, Some(89))
+        );
+
+        // The orders keep their limits unless new ones are given.
+        ledger.resume();
+        assert_eq!(ledger.replace(replace(1, 4, None)), Ok(vec![]));
+        assert_eq!(ledger.replace(replace(2, 4, None)), Ok(vec![]));
+        let snapshot = ledger.snapshot();
+        assert_eq!(
+            (snapshot.asks[0].quantity, snapshot.asks[0].limit),
+            (4, Some(100))
+        );
+        assert_eq!(
+            (snapshot.stops[0].quantity, snapshot.stops[0].limit),
+            (4, Some(89))
+        );
+        assert_eq!(ledger.replace(replace(2, 4, Some(88))), Ok(vec![]));
+        assert_eq!(ledger.snapshot().stops[0].limit, Some(88));
+    }
+
+    #[test]
+    fn test_good_till_date_orders() {
+        let mut ledger = ProductLedger::new(Product::Apple, &Config::default());
+        let gtd = |id, expiry| Order {
+            expiry: Some(expiry),
+            ..order(id, 1, Side::Sell, 5, Some(100))
+        };
+        assert_eq!(
+            ledger.handle_user_order(gtd(1, 1000)),
+            Err("APPLE order expired".to_string())
+        );
+        assert_eq!(
+            ledger.handle_user_order(gtd(2, 2000 + MAX_EXPIRY_HORIZON + 1)),
+            Err("APPLE order expiry too far".to_string())
+        );
+        assert_eq!(
+            ledger.handle_user_order(gtd(3, 3000 + MAX_EXPIRY_HORIZON)),
+            Ok(vec![])
+        );
+        assert_eq!(ledger.handle_user_order(gtd(4, 5000)), Ok(vec![]));
+        assert_eq!(ledger.snapshot().asks.len(), 2);
+
+        let replace = |timestamp| Replace {
+            user_id: 1,
+            order_id: 4,
+            quantity: 6,
+            limit: Some(100),
+            timestamp,
+        };
+        assert_eq!(
+            ledger.replace(replace(5000)),
+            Err("APPLE order expired".to_string())
+        );
+        assert_eq!(ledger.replace(replace(4500)), Ok(vec![]));
+        assert_eq!(ledger.snapshot().asks[1].expiry, Some(5000));
+    }
+
+    #[test]
+    fn test_iceberg_and_hidden_orders() {
+        let mut ledger = ProductLedger::new(Product::Apple, &Config::default());
+        let mut iceberg = order(1, 1, Side::Sell, 20, Some(100));
+        iceberg.visibility = Visibility::Iceberg { peak: 5, shown: 0 };
+        ledger.handle_user_order(iceberg).unwrap();
+        ledger
+            .handle_user_order(order(2, 2, Side::Sell, 5, Some(100)))
+            .unwrap();
+        assert_eq!(ledger.depth(), (vec![], vec![(10, 100)]));
+
+        // The filled peak loses the time priority.
+        let transactions = ledger
+            .handle_user_order(order(3, 3, Side::Buy, 7, Some(100)))
+            .unwrap();
+        assert_eq!(
+            summary(&transactions),
+            vec![(3, 1, 5, Some(100)), (3, 2, 2, Some(100))]
+        );
+        assert_eq!(ledger.depth(), (vec![], vec![(8, 100)]));
+        let transactions = ledger
+            .handle_user_order(order(4, 3, Side::Buy, 10, Some(100)))
+            .unwrap();
+        assert_eq!(
+            summary(&transactions),
+            vec![
+                (4, 2, 3, Some(100)),
+                (4, 1, 5, Some(100)),
+                (4, 1, 2, Some(100))
+            ]
+        );
+        assert_eq!(ledger.depth(), (vec![], vec![(3, 100)]));
+
+        // The hidden order is matched, but never shown.
+        let mut hidden = order(5, 4, Side::Buy, 10, Some(99));
+        hidden.visibility = Visibility::Hidden;
+        ledger.handle_user_order(hidden).unwrap();
+        assert_eq!(ledger.depth(), (vec![], vec![(3, 100)]));
+        let transactions = ledger
+            .handle_user_order(order(6, 5, Side::Sell, 4, Some(99)))
+            .unwrap();
+        assert_eq!(summary(&transactions), vec![(5, 6, 4, Some(99))]);
+
+        // The incoming iceberg order is matched with its whole
+        // quantity, only the rest of it is shown by peaks.
+        let mut iceberg = order(7, 5, Side::Sell, 10, Some(99));
+        iceberg.visibility = Visibility::Iceberg { peak: 2, shown: 0 };
+        let transactions = ledger.handle_user_order(iceberg).unwrap();
+        assert_eq!(summary(&transactions), vec![(5, 7, 6, Some(99))]);
+        assert_eq!(ledger.depth(), (vec![], vec![(2, 99), (3, 100)]));
+        assert_eq!(ledger.snapshot().asks.len(), 2);
+    }
+
+    #[test]
+    fn test_stop_orders_cascade_in_order_of_arrival() {
+        let mut ledger = ProductLedger::new(Product::Apple, &Config::default());
+        let stop = |id, quantity, limit, stop| Order {
+            stop: Some(stop),
+            ..order(id, 2, Side::Sell, quantity, limit)
+        };
+        for (id, quantity, limit) in [(1, 2, 100), (2, 5, 98), (3, 5, 95)].iter() {
+            ledger
+                .handle_user_order(order(*id, 1, Side::Buy, *quantity, Some(*limit)))
+                .unwrap();
+        }
+        ledger.handle_user_order(stop(4, 5, None, 99)).unwrap();
+        ledger.handle_user_order(stop(5, 5, Some(96), 98)).unwrap();
+        ledger.handle_user_order(stop(6, 1, None, 90)).unwrap();
+        assert_eq!(ledger.depth(), (vec![(2, 100), (5, 98), (5, 95)], vec![]));
+
+        let transactions = ledger
+            .handle_user_order(order(7, 3, Side::Sell, 2, Some(100)))
+            .unwrap();
+        assert_eq!(summary(&transactions), vec![(1, 7, 2, Some(100))]);
+        assert_eq!(ledger.snapshot().stops.len(), 3);
+
+        // Both stops are triggered at 98, the earlier one moves
+        // the price to 95 before the stop-limit one enters the book.
+        let transactions = ledger
+            .handle_user_order(order(8, 3, Side::Sell, 1, Some(98)))
+            .unwrap();
+        assert_eq!(
+            summary(&transactions),
+            vec![
+                (2, 8, 1, Some(98)),
+                (2, 4, 4, Some(98)),
+                (3, 4, 1, Some(95))
+            ]
+        );
+        assert_eq!(ledger.depth(), (vec![(4, 95)], vec![(5, 96)]));
+        let stops: Vec<_> = ledger.snapshot().stops.iter().map(|o| o.id).collect();
+        assert_eq!(stops, vec![6]);
+
+        // The stop price already reached enters the book right away.
+        let transactions = ledger
+            .handle_user_order(Order {
+                stop: Some(90),
+                ..order(9, 3, Side::Buy, 1, None)
+            })
+            .unwrap();
+        assert_eq!(summary(&transactions), vec![(9, 5, 1, Some(96))]);
+
+        let cancelled = ledger.cancel_orders(|order| order.user_id == 2);
+        let cancelled: Vec<_> = cancelled.iter().map(|o| o.id).collect();
+        assert_eq!(cancelled, vec![5, 6]);
+        assert!(ledger.snapshot().stops.is_empty());
+    }
+
+    #[test]
+    fn test_pro_rata_matching() {
+        let config = Config {
+            matching: [(Product::Apple, crate::config::Matching::ProRata)]
+                .iter()
+                .copied()
+                .collect(),
+            ..Default::default()
+        };
+        let mut ledger = ProductLedger::new(Product::Apple, &config);
+        for (id, quantity, limit) in [(1, 60, 100), (2, 30, 100), (3, 10, 100), (4, 5, 101)].iter()
+        {
+            ledger
+                .handle_user_order(order(*id, 1, Side::Sell, *quantity, Some(*limit)))
+                .unwrap();
+        }
+        let transactions = ledger
+            .handle_user_order(order(5, 2, Side::Buy, 50, Some(101)))
+            .unwrap();
+        assert_eq!(
+            summary(&transactions),
+            vec![
+                (5, 1, 30, Some(100)),
+                (5, 2, 15, Some(100)),
+                (5, 3, 5, Some(100))
+            ]
+        );
+
+        // The level is filled as a whole before the next one.
+        let transactions = ledger
+            .handle_user_order(order(6, 2, Side::Buy, 60, Some(101)))
+            .unwrap();
+        assert_eq!(
+            summary(&transactions),
+            vec![
+                (6, 1, 30, Some(100)),
+                (6, 2, 15, Some(100)),
+                (6, 3, 5, Some(100)),
+                (6, 4, 5, Some(101))
+            ]
+        );
+        assert_eq!(ledger.depth(), (vec![(5, 101)], vec![]));
+    }
+
+    #[test]
+    fn test_auction_only_phase() {
+        let config = Config {
+            auction_only: [Product::Apple].iter().copied().collect(),
+            ..Default::default()
+        };
+        let mut ledger = ProductLedger::new(Product::Apple, &config);
+        assert_eq!(ledger.phase(), Phase::Auction);
+        let crossing = |ledger: &mut ProductLedger, id| {
+            for (id, side, limit) in [(id, Side::Sell, 100), (id + 1, Side::Buy, 101)].iter() {
+                let transactions = ledger
+                    .handle_user_order(order(*id, 1, *side, 5, Some(*limit)))
+                    .unwrap();
+                assert!(transactions.is_empty());
+            }
+        };
+
+        // The orders are collected whatever the market does.
+        crossing(&mut ledger, 1);
+        assert!(ledger.set_market_phase(Phase::Auction).is_empty());
+        assert!(ledger.set_market_phase(Phase::Continuous).is_empty());
+        assert_eq!(ledger.phase(), Phase::Auction);
+        assert_eq!(ledger.depth(), (vec![(5, 101)], vec![(5, 100)]));
+
+        // Opening the product uncrosses the book.
+        let transactions = ledger.set_auction_only(false);
+        assert_eq!(summary(&transactions), vec![(2, 1, 5, Some(100))]);
+        assert_eq!(ledger.phase(), Phase::Continuous);
+
+        // The operator's auction-only phase lasts until the market closes.
+        assert!(ledger.set_auction_only(true).is_empty());
+        assert_eq!(ledger.phase(), Phase::Auction);
+        crossing(&mut ledger, 3);
+        let transactions = ledger.set_market_phase(Phase::Closed);
+        assert_eq!(summary(&transactions), vec![(4, 3, 5, Some(100))]);
+        assert_eq!(ledger.phase(), Phase::Closed);
+
+        // The halted product is opened once it's resumed.
+        ledger.set_market_phase(Phase::Continuous);
+        ledger.halt();
+        assert!(ledger.set_auction_only(false).is_empty());
+        assert_eq!(ledger.phase(), Phase::Halted);
+        ledger.resume();
+        assert_eq!(ledger.phase(), Phase::Continuous);
+    }
+
+    #[test]
+    fn test_batch_auction_clears_at_single_price() {
+        let config = Config {
+            batch_auctions: [(
+                Product::Apple,
+                crate::config::BatchAuction {
+                    interval: std::time::Duration::from_millis(100),
+                },
+            )]
+            .iter()
+            .cloned()
+            .collect(),
+            ..Default::default()
+        };
+        let mut ledger = ProductLedger::new(Product::Apple, &config);
+        assert_eq!(ledger.phase(), Phase::Auction);
+        for (id, side, quantity, limit) in [
+            (1, Side::Sell, 5, 100),
+            (2, Side::Sell, 5, 102),
+            (3, Side::Buy, 8, 103),
+        ]
+        .iter()
+        {
+            let transactions = ledger
+                .handle_user_order(order(*id, 1, *side, *quantity, Some(*limit)))
+                .unwrap();
+            assert!(transactions.is_empty());
+        }
+        ledger
+            .handle_user_order(Order {
+                stop: Some(102),
+                ..order(4, 2, Side::Buy, 1, None)
+            })
+            .unwrap();
+
+        assert_eq!(
+            summary(&ledger.clear_batch()),
+            vec![(3, 1, 5, Some(102)), (3, 2, 3, Some(102))]
+        );
+        assert_eq!(ledger.phase(), Phase::Auction);
+        // The triggered stop order waits for the next batch.
+        assert_eq!(summary(&ledger.clear_batch()), vec![(4, 2, 1, Some(102))]);
+        assert!(ledger.clear_batch().is_empty());
+
+        // The scheduled auction is not cleared in the batches.
+        ledger.set_market_phase(Phase::Auction);
+        ledger
+            .handle_user_order(order(5, 2, Side::Buy, 1, Some(102)))
+            .unwrap();
+        assert!(ledger.clear_batch().is_empty());
+        let transactions = ledger.set_market_phase(Phase::Continuous);
+        assert_eq!(summary(&transactions), vec![(5, 2, 1, Some(102))]);
+        assert_eq!(ledger.phase(), Phase::Auction);
     }
 }
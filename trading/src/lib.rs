@@ -2,17 +2,43 @@
 /// 
 ///
 
+mod admin;
+pub mod audit;
+mod binary;
+pub mod client;
+pub mod config;
+mod dispatcher;
+mod drop_copy;
+mod fees;
+mod journal;
+mod json;
 mod ledger;
+mod listener;
+pub mod logging;
+mod matching;
+mod message;
 mod order;
+mod protocol;
+pub mod replay;
+pub mod report;
+mod request;
 mod server;
+mod shard;
+mod tls;
 mod transaction;
+mod websocket;
 
 pub async fn start_server(interface: String) -> anyhow::Result<()> {
-    let mut ledger = ledger::Ledger::new();
-    let mut server = server::Server::new();
-    server
-        .start(&mut ledger, interface)
-        .await
+    run(config::Config {
+        interface,
+        ..Default::default()
+    })
+    .await
+}
+
+pub async fn run(config: config::Config) -> anyhow::Result<()> {
+    let ledger = ledger::Ledger::new(&config);
+    server::Server::start(ledger, &config).await
 }
 
 #[cfg(test)]
@@ -20,7 +46,6 @@ mod tests {
     use super::*;
     use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-
     macro_rules! check_product {
         ($client:ident, $client_buf:ident, $product:literal) => {
             $client
@@ -39,7 +64,7 @@ mod tests {
                 .write_all(format!("SELL:{}\n", $product).as_bytes())
                 .await
                 .expect("Client error");
-            
+
             // This is crucial for the system to fully receive the server's response
             // in the socket
             tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
@@ -86,7 +111,7 @@ mod tests {
         check_product!(client1, client1_buf, "ONION");
     }
 
-    #[tokio::test]
+    #[tokio::test(flavor = "multi_thread")]
     async fn test_multiple_client_all_products() {
         tokio::spawn(start_server("127.0.0.1:8081".to_string()));
         let mut client_buf = [0; 2048];
@@ -112,4 +137,1224 @@ mod tests {
         check_product!(client4, client_buf, "POTATO");
         check_product!(client5, client_buf, "ONION");
     }
+
+    #[tokio::test]
+    async fn test_opening_auction() {
+        let config = config::Config {
+            interface: "127.0.0.1:8082".to_string(),
+            schedule: vec![
+                config::ScheduledPhase {
+                    phase: ledger::Phase::Auction,
+                    duration: Some(tokio::time::Duration::from_secs(1)),
+                },
+                config::ScheduledPhase {
+                    phase: ledger::Phase::Continuous,
+                    duration: None,
+                },
+            ],
+            ..Default::default()
+        };
+        tokio::spawn(run(config));
+        let mut client = tokio::net::TcpStream::connect("localhost:8082")
+            .await
+            .expect("Problem with client");
+        let mut client_buf = [0; 2048];
+
+        client
+            .write_all(b"BUY:APPLE:10@101\nSELL:APPLE:5@100\n")
+            .await
+            .expect("Client error");
+        tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+        let n = client.read(&mut client_buf).await.expect("Socket error");
+        let response = std::str::from_utf8(&client_buf[0..n]).expect("Invalid response");
+        assert_eq!(
+            response,
+            "ACK:APPLE:1\nINDICATIVE:APPLE:0\nACK:APPLE:2\nINDICATIVE:APPLE:5@100\n"
+        );
+
+        // The auction is over, the book is uncrossed.
+        tokio::time::sleep(tokio::time::Duration::from_millis(1000)).await;
+        let n = client.read(&mut client_buf).await.expect("Socket error");
+        let response = std::str::from_utf8(&client_buf[0..n]).expect("Invalid response");
+        let lines: Vec<&str> = response.lines().collect();
+        assert_eq!(
+            lines[..4],
+            [
+                "FILL:1:5@100",
+                "FILL:2:5@100",
+                "TRADE:APPLE:5@100",
+                "PHASE:APPLE:CONTINUOUS"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_admin_channel() {
+        use tokio::io::AsyncBufReadExt;
+
+        let config = config::Config {
+            interface: "127.0.0.1:8083".to_string(),
+            admin: Some(config::AdminConfig {
+                listen: "127.0.0.1:8183".to_string(),
+                token: "secret".to_string(),
+                snapshot_dir: std::env::temp_dir(),
+            }),
+            ..Default::default()
+        };
+        tokio::spawn(run(config));
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        let mut client = tokio::net::TcpStream::connect("localhost:8083")
+            .await
+            .expect("Problem with client");
+        let mut client_buf = [0; 2048];
+        let port = client.local_addr().expect("Socket error").port();
+        // The users get their IDs one after another.
+        let user_id = 1;
+        client
+            .write_all(b"BUY:APPLE:5@100\n")
+            .await
+            .expect("Client error");
+
+        let intruder = tokio::net::TcpStream::connect("localhost:8183")
+            .await
+            .expect("Problem with admin");
+        let mut intruder = tokio::io::BufReader::new(intruder);
+        intruder
+            .write_all(b"AUTH:guess\nUSERS\n")
+            .await
+            .expect("Admin error");
+        let mut line = String::new();
+        intruder.read_line(&mut line).await.expect("Admin error");
+        assert_eq!(line, "ERROR:unauthorized\n");
+
+        let admin = tokio::net::TcpStream::connect("localhost:8183")
+            .await
+            .expect("Problem with admin");
+        let mut admin = tokio::io::BufReader::new(admin).lines();
+        let commands = format!(
+            "AUTH:secret\nUSERS\nHALT:APPLE\nCANCEL_ALL:USER:{}\nRESUME:PEAR\n",
+            user_id
+        );
+        admin
+            .get_mut()
+            .write_all(commands.as_bytes())
+            .await
+            .expect("Admin error");
+
+        let mut responses = vec![];
+        for _ in 0..7 {
+            let line = admin.next_line().await.expect("Admin error");
+            responses.push(line.expect("Admin connection closed"));
+        }
+        assert_eq!(
+            responses,
+            [
+                "OK".to_string(),
+                format!(
+                    "USER:{} addr=127.0.0.1:{} orders=1 rejected=0 fills=0 volume=0 open=1",
+                    user_id, port
+                ),
+                "OK".to_string(),
+                "OK".to_string(),
+                "CANCELLED:APPLE:1".to_string(),
+                "OK".to_string(),
+                "ERROR:PEAR is not halted".to_string(),
+            ]
+        );
+
+        let n = client.read(&mut client_buf).await.expect("Socket error");
+        let response = std::str::from_utf8(&client_buf[0..n]).expect("Invalid response");
+        assert_eq!(
+            response,
+            "ACK:APPLE:1\nPHASE:APPLE:HALTED\nCANCELLED:APPLE:1\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_client() {
+        use crate::client::{Client, Message, Product, Side};
+        use futures::StreamExt;
+
+        tokio::spawn(start_server("127.0.0.1:8084".to_string()));
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        let (buyer, mut buyer_events) = Client::connect("127.0.0.1:8084")
+            .await
+            .expect("Problem with buyer");
+        let (seller, _) = Client::connect("127.0.0.1:8084")
+            .await
+            .expect("Problem with seller");
+        let mut trades = buyer.subscribe(&[Product::Apple]);
+
+        let buy = buyer
+            .place_order(Side::Buy, Product::Apple, 5, Some(100))
+            .await
+            .expect("Order rejected");
+        let sell = seller
+            .place_order(Side::Sell, Product::Apple, 3, None)
+            .await
+            .expect("Order rejected");
+        assert_eq!((buy, sell), (1, 2));
+        assert_eq!(
+            trades.next().await,
+            Some(Message::Trade {
+                product: Product::Apple,
+                quantity: 3,
+                price: Some(100)
+            })
+        );
+        assert_eq!(
+            buyer_events.next().await,
+            Some(Message::Ack {
+                product: Product::Apple,
+                order_id: Some(1)
+            })
+        );
+        assert_eq!(
+            buyer_events.next().await,
+            Some(Message::Fill {
+                order_id: 1,
+                quantity: 3,
+                price: Some(100),
+                fee: None,
+            })
+        );
+
+        assert_eq!(
+            seller.cancel(Product::Apple, buy).await,
+            Err("APPLE unknown order 1".to_string())
+        );
+        assert_eq!(buyer.cancel(Product::Apple, buy).await, Ok(()));
+        assert!(buyer.cancel(Product::Apple, buy).await.is_err());
+        assert!(buyer
+            .place_order(Side::Buy, Product::Pear, 0, None)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_session_retained_from_login() {
+        use tokio::io::AsyncBufReadExt;
+
+        let config = config::Config {
+            interface: "127.0.0.1:8097".to_string(),
+            ..Default::default()
+        };
+        tokio::spawn(run(config));
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+        let client = tokio::net::TcpStream::connect("localhost:8097")
+            .await
+            .expect("Problem with client");
+        let mut client = tokio::io::BufReader::new(client).lines();
+        client
+            .get_mut()
+            .write_all(b"BUY:APPLE:5@100\n")
+            .await
+            .expect("Client error");
+        assert_eq!(
+            client.next_line().await.expect("Client error").as_deref(),
+            Some("ACK:APPLE:1")
+        );
+        client
+            .get_mut()
+            .write_all(b"SESSION\n")
+            .await
+            .expect("Client error");
+        let session = client.next_line().await.expect("Client error").unwrap();
+        let id = session
+            .strip_prefix("SESSION:")
+            .and_then(|session| session.strip_suffix(":1"))
+            .expect("Unexpected session")
+            .to_string();
+        drop(client);
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+        // The messages sent before the session was started are retained too.
+        let client = tokio::net::TcpStream::connect("localhost:8097")
+            .await
+            .expect("Problem with client");
+        let mut client = tokio::io::BufReader::new(client).lines();
+        client
+            .get_mut()
+            .write_all(format!("RESUME:{}:0\n", id).as_bytes())
+            .await
+            .expect("Client error");
+        let expected = [format!("SESSION:{}:1", id), "1 ACK:APPLE:1".to_string()];
+        for line in expected.iter() {
+            assert_eq!(
+                client.next_line().await.expect("Client error").as_ref(),
+                Some(line)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_session_resume() {
+        use tokio::io::AsyncBufReadExt;
+
+        let config = config::Config {
+            interface: "127.0.0.1:8085".to_string(),
+            session: config::SessionConfig {
+                retention: 2,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        tokio::spawn(run(config));
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+        let client = tokio::net::TcpStream::connect("localhost:8085")
+            .await
+            .expect("Problem with client");
+        let mut client = tokio::io::BufReader::new(client).lines();
+        client
+            .get_mut()
+            .write_all(b"SESSION\nBUY:APPLE:5@100\n")
+            .await
+            .expect("Client error");
+        let session = client.next_line().await.expect("Client error").unwrap();
+        let id = session
+            .strip_prefix("SESSION:")
+            .and_then(|session| session.strip_suffix(":0"))
+            .expect("Unexpected session")
+            .to_string();
+        assert_eq!(
+            client.next_line().await.expect("Client error").as_deref(),
+            Some("1 ACK:APPLE:1")
+        );
+        drop(client);
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+        // The order is filled while the client is away.
+        let mut seller = tokio::net::TcpStream::connect("localhost:8085")
+            .await
+            .expect("Problem with seller");
+        seller
+            .write_all(b"SELL:APPLE:5@100\n")
+            .await
+            .expect("Client error");
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+        let client = tokio::net::TcpStream::connect("localhost:8085")
+            .await
+            .expect("Problem with client");
+        let mut client = tokio::io::BufReader::new(client).lines();
+        client
+            .get_mut()
+            .write_all(format!("RESUME:{}:0\nCANCEL:APPLE:1\n", id).as_bytes())
+            .await
+            .expect("Client error");
+        let expected = [
+            format!("SESSION:{}:3", id),
+            "GAP:1:1".to_string(),
+            "2 FILL:1:5@100".to_string(),
+            "3 TRADE:APPLE:5@100".to_string(),
+            // The cancel is sent on behalf of the session's user.
+            "4 ERROR:APPLE unknown order 1".to_string(),
+        ];
+        for line in expected.iter() {
+            assert_eq!(
+                client.next_line().await.expect("Client error").as_ref(),
+                Some(line)
+            );
+        }
+
+        let stranger = tokio::net::TcpStream::connect("localhost:8085")
+            .await
+            .expect("Problem with client");
+        let mut stranger = tokio::io::BufReader::new(stranger).lines();
+        stranger
+            .get_mut()
+            .write_all(b"RESUME:1:0\n")
+            .await
+            .expect("Client error");
+        assert_eq!(
+            stranger.next_line().await.expect("Client error").as_deref(),
+            Some("ERROR:Unknown session: 1")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat_timeout() {
+        use tokio::io::AsyncBufReadExt;
+
+        let config = config::Config {
+            interface: "127.0.0.1:8086".to_string(),
+            heartbeat: Some(config::HeartbeatConfig {
+                interval: std::time::Duration::from_millis(100),
+                timeout: std::time::Duration::from_millis(500),
+                cancel_on_disconnect: false,
+            }),
+            ..Default::default()
+        };
+        tokio::spawn(run(config));
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+        // The buyer goes silent and its order is cancelled.
+        let buyer = tokio::net::TcpStream::connect("localhost:8086")
+            .await
+            .expect("Problem with buyer");
+        let mut buyer = tokio::io::BufReader::new(buyer).lines();
+        buyer
+            .get_mut()
+            .write_all(b"CANCEL_ON_DISCONNECT:ON\nBUY:APPLE:5@100\n")
+            .await
+            .expect("Client error");
+        assert_eq!(
+            buyer.next_line().await.expect("Client error").as_deref(),
+            Some("ACK:APPLE:1")
+        );
+        let mut heartbeats = 0;
+        while let Some(line) = buyer.next_line().await.expect("Client error") {
+            assert_eq!(line, "HEARTBEAT");
+            heartbeats += 1;
+        }
+        assert!(heartbeats >= 3, "{}", heartbeats);
+
+        // The seller keeps its connection and its order.
+        let seller = tokio::net::TcpStream::connect("localhost:8086")
+            .await
+            .expect("Problem with seller");
+        let mut seller = tokio::io::BufReader::new(seller).lines();
+        seller
+            .get_mut()
+            .write_all(b"SELL:APPLE:5@100\n")
+            .await
+            .expect("Client error");
+        assert_eq!(
+            seller.next_line().await.expect("Client error").as_deref(),
+            Some("ACK:APPLE:2")
+        );
+        for _ in 0..8 {
+            assert_eq!(
+                seller.next_line().await.expect("Client error").as_deref(),
+                Some("HEARTBEAT")
+            );
+            seller
+                .get_mut()
+                .write_all(b"HEARTBEAT\n")
+                .await
+                .expect("Client error");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_websocket_gateway() {
+        use futures::{SinkExt, StreamExt};
+        use tokio::io::AsyncBufReadExt;
+        use tokio_tungstenite::tungstenite::Message as Frame;
+
+        let config = config::Config {
+            interface: "127.0.0.1:8087".to_string(),
+            listeners: vec![config::ListenerConfig {
+                listen: "127.0.0.1:8187".to_string(),
+                protocol: config::ListenerProtocol::Websocket,
+                tls: None,
+            }],
+            ..Default::default()
+        };
+        tokio::spawn(run(config));
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+        let (mut web, _) = tokio_tungstenite::connect_async("ws://127.0.0.1:8187")
+            .await
+            .expect("Problem with WebSocket user");
+        web.send(Frame::text(
+            r#"{"type":"order","side":"BUY","product":"APPLE","quantity":5,"price":100}"#,
+        ))
+        .await
+        .expect("WebSocket error");
+        let frame = web.next().await.expect("Closed").expect("WebSocket error");
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(frame.to_text().unwrap()).unwrap(),
+            serde_json::json!({"type": "ack", "product": "APPLE", "order_id": 1})
+        );
+
+        // The TCP user trades in the same book.
+        let tcp = tokio::net::TcpStream::connect("localhost:8087")
+            .await
+            .expect("Problem with TCP user");
+        let mut tcp = tokio::io::BufReader::new(tcp).lines();
+        tcp.get_mut()
+            .write_all(b"SELL:APPLE:3@100\n")
+            .await
+            .expect("Client error");
+        assert_eq!(
+            tcp.next_line().await.expect("Client error").as_deref(),
+            Some("ACK:APPLE:2")
+        );
+
+        let expected = [
+            serde_json::json!({"type": "fill", "order_id": 1, "quantity": 3, "price": 100}),
+            serde_json::json!({"type": "trade", "product": "APPLE", "quantity": 3, "price": 100}),
+        ];
+        for expected in expected.iter() {
+            let frame = web.next().await.expect("Closed").expect("WebSocket error");
+            assert_eq!(
+                &serde_json::from_str::<serde_json::Value>(frame.to_text().unwrap()).unwrap(),
+                expected
+            );
+        }
+
+        web.send(Frame::text(r#"{"type":"order","side":"BUY"}"#))
+            .await
+            .expect("WebSocket error");
+        let frame = web.next().await.expect("Closed").expect("WebSocket error");
+        let error: serde_json::Value = serde_json::from_str(frame.to_text().unwrap()).unwrap();
+        assert_eq!(error["type"], "error");
+    }
+
+    #[tokio::test]
+    async fn test_json_lines_protocol() {
+        use serde_json::{json, Value};
+        use tokio::io::{AsyncBufReadExt, BufReader, Lines};
+        use tokio::net::TcpStream;
+
+        async fn next(lines: &mut Lines<BufReader<TcpStream>>) -> Value {
+            let line = lines.next_line().await.expect("Client error").unwrap();
+            serde_json::from_str(&line).expect("Invalid JSON")
+        }
+
+        tokio::spawn(start_server("127.0.0.1:8088".to_string()));
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+        let buyer = tokio::net::TcpStream::connect("localhost:8088")
+            .await
+            .expect("Problem with buyer");
+        let mut buyer = tokio::io::BufReader::new(buyer).lines();
+        buyer
+            .get_mut()
+            .write_all(
+                b"PROTO:JSON\n{\"type\":\"order\",\"side\":\"BUY\",\"product\":\"APPLE\",\"quantity\":5,\"price\":100}\n",
+            )
+            .await
+            .expect("Client error");
+        assert_eq!(
+            next(&mut buyer).await,
+            json!({"type": "ack", "product": "APPLE", "order_id": 1})
+        );
+
+        // The text protocol keeps working for everyone else.
+        let seller = tokio::net::TcpStream::connect("localhost:8088")
+            .await
+            .expect("Problem with seller");
+        let mut seller = tokio::io::BufReader::new(seller).lines();
+        seller
+            .get_mut()
+            .write_all(b"SELL:APPLE:3@100\n")
+            .await
+            .expect("Client error");
+        assert_eq!(
+            seller.next_line().await.expect("Client error").as_deref(),
+            Some("ACK:APPLE:2")
+        );
+
+        assert_eq!(
+            next(&mut buyer).await,
+            json!({"type": "fill", "order_id": 1, "quantity": 3, "price": 100})
+        );
+        assert_eq!(
+            next(&mut buyer).await,
+            json!({"type": "trade", "product": "APPLE", "quantity": 3, "price": 100})
+        );
+
+        buyer
+            .get_mut()
+            .write_all(b"BUY:APPLE\n{\"type\":\"cancel\",\"product\":\"APPLE\",\"order_id\":7}\n")
+            .await
+            .expect("Client error");
+        assert_eq!(next(&mut buyer).await["type"], "error");
+        assert_eq!(
+            next(&mut buyer).await,
+            json!({"type": "error", "reason": "APPLE unknown order 7"})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_binary_protocol() {
+        use tokio::io::AsyncBufReadExt;
+
+        let config = config::Config {
+            interface: "127.0.0.1:8089".to_string(),
+            listeners: vec![config::ListenerConfig {
+                listen: "127.0.0.1:8189".to_string(),
+                protocol: config::ListenerProtocol::Binary,
+                tls: None,
+            }],
+            ..Default::default()
+        };
+        tokio::spawn(run(config));
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+        let mut buyer = tokio::net::TcpStream::connect("localhost:8189")
+            .await
+            .expect("Problem with buyer");
+        // BUY APPLE 5 @ 100
+        let mut order = vec![19, 0, 0x01, 0, 0];
+        order.extend_from_slice(&5u64.to_le_bytes());
+        order.extend_from_slice(&100u64.to_le_bytes());
+        buyer.write_all(&order).await.expect("Client error");
+        let mut ack = [0; 12];
+        buyer.read_exact(&mut ack).await.expect("Client error");
+        assert_eq!(ack, [10, 0, 0x81, 0, 1, 0, 0, 0, 0, 0, 0, 0]);
+
+        // The text users trade in the same book.
+        let seller = tokio::net::TcpStream::connect("localhost:8089")
+            .await
+            .expect("Problem with seller");
+        let mut seller = tokio::io::BufReader::new(seller).lines();
+        seller
+            .get_mut()
+            .write_all(b"SELL:APPLE:3@100\n")
+            .await
+            .expect("Client error");
+        assert_eq!(
+            seller.next_line().await.expect("Client error").as_deref(),
+            Some("ACK:APPLE:2")
+        );
+
+        // The fee is 0 without the fees.
+        let mut fill = [0; 35];
+        buyer.read_exact(&mut fill).await.expect("Client error");
+        let mut expected = vec![33, 0, 0x82];
+        for field in [1u64, 3, 100, 0].iter() {
+            expected.extend_from_slice(&field.to_le_bytes());
+        }
+        assert_eq!(&fill[..], &expected[..]);
+        let mut trade = [0; 20];
+        buyer.read_exact(&mut trade).await.expect("Client error");
+        assert_eq!(&trade[..3], &[18, 0, 0x83]);
+
+        // An unknown request type.
+        buyer.write_all(&[1, 0, 0x7f]).await.expect("Client error");
+        let mut error = [0; 3];
+        buyer.read_exact(&mut error).await.expect("Client error");
+        assert_eq!(error[2], 0x84);
+        let mut reason = vec![0; u16::from_le_bytes([error[0], error[1]]) as usize - 1];
+        buyer.read_exact(&mut reason).await.expect("Client error");
+        assert_eq!(reason, b"Unknown request type: 0x7f");
+    }
+
+    #[tokio::test]
+    async fn test_tls_client_certificate() {
+        use rcgen::{BasicConstraints, CertificateParams, ExtendedKeyUsagePurpose, IsCa, KeyPair};
+        use std::convert::TryFrom;
+        use std::sync::Arc;
+        use tokio::io::AsyncBufReadExt;
+        use tokio_rustls::rustls::crypto::ring::default_provider;
+        use tokio_rustls::rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer, ServerName};
+        use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+
+        // The self-signed CA signs both the server's and the user's certificates.
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        let server_key = KeyPair::generate().unwrap();
+        let server = CertificateParams::new(vec!["localhost".to_string()])
+            .unwrap()
+            .signed_by(&server_key, &ca, &ca_key)
+            .unwrap();
+        let user_key = KeyPair::generate().unwrap();
+        let mut user_params = CertificateParams::new(vec!["trader".to_string()]).unwrap();
+        user_params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        let user = user_params.signed_by(&user_key, &ca, &ca_key).unwrap();
+
+        let dir = std::env::temp_dir().join(format!("trading-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let files = [
+            ("ca.pem", ca.pem()),
+            ("server.pem", server.pem()),
+            ("server.key", server_key.serialize_pem()),
+            ("user.pem", user.pem()),
+        ];
+        for (name, content) in files.iter() {
+            std::fs::write(dir.join(name), content).unwrap();
+        }
+        let config = config::Config {
+            interface: "127.0.0.1:8090".to_string(),
+            tls: Some(config::TlsConfig {
+                cert: dir.join("server.pem"),
+                key: dir.join("server.key"),
+                client_ca: Some(dir.join("ca.pem")),
+                users: vec![config::TlsUser {
+                    cert: dir.join("user.pem"),
+                    user_id: 1,
+                }],
+            }),
+            listeners: vec![config::ListenerConfig {
+                listen: "127.0.0.1:8196".to_string(),
+                protocol: config::ListenerProtocol::Text,
+                tls: None,
+            }],
+            ..Default::default()
+        };
+        tokio::spawn(run(config));
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+        let mut roots = RootCertStore::empty();
+        roots.add(ca.der().clone()).unwrap();
+        let builder = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots);
+        let user_key = PrivateKeyDer::from(PrivatePkcs8KeyDer::from(user_key.serialize_der()));
+        let certified = tokio_rustls::TlsConnector::from(Arc::new(
+            builder
+                .clone()
+                .with_client_auth_cert(vec![user.der().clone()], user_key)
+                .unwrap(),
+        ));
+        let anonymous = tokio_rustls::TlsConnector::from(Arc::new(builder.with_no_client_auth()));
+        let connect = |connector: tokio_rustls::TlsConnector| async move {
+            let stream = tokio::net::TcpStream::connect("localhost:8090")
+                .await
+                .expect("Problem with client");
+            let name = ServerName::try_from("localhost").unwrap();
+            let stream = connector.connect(name, stream).await?;
+            Ok::<_, std::io::Error>(tokio::io::BufReader::new(stream).lines())
+        };
+
+        let mut client = connect(certified.clone()).await.expect("Handshake failed");
+        client
+            .get_mut()
+            .write_all(b"BUY:APPLE:5@100\n")
+            .await
+            .expect("Client error");
+        assert_eq!(
+            client.next_line().await.expect("Client error").as_deref(),
+            Some("ACK:APPLE:1")
+        );
+        client
+            .get_mut()
+            .write_all(b"SESSION\n")
+            .await
+            .expect("Client error");
+        let session = client.next_line().await.expect("Client error").unwrap();
+        assert!(session.starts_with("SESSION:") && session.ends_with(":1"));
+
+        // The certificate's ID is never given to the other users.
+        let stream = tokio::net::TcpStream::connect("127.0.0.1:8196")
+            .await
+            .expect("Problem with client");
+        let mut other = tokio::io::BufReader::new(stream).lines();
+        other
+            .get_mut()
+            .write_all(b"CANCEL:APPLE:1\n")
+            .await
+            .expect("Client error");
+        assert_eq!(
+            other.next_line().await.expect("Client error").as_deref(),
+            Some("ERROR:APPLE unknown order 1")
+        );
+
+        // A single connection with the certificate at a time.
+        if let Ok(mut second) = connect(certified.clone()).await {
+            let _ = second.get_mut().write_all(b"CANCEL:APPLE:1\n").await;
+            assert!(!matches!(second.next_line().await, Ok(Some(_))));
+        }
+
+        // Every connection with the certificate belongs to the same
+        // user and continues its session.
+        drop(client);
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        let mut client = connect(certified).await.expect("Handshake failed");
+        client
+            .get_mut()
+            .write_all(b"CANCEL:APPLE:1\n")
+            .await
+            .expect("Client error");
+        assert_eq!(
+            client.next_line().await.expect("Client error").as_deref(),
+            Some("2 CANCELLED:APPLE:1")
+        );
+
+        // The users without the certificate are not accepted.
+        if let Ok(mut client) = connect(anonymous).await {
+            let _ = client.get_mut().write_all(b"BUY:APPLE\n").await;
+            assert!(!matches!(client.next_line().await, Ok(Some(_))));
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_multiple_listeners() {
+        use tokio::io::AsyncBufReadExt;
+
+        let path = std::env::temp_dir().join(format!("trading-{}.sock", std::process::id()));
+        let config = config::Config {
+            interface: "127.0.0.1:8091".to_string(),
+            listeners: vec![
+                config::ListenerConfig {
+                    listen: format!("unix:{}", path.display()),
+                    protocol: config::ListenerProtocol::Text,
+                    tls: None,
+                },
+                config::ListenerConfig {
+                    listen: "127.0.0.1:8191".to_string(),
+                    protocol: config::ListenerProtocol::Json,
+                    tls: None,
+                },
+            ],
+            ..Default::default()
+        };
+        tokio::spawn(run(config));
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+        let bot = tokio::net::UnixStream::connect(&path)
+            .await
+            .expect("Problem with bot");
+        let mut bot = tokio::io::BufReader::new(bot).lines();
+        bot.get_mut()
+            .write_all(b"BUY:APPLE:5@100\n")
+            .await
+            .expect("Client error");
+        assert_eq!(
+            bot.next_line().await.expect("Client error").as_deref(),
+            Some("ACK:APPLE:1")
+        );
+
+        // The JSON listener needs no greeting.
+        let json = tokio::net::TcpStream::connect("localhost:8191")
+            .await
+            .expect("Problem with JSON user");
+        let mut json = tokio::io::BufReader::new(json).lines();
+        json.get_mut()
+            .write_all(b"{\"type\":\"order\",\"side\":\"SELL\",\"product\":\"APPLE\",\"quantity\":2,\"price\":100}\n")
+            .await
+            .expect("Client error");
+        let ack: serde_json::Value =
+            serde_json::from_str(&json.next_line().await.expect("Client error").unwrap()).unwrap();
+        assert_eq!(
+            ack,
+            serde_json::json!({"type": "ack", "product": "APPLE", "order_id": 2})
+        );
+
+        // Everyone trades in the same book.
+        let tcp = tokio::net::TcpStream::connect("localhost:8091")
+            .await
+            .expect("Problem with TCP user");
+        let mut tcp = tokio::io::BufReader::new(tcp).lines();
+        tcp.get_mut()
+            .write_all(b"SELL:APPLE:3@100\n")
+            .await
+            .expect("Client error");
+        assert_eq!(
+            tcp.next_line().await.expect("Client error").as_deref(),
+            Some("ACK:APPLE:3")
+        );
+
+        for expected in [
+            "FILL:1:2@100",
+            "TRADE:APPLE:2@100",
+            "FILL:1:3@100",
+            "TRADE:APPLE:3@100",
+        ]
+        .iter()
+        {
+            assert_eq!(
+                bot.next_line().await.expect("Client error").as_deref(),
+                Some(*expected)
+            );
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_drop_copy() {
+        use tokio::io::{AsyncBufReadExt, BufReader, Lines};
+        use tokio::net::TcpStream;
+
+        async fn connect(address: &str, lines: &[&str]) -> Lines<BufReader<TcpStream>> {
+            let stream = TcpStream::connect(address).await.expect("Connection error");
+            let mut stream = BufReader::new(stream).lines();
+            for line in lines.iter() {
+                stream
+                    .get_mut()
+                    .write_all(format!("{}\n", line).as_bytes())
+                    .await
+                    .expect("Client error");
+            }
+            stream
+        }
+        async fn next(lines: &mut Lines<BufReader<TcpStream>>) -> Option<String> {
+            lines.next_line().await.expect("Client error")
+        }
+
+        let journal =
+            std::env::temp_dir().join(format!("trading-drop-copy-{}", std::process::id()));
+        let _ = std::fs::remove_file(&journal);
+        let config = config::Config {
+            interface: "127.0.0.1:8092".to_string(),
+            journal: Some(journal.clone()),
+            drop_copy: Some(config::DropCopyConfig {
+                listen: "127.0.0.1:8192".to_string(),
+                token: "secret".to_string(),
+            }),
+            ..Default::default()
+        };
+        tokio::spawn(run(config));
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+        let mut intruder = connect("localhost:8192", &["AUTH:guess"]).await;
+        assert_eq!(
+            next(&mut intruder).await.as_deref(),
+            Some("ERROR:unauthorized")
+        );
+
+        let mut live = connect("localhost:8192", &["AUTH:secret", "SUBSCRIBE"]).await;
+        assert_eq!(next(&mut live).await.as_deref(), Some("OK"));
+        assert_eq!(next(&mut live).await.as_deref(), Some("OK"));
+
+        let mut buyer = connect("localhost:8092", &["BUY:APPLE:5@100"]).await;
+        assert_eq!(next(&mut buyer).await.as_deref(), Some("ACK:APPLE:1"));
+        let mut seller = connect("localhost:8092", &["SELL:APPLE:3@100"]).await;
+        assert_eq!(next(&mut seller).await.as_deref(), Some("ACK:APPLE:2"));
+        seller
+            .get_mut()
+            .write_all(b"SELL:APPLE:2@100\n")
+            .await
+            .expect("Client error");
+
+        let (buyer_id, seller_id) = (1, 2);
+        let executions = [
+            format!("EXEC:1:APPLE:{}:1:{}:2:3@100", buyer_id, seller_id),
+            format!("EXEC:2:APPLE:{}:1:{}:3:2@100", buyer_id, seller_id),
+        ];
+        for execution in executions.iter() {
+            assert_eq!(next(&mut live).await.as_ref(), Some(execution));
+        }
+
+        // The earlier executions are replayed out of the journal.
+        let mut replay = connect("localhost:8192", &["AUTH:secret", "SUBSCRIBE:2"]).await;
+        assert_eq!(next(&mut replay).await.as_deref(), Some("OK"));
+        assert_eq!(next(&mut replay).await.as_deref(), Some("OK"));
+        assert_eq!(next(&mut replay).await.as_ref(), Some(&executions[1]));
+        let _ = std::fs::remove_file(&journal);
+    }
+
+    #[tokio::test]
+    async fn test_good_till_date_expiry() {
+        use tokio::io::AsyncBufReadExt;
+
+        let journal = std::env::temp_dir().join(format!("trading-gtd-{}", std::process::id()));
+        let _ = std::fs::remove_file(&journal);
+        let config = config::Config {
+            interface: "127.0.0.1:8093".to_string(),
+            journal: Some(journal.clone()),
+            ..Default::default()
+        };
+        tokio::spawn(run(config));
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+        let user = tokio::net::TcpStream::connect("localhost:8093")
+            .await
+            .expect("Problem with user");
+        let user_id = 1;
+        let mut user = tokio::io::BufReader::new(user).lines();
+        let expiry = server::timestamp() + 300;
+        user.get_mut()
+            .write_all(format!("BUY:APPLE:5@100:GTD:{}\nBUY:APPLE:1@99:GTD:1\n", expiry).as_bytes())
+            .await
+            .expect("Client error");
+        assert_eq!(
+            user.next_line().await.expect("Client error").as_deref(),
+            Some("ACK:APPLE:1")
+        );
+        assert_eq!(
+            user.next_line().await.expect("Client error").as_deref(),
+            Some("ERROR:APPLE order expired")
+        );
+        assert_eq!(
+            user.next_line().await.expect("Client error").as_deref(),
+            Some("EXPIRED:APPLE:1")
+        );
+        assert!(server::timestamp() >= expiry);
+
+        // The expiry is recorded, so the journal replays it.
+        tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
+        let mut output = vec![];
+        let input = std::io::BufReader::new(std::fs::File::open(&journal).unwrap());
+        replay::replay(&config::Config::default(), input, &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            format!(
+                "{0} ACK:APPLE:1\n{0} ERROR:APPLE order expired\n{0} EXPIRED:APPLE:1\n",
+                user_id
+            )
+        );
+        let _ = std::fs::remove_file(&journal);
+    }
+
+    #[tokio::test]
+    async fn test_far_expiry_is_rejected() {
+        use tokio::io::AsyncBufReadExt;
+
+        tokio::spawn(start_server("127.0.0.1:8096".to_string()));
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+        let user = tokio::net::TcpStream::connect("localhost:8096")
+            .await
+            .expect("Problem with user");
+        let mut user = tokio::io::BufReader::new(user).lines();
+        // Such a delay used to panic the timers and the whole server.
+        user.get_mut()
+            .write_all(b"BUY:APPLE:1@100:GTD:99999999999999\nBUY:APPLE:1@100\n")
+            .await
+            .expect("Client error");
+        assert_eq!(
+            user.next_line().await.expect("Client error").as_deref(),
+            Some("ERROR:APPLE order expiry too far")
+        );
+        assert_eq!(
+            user.next_line().await.expect("Client error").as_deref(),
+            Some("ACK:APPLE:2")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_batch_auction() {
+        use tokio::io::AsyncBufReadExt;
+
+        let config = config::Config {
+            interface: "127.0.0.1:8094".to_string(),
+            batch_auctions: [(
+                transaction::Product::Onion,
+                config::BatchAuction {
+                    interval: std::time::Duration::from_millis(100),
+                },
+            )]
+            .iter()
+            .cloned()
+            .collect(),
+            ..Default::default()
+        };
+        tokio::spawn(run(config));
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+        let stream = tokio::net::TcpStream::connect("localhost:8094")
+            .await
+            .expect("Problem with user");
+        let mut user = tokio::io::BufReader::new(stream).lines();
+        user.get_mut()
+            .write_all(b"SELL:ONION:5@100\nBUY:ONION:3@101\nBUY:APPLE:1@100\n")
+            .await
+            .expect("Client error");
+        let mut lines = vec![];
+        while lines.last().map(String::as_str) != Some("CLEARED:ONION:3@100") {
+            let line = user.next_line().await.expect("Client error").unwrap();
+            lines.push(line);
+        }
+        // The orders are only collected until the batch is cleared.
+        assert_eq!(
+            lines,
+            [
+                "ACK:ONION:1",
+                "INDICATIVE:ONION:0",
+                "ACK:ONION:2",
+                "INDICATIVE:ONION:3@100",
+                "ACK:APPLE:3",
+                "FILL:2:3@100",
+                "FILL:1:3@100",
+                "TRADE:ONION:3@100",
+                "CLEARED:ONION:3@100",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker_halt() {
+        use tokio::io::AsyncBufReadExt;
+
+        let config = config::Config {
+            interface: "127.0.0.1:8098".to_string(),
+            admin: Some(config::AdminConfig {
+                listen: "127.0.0.1:8197".to_string(),
+                token: "secret".to_string(),
+                snapshot_dir: std::env::temp_dir(),
+            }),
+            circuit_breaker: Some(config::CircuitBreaker {
+                max_move: 10.0,
+                window: std::time::Duration::from_secs(60),
+                halt: std::time::Duration::from_millis(200),
+            }),
+            ..Default::default()
+        };
+        tokio::spawn(run(config));
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+        let stream = tokio::net::TcpStream::connect("localhost:8098")
+            .await
+            .expect("Problem with user");
+        let mut user = tokio::io::BufReader::new(stream).lines();
+        user.get_mut()
+            .write_all(b"SELL:APPLE:1@100\nSELL:APPLE:1@120\nBUY:APPLE:3@120\n")
+            .await
+            .expect("Client error");
+        let mut lines = vec![];
+        while lines.last().map(String::as_str) != Some("CANCELLED:APPLE:3") {
+            let line = user.next_line().await.expect("Client error").unwrap();
+            lines.push(line);
+        }
+        // The rest of the order which tripped the breaker is
+        // rejected like the orders sent while halted.
+        assert_eq!(
+            lines,
+            [
+                "ACK:APPLE:1",
+                "ACK:APPLE:2",
+                "ACK:APPLE:3",
+                "FILL:3:1@100",
+                "FILL:1:1@100",
+                "TRADE:APPLE:1@100",
+                "PHASE:APPLE:HALTED",
+                "CANCELLED:APPLE:3",
+            ]
+        );
+
+        let admin = tokio::net::TcpStream::connect("localhost:8197")
+            .await
+            .expect("Problem with admin");
+        let mut admin = tokio::io::BufReader::new(admin).lines();
+        admin
+            .get_mut()
+            .write_all(b"AUTH:secret\nHALT:APPLE\n")
+            .await
+            .expect("Admin error");
+        for _ in 0..2 {
+            assert_eq!(admin.next_line().await.unwrap().unwrap(), "OK");
+        }
+
+        // The operator's halt outlasts the breaker's one.
+        tokio::time::sleep(tokio::time::Duration::from_millis(400)).await;
+        user.get_mut()
+            .write_all(b"BUY:APPLE:1@100\n")
+            .await
+            .expect("Client error");
+        assert_eq!(
+            user.next_line().await.unwrap().unwrap(),
+            "ERROR:APPLE trading halted"
+        );
+        admin
+            .get_mut()
+            .write_all(b"RESUME:APPLE\n")
+            .await
+            .expect("Admin error");
+        assert_eq!(admin.next_line().await.unwrap().unwrap(), "OK");
+    }
+
+    #[tokio::test]
+    async fn test_fees_and_statements() {
+        use tokio::io::AsyncBufReadExt;
+
+        let config = config::Config {
+            interface: "127.0.0.1:8095".to_string(),
+            admin: Some(config::AdminConfig {
+                listen: "127.0.0.1:8195".to_string(),
+                token: "secret".to_string(),
+                snapshot_dir: std::env::temp_dir(),
+            }),
+            fees: [(
+                transaction::Product::Apple,
+                config::FeeSchedule {
+                    maker: -1,
+                    taker: 2,
+                    tiers: vec![],
+                },
+            )]
+            .iter()
+            .cloned()
+            .collect(),
+            ..Default::default()
+        };
+        tokio::spawn(run(config));
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+        let seller = tokio::net::TcpStream::connect("localhost:8095")
+            .await
+            .expect("Problem with seller");
+        let mut seller = tokio::io::BufReader::new(seller).lines();
+        let buyer = tokio::net::TcpStream::connect("localhost:8095")
+            .await
+            .expect("Problem with buyer");
+        let mut buyer = tokio::io::BufReader::new(buyer).lines();
+
+        seller
+            .get_mut()
+            .write_all(b"SELL:APPLE:5@100\n")
+            .await
+            .expect("Client error");
+        assert_eq!(seller.next_line().await.unwrap().unwrap(), "ACK:APPLE:1");
+        buyer
+            .get_mut()
+            .write_all(b"BUY:APPLE:3@100\nBUY:PEAR:1@10\nSELL:PEAR:1@10\n")
+            .await
+            .expect("Client error");
+        let mut lines = vec![];
+        for _ in 0..8 {
+            lines.push(buyer.next_line().await.unwrap().unwrap());
+        }
+        // The products without the fees have the fills without them.
+        assert_eq!(
+            lines,
+            [
+                "ACK:APPLE:2",
+                "FILL:2:3@100:6",
+                "TRADE:APPLE:3@100",
+                "ACK:PEAR:3",
+                "ACK:PEAR:4",
+                "FILL:3:1@10",
+                "FILL:4:1@10",
+                "TRADE:PEAR:1@10",
+            ]
+        );
+        assert_eq!(
+            seller.next_line().await.unwrap().unwrap(),
+            "FILL:1:3@100:-3"
+        );
+
+        let admin = tokio::net::TcpStream::connect("localhost:8195")
+            .await
+            .expect("Problem with admin");
+        let mut admin = tokio::io::BufReader::new(admin).lines();
+        admin
+            .get_mut()
+            .write_all(b"AUTH:secret\nSTATEMENTS\n")
+            .await
+            .expect("Admin error");
+        let mut responses = vec![];
+        for _ in 0..4 {
+            responses.push(admin.next_line().await.unwrap().unwrap());
+        }
+        assert_eq!(
+            responses,
+            [
+                "OK",
+                "STATEMENT:1 gross=300 fees=-3 net=303",
+                "STATEMENT:2 gross=-300 fees=6 net=-306",
+                "OK",
+            ]
+        );
+
+        // The short form order has no fill, so its fee is only
+        // seen in the statement.
+        buyer
+            .get_mut()
+            .write_all(b"BUY:APPLE\nSTATEMENT\n")
+            .await
+            .expect("Client error");
+        let mut lines = vec![];
+        for _ in 0..3 {
+            lines.push(buyer.next_line().await.unwrap().unwrap());
+        }
+        assert_eq!(
+            lines,
+            [
+                "ACK:APPLE",
+                "TRADE:APPLE:1@100",
+                "STATEMENT:2 gross=-400 fees=8 net=-408",
+            ]
+        );
+    }
 }
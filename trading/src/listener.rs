@@ -0,0 +1,399 @@
+/// Author: Tomasz Kulik
+/// 
+///
+
+use crate::config::{HeartbeatConfig, ListenerConfig, ListenerProtocol};
+use crate::order::UserId;
+use crate::protocol::Protocol;
+use crate::server::{Event, Server};
+use crate::tls::TlsAcceptor;
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::Sender;
+use tracing::{info, warn};
+
+/// How long the client may take to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where the user is connected from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Peer {
+    Tcp(SocketAddr),
+    /// The connections of the Unix domain sockets have no
+    /// addresses, so they are told apart by their numbers.
+    Unix(u64),
+}
+
+impl Display for Peer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Peer::Tcp(addr) => write!(f, "{}", addr),
+            Peer::Unix(number) => write!(f, "unix#{}", number),
+        }
+    }
+}
+
+/// Assigns the IDs of the users connected through every listener
+///
+/// The users identified by their client certificates have their
+/// own IDs, never given to anyone else, and only one connection
+/// at a time may use such an ID. Every other user gets the next
+/// ID of a single counter, skipping the IDs of the users still
+/// connected and of the sessions kept for the users who lost their
+/// connections, so no two connections share an ID, whichever
+/// listeners or hosts they come from.
+#[derive(Debug, Default)]
+pub(crate) struct UserIds(Mutex<Registry>);
+
+#[derive(Debug, Default)]
+struct Registry {
+    /// The ID given most recently.
+    last: UserId,
+    /// The IDs of the client certificates.
+    certified: HashSet<UserId>,
+    connected: HashSet<UserId>,
+    /// The users with the started sessions, see `Dispatcher`.
+    sessions: HashSet<UserId>,
+}
+
+impl UserIds {
+    pub fn new(certified: HashSet<UserId>) -> UserIds {
+        UserIds(Mutex::new(Registry {
+            certified,
+            ..Default::default()
+        }))
+    }
+
+    /// The next free ID, none if every ID is taken.
+    pub fn next(self: &Arc<Self>) -> Option<ConnectedUser> {
+        let mut registry = self.0.lock().expect("Never poisoned");
+        for _ in 0..UserId::MAX {
+            registry.last = registry.last.wrapping_add(1).max(1);
+            let user_id = registry.last;
+            let reserved =
+                registry.certified.contains(&user_id) || registry.sessions.contains(&user_id);
+            if !reserved && registry.connected.insert(user_id) {
+                return Some(ConnectedUser {
+                    user_id,
+                    ids: self.clone(),
+                });
+            }
+        }
+        None
+    }
+
+    /// The ID of the user identified by its client certificate,
+    /// none if the user is connected already.
+    pub fn certified(self: &Arc<Self>, user_id: UserId) -> Option<ConnectedUser> {
+        let mut registry = self.0.lock().expect("Never poisoned");
+        registry.certified.insert(user_id);
+        match registry.connected.insert(user_id) {
+            true => Some(ConnectedUser {
+                user_id,
+                ids: self.clone(),
+            }),
+            false => None,
+        }
+    }
+
+    /// Keeps the ID of the user with the started session until
+    /// the session is removed.
+    pub fn keep_session(&self, user_id: UserId) {
+        let mut registry = self.0.lock().expect("Never poisoned");
+        registry.sessions.insert(user_id);
+    }
+
+    pub fn remove_session(&self, user_id: UserId) {
+        let mut registry = self.0.lock().expect("Never poisoned");
+        registry.sessions.remove(&user_id);
+    }
+}
+
+/// The ID of a connected user, given back once it's dropped,
+/// i.e. once the user's connection is closed.
+#[derive(Debug)]
+pub(crate) struct ConnectedUser {
+    pub user_id: UserId,
+    ids: Arc<UserIds>,
+}
+
+impl Drop for ConnectedUser {
+    fn drop(&mut self) {
+        if let Ok(mut registry) = self.ids.0.lock() {
+            registry.connected.remove(&self.user_id);
+        }
+    }
+}
+
+/// A connection accepted by a `Socket`.
+pub(crate) trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Stream for S {}
+
+/// Either the TCP or the Unix domain socket, shared by the users',
+/// the admin and the drop-copy listeners.
+pub(crate) enum Socket {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+impl Socket {
+    /// Bind either the TCP interface, e.g. `"127.0.0.1:8070"`, or
+    /// the Unix domain socket path, e.g. `"unix:/run/trading.sock"`.
+    pub async fn bind(listen: &str) -> anyhow::Result<Socket> {
+        Ok(match listen.strip_prefix("unix:") {
+            #[cfg(unix)]
+            Some(path) => {
+                // The socket file is left behind by the previous run.
+                let _ = std::fs::remove_file(path);
+                Socket::Unix(tokio::net::UnixListener::bind(path)?)
+            }
+            #[cfg(not(unix))]
+            Some(_) => anyhow::bail!("Unix domain sockets are not supported"),
+            None => Socket::Tcp(TcpListener::bind(listen).await?),
+        })
+    }
+
+    /// Accept the next connection. The Unix domain socket's ones
+    /// are numbered by counting the `connections`.
+    pub async fn accept(&self, connections: &mut u64) -> std::io::Result<(Box<dyn Stream>, Peer)> {
+        match self {
+            Socket::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Box::new(stream), Peer::Tcp(addr)))
+            }
+            #[cfg(unix)]
+            Socket::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                *connections += 1;
+                Ok((Box::new(stream), Peer::Unix(*connections)))
+            }
+        }
+    }
+}
+
+/// Accepts the users' connections of a single listener
+///
+/// Every user is identified by its client certificate, see
+/// `TlsAcceptor`, or gets the next ID, see `UserIds`.
+pub(crate) struct Listener {
+    config: ListenerConfig,
+    socket: Socket,
+    tls: Option<Arc<TlsAcceptor>>,
+}
+
+impl Listener {
+    pub async fn bind(config: ListenerConfig) -> anyhow::Result<Listener> {
+        let tls = config.tls.as_ref().map(TlsAcceptor::new).transpose()?;
+        let socket = Socket::bind(&config.listen).await?;
+        info!(
+            listen = %config.listen,
+            protocol = ?config.protocol,
+            tls = tls.is_some(),
+            "listening"
+        );
+        Ok(Listener {
+            config,
+            socket,
+            tls: tls.map(Arc::new),
+        })
+    }
+
+    /// Accepts the connections until the listener fails.
+    pub async fn run(
+        self,
+        ids: Arc<UserIds>,
+        heartbeat: Option<HeartbeatConfig>,
+        event_notification_sender: Sender<Event>,
+    ) -> anyhow::Result<()> {
+        let mut connections = 0u64;
+        loop {
+            let (stream, peer) = self.socket.accept(&mut connections).await?;
+            self.spawn(stream, peer, &ids, &heartbeat, &event_notification_sender);
+        }
+    }
+
+    /// Completes the TLS handshake, if any, and connects the user.
+    /// The handshake must not hold up other users.
+    fn spawn<S>(
+        &self,
+        stream: S,
+        peer: Peer,
+        ids: &Arc<UserIds>,
+        heartbeat: &Option<HeartbeatConfig>,
+        event_notification_sender: &Sender<Event>,
+    ) where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let protocol = self.config.protocol;
+        let tls = self.tls.clone();
+        let ids = ids.clone();
+        let heartbeat = heartbeat.clone();
+        let event_notification_sender = event_notification_sender.clone();
+        tokio::spawn(async move {
+            let connected = match tls {
+                Some(tls) => match tokio::time::timeout(HANDSHAKE_TIMEOUT, tls.accept(stream))
+                    .await
+                    .unwrap_or_else(|_| Err(anyhow::anyhow!("handshake timed out")))
+                {
+                    Ok((stream, certified)) => {
+                        let user = match certified {
+                            Some(user_id) => match ids.certified(user_id) {
+                                Some(user) => user,
+                                None => {
+                                    warn!(%peer, user_id, "user already connected");
+                                    return;
+                                }
+                            },
+                            None => match ids.next() {
+                                Some(user) => user,
+                                None => {
+                                    warn!(%peer, "no user ID left");
+                                    return;
+                                }
+                            },
+                        };
+                        connect(
+                            stream,
+                            peer,
+                            user,
+                            protocol,
+                            heartbeat,
+                            event_notification_sender,
+                        )
+                        .await
+                    }
+                    Err(e) => {
+                        warn!(%peer, error = %e, "TLS handshake failed");
+                        return;
+                    }
+                },
+                None => {
+                    let user = match ids.next() {
+                        Some(user) => user,
+                        None => {
+                            warn!(%peer, "no user ID left");
+                            return;
+                        }
+                    };
+                    connect(
+                        stream,
+                        peer,
+                        user,
+                        protocol,
+                        heartbeat,
+                        event_notification_sender,
+                    )
+                    .await
+                }
+            };
+            if let Err(e) = connected {
+                warn!(%peer, error = %e, "connection error");
+            }
+        });
+    }
+}
+
+/// Connects the user through the listener's protocol.
+async fn connect<S>(
+    stream: S,
+    peer: Peer,
+    user: ConnectedUser,
+    protocol: ListenerProtocol,
+    heartbeat: Option<HeartbeatConfig>,
+    event_notification_sender: Sender<Event>,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let protocol = match protocol {
+        ListenerProtocol::Text => Protocol::Text,
+        ListenerProtocol::Json => Protocol::Json,
+        ListenerProtocol::Binary => Protocol::Binary,
+        ListenerProtocol::Websocket => {
+            return crate::websocket::gateway(
+                stream,
+                peer,
+                user,
+                heartbeat,
+                event_notification_sender,
+            )
+            .await
+        }
+    };
+    Server::connect(
+        stream,
+        peer,
+        user,
+        protocol,
+        heartbeat.as_ref(),
+        &event_notification_sender,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_certified_user_ids() {
+        let ids = Arc::new(UserIds::new([1, 3].iter().copied().collect()));
+        let anonymous = ids.next().unwrap();
+        assert_eq!(anonymous.user_id, 2);
+        assert_eq!(ids.next().unwrap().user_id, 4);
+
+        // The certificate may be used by a single connection at a time.
+        let certified = ids.certified(1).unwrap();
+        assert!(ids.certified(1).is_none());
+        drop(certified);
+        assert!(ids.certified(1).is_some());
+    }
+
+    #[test]
+    fn test_kept_sessions_user_ids() {
+        let ids = Arc::new(UserIds::default());
+        let user = ids.next().unwrap();
+        assert_eq!(user.user_id, 1);
+        ids.keep_session(1);
+        drop(user);
+        // Wrap around to the kept session's ID.
+        ids.0.lock().unwrap().last = UserId::MAX;
+        assert_eq!(ids.next().unwrap().user_id, 2);
+
+        ids.remove_session(1);
+        ids.0.lock().unwrap().last = UserId::MAX;
+        assert_eq!(ids.next().unwrap().user_id, 1);
+    }
+
+    #[test]
+    fn test_user_ids_are_never_shared() {
+        let ids = Arc::new(UserIds::default());
+        let first = ids.next().unwrap();
+        let second = ids.next().unwrap();
+        assert_eq!((first.user_id, second.user_id), (1, 2));
+
+        // The counter wraps around, skipping the connected users.
+        ids.0.lock().unwrap().last = UserId::MAX - 1;
+        let last = ids.next().unwrap();
+        let third = ids.next().unwrap();
+        assert_eq!((last.user_id, third.user_id), (UserId::MAX, 3));
+
+        // The ID is given back once the user disconnects.
+        drop(first);
+        ids.0.lock().unwrap().last = 0;
+        assert_eq!(ids.next().unwrap().user_id, 1);
+
+        let all: Vec<ConnectedUser> = std::iter::from_fn(|| ids.next()).collect();
+        assert_eq!(all.len(), UserId::MAX as usize - 3);
+        assert!(ids.next().is_none());
+        drop(all);
+        assert!(ids.next().is_some());
+    }
+}
@@ -0,0 +1,101 @@
+/// Author: Tomasz Kulik
+/// 
+///
+
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, EnvFilter, Registry};
+
+/// The format of the emitted log records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    /// Human readable, one line per event.
+    Text,
+    /// One JSON object per event, including the fields
+    /// of all the enclosing spans.
+    Json,
+}
+
+/// A handle allowing to change the log filter of
+/// the running process.
+#[derive(Clone)]
+pub struct LogHandle(reload::Handle<EnvFilter, Registry>);
+
+/// Install the global subscriber.
+///
+/// The `filter` uses the `RUST_LOG` syntax, e.g. `info` or
+/// `trading=debug`. Logs are written to the stderr so they
+/// never interleave with the data written to the stdout.
+pub fn init(filter: &str, format: LogFormat) -> anyhow::Result<LogHandle> {
+    let (filter, handle) = reload::Layer::new(EnvFilter::try_new(filter)?);
+    let registry = tracing_subscriber::registry().with(filter);
+    match format {
+        LogFormat::Text => registry
+            .with(fmt::layer().with_writer(std::io::stderr))
+            .try_init()?,
+        LogFormat::Json => registry
+            .with(
+                fmt::layer()
+                    .json()
+                    .with_current_span(true)
+                    .with_span_list(true)
+                    .with_writer(std::io::stderr),
+            )
+            .try_init()?,
+    }
+    Ok(LogHandle(handle))
+}
+
+/// Verbosity levels in the increasing order.
+const LEVELS: [LevelFilter; 6] = [
+    LevelFilter::OFF,
+    LevelFilter::ERROR,
+    LevelFilter::WARN,
+    LevelFilter::INFO,
+    LevelFilter::DEBUG,
+    LevelFilter::TRACE,
+];
+
+impl LogHandle {
+    /// Replace the current filter with a new one.
+    pub fn set_filter(&self, filter: &str) -> anyhow::Result<()> {
+        self.0.reload(EnvFilter::try_new(filter)?)?;
+        tracing::info!(filter, "log filter changed");
+        Ok(())
+    }
+
+    /// Make the logs more (positive `steps`) or less (negative
+    /// `steps`) verbose.
+    ///
+    /// NOTE: The per-target directives are replaced by
+    /// a single global level.
+    pub fn shift_level(&self, steps: isize) -> anyhow::Result<()> {
+        let current = self
+            .0
+            .with_current(|filter| filter.max_level_hint())?
+            .unwrap_or(LevelFilter::TRACE);
+        let index = LEVELS.iter().position(|l| *l == current).unwrap_or(3) as isize;
+        let index = (index + steps).clamp(0, LEVELS.len() as isize - 1) as usize;
+        self.set_filter(&LEVELS[index].to_string())
+    }
+
+    /// Change the verbosity on the unix signals:
+    /// - SIGUSR1 - more verbose
+    /// - SIGUSR2 - less verbose
+    #[cfg(unix)]
+    pub async fn watch_signals(self) -> anyhow::Result<()> {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut more = signal(SignalKind::user_defined1())?;
+        let mut less = signal(SignalKind::user_defined2())?;
+        loop {
+            let steps = tokio::select! {
+                _ = more.recv() => 1,
+                _ = less.recv() => -1,
+            };
+            if let Err(e) = self.shift_level(steps) {
+                tracing::warn!(error = %e, "unable to change the log level");
+            }
+        }
+    }
+}
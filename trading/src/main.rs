@@ -2,8 +2,109 @@
 /// 
 ///
 
-#[tokio::main(flavor = "current_thread")]
+use clap::{Parser, Subcommand};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+use trading::config::Config;
+use trading::logging::{self, LogFormat};
+
+/// Trading market
+#[derive(Parser)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// The TOML configuration file.
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+
+    /// The interface the server listens on, overrides the configuration.
+    #[arg(long)]
+    interface: Option<String>,
+
+    /// The log filter, e.g. `info` or `trading=debug`.
+    /// It may be changed at runtime with SIGUSR1 (more verbose)
+    /// and SIGUSR2 (less verbose).
+    #[arg(long, env = "RUST_LOG", default_value = "info")]
+    log: String,
+
+    /// The format of the logs.
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run the trading server, the default.
+    Serve,
+    /// Replay the journal without any networking and write
+    /// the messages sent to the users.
+    Replay {
+        /// The journal, one `<TIMESTAMP> <USER_ID> <ORDER>` per line.
+        input: PathBuf,
+        /// Where the messages are written, stdout by default.
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Check that no record of the journal was edited, removed
+    /// or inserted, reporting the first broken link, and print
+    /// the head of its hash chain.
+    VerifyAudit {
+        /// The journal written by the server.
+        input: PathBuf,
+        /// The head logged by the server's `journal checkpoint`, or
+        /// printed by the earlier audit, the journal has to contain.
+        #[arg(long)]
+        checkpoint: Option<String>,
+    },
+    /// Write the end-of-day CSV reports of the journal's last run.
+    Report {
+        /// The journal of the day.
+        input: PathBuf,
+        /// The directory the reports are written to.
+        #[arg(long, default_value = ".")]
+        dir: PathBuf,
+    },
+}
+
+#[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    println!("Trading market");
-    trading::start_server("127.0.0.1:8080".to_string()).await
+    let args = Args::parse();
+    let log_handle = logging::init(&args.log, args.log_format)?;
+    #[cfg(unix)]
+    tokio::spawn(log_handle.watch_signals());
+
+    let mut config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    if let Some(interface) = args.interface {
+        config.interface = interface;
+    }
+
+    match args.command.unwrap_or(Command::Serve) {
+        Command::Serve => {
+            tracing::info!("Trading market");
+            trading::run(config).await
+        }
+        Command::Replay { input, output } => {
+            let input = BufReader::new(File::open(input)?);
+            match output {
+                Some(output) => trading::replay::replay(&config, input, File::create(output)?),
+                None => trading::replay::replay(&config, input, std::io::stdout().lock()),
+            }
+        }
+        Command::VerifyAudit { input, checkpoint } => {
+            let audit = trading::audit::verify_audit(
+                BufReader::new(File::open(input)?),
+                checkpoint.as_deref(),
+            )?;
+            println!("OK:{} records, head {}", audit.records, audit.head);
+            Ok(())
+        }
+        Command::Report { input, dir } => {
+            trading::report::report(&config, BufReader::new(File::open(input)?), &dir)
+        }
+    }
 }
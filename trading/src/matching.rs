@@ -0,0 +1,119 @@
+/// Author: Tomasz Kulik
+/// 
+///
+
+use crate::config::Matching;
+use crate::order::{Order, Quantity};
+use std::collections::VecDeque;
+
+/// Allocates the incoming order across the resting orders
+/// at a single price level.
+///
+/// The ledger asks the policy about the best price level
+/// until the incoming order is filled or the price is not
+/// acceptable anymore.
+pub trait MatchingPolicy: Send {
+    /// The quantities filled of every resting order, in the time
+    /// priority. None of them may exceed the `available` quantity of
+    /// its order and their sum may not exceed the incoming quantity.
+    /// The level is never empty and each of its orders has some
+    /// quantity available, so at least a unit has to be allocated.
+    fn allocate(&self, level: &VecDeque<Order>, quantity: Quantity) -> Vec<Quantity>;
+}
+
+impl Matching {
+    /// The policy implementing the algorithm.
+    pub fn policy(self) -> Box<dyn MatchingPolicy> {
+        match self {
+            Matching::Fifo => Box::new(Fifo),
+            Matching::ProRata => Box::new(ProRata),
+        }
+    }
+}
+
+/// The oldest resting order is filled first.
+pub struct Fifo;
+
+impl MatchingPolicy for Fifo {
+    fn allocate(&self, level: &VecDeque<Order>, mut quantity: Quantity) -> Vec<Quantity> {
+        level
+            .iter()
+            .map(|order| {
+                let allocated = order.available().min(quantity);
+                quantity -= allocated;
+                allocated
+            })
+            .collect()
+    }
+}
+
+/// Every resting order is filled in proportion to its available
+/// quantity, rounded down. What's left due to the rounding goes
+/// to the resting orders in the time priority, a unit each.
+pub struct ProRata;
+
+impl MatchingPolicy for ProRata {
+    fn allocate(&self, level: &VecDeque<Order>, quantity: Quantity) -> Vec<Quantity> {
+        let total: u128 = level.iter().map(|order| order.available() as u128).sum();
+        if total <= quantity as u128 {
+            return level.iter().map(Order::available).collect();
+        }
+        let mut allocation: Vec<Quantity> = level
+            .iter()
+            .map(|order| (order.available() as u128 * quantity as u128 / total) as Quantity)
+            .collect();
+        // Less than a unit is lost per order, and every one of them
+        // got less than its available quantity.
+        let left = quantity - allocation.iter().sum::<Quantity>();
+        for allocated in allocation.iter_mut().take(left as usize) {
+            *allocated += 1;
+        }
+        allocation
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::order::{Side, Visibility};
+    use crate::transaction::Product;
+
+    fn level(quantities: &[Quantity]) -> VecDeque<Order> {
+        quantities
+            .iter()
+            .enumerate()
+            .map(|(id, quantity)| Order {
+                id: id as u64 + 1,
+                timestamp: 0,
+                user_id: 1,
+                side: Side::Sell,
+                product: Product::Apple,
+                quantity: *quantity,
+                limit: Some(100),
+                short_form: false,
+                visibility: Visibility::Visible,
+                stop: None,
+                expiry: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_fifo_allocation() {
+        assert_eq!(Fifo.allocate(&level(&[5, 3, 2]), 7), vec![5, 2, 0]);
+        assert_eq!(Fifo.allocate(&level(&[5, 3]), 10), vec![5, 3]);
+    }
+
+    #[test]
+    fn test_pro_rata_allocation() {
+        assert_eq!(ProRata.allocate(&level(&[60, 30, 10]), 50), vec![30, 15, 5]);
+        // The rounding leftovers follow the time priority.
+        assert_eq!(ProRata.allocate(&level(&[1, 1, 1]), 2), vec![1, 1, 0]);
+        assert_eq!(ProRata.allocate(&level(&[10, 20, 10]), 7), vec![2, 4, 1]);
+        assert_eq!(ProRata.allocate(&level(&[5, 3]), 10), vec![5, 3]);
+        assert_eq!(
+            ProRata.allocate(&level(&[u64::MAX, u64::MAX]), u64::MAX),
+            vec![u64::MAX / 2 + 1, u64::MAX / 2]
+        );
+    }
+}
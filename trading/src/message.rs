@@ -0,0 +1,354 @@
+/// Author: Tomasz Kulik
+/// 
+///
+
+use crate::fees::{Amount, Statement};
+use crate::ledger::{Depth, Phase};
+use crate::order::{OrderId, Price, Quantity};
+use crate::transaction::Product;
+
+/// Identifies the user's session, see `RESUME`. It's drawn from
+/// the system's secure random generator, so it can't be guessed.
+pub type SessionId = u64;
+
+/// The number of the message within the user's session.
+pub type SequenceNumber = u64;
+
+/// A single message sent by the server to the user.
+///
+/// It's independent of the connection, it's rendered as
+/// a line of the text protocol with `Display`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    /// The order was accepted. The short form orders are
+    /// acknowledged without the order ID.
+    Ack {
+        product: Product,
+        order_id: Option<OrderId>,
+    },
+    /// The order or the command was rejected.
+    Error(String),
+    /// The user's order was filled, sent to its owner only.
+    /// The fee is given if the product has the fees.
+    Fill {
+        order_id: OrderId,
+        quantity: Quantity,
+        price: Option<Price>,
+        fee: Option<Amount>,
+    },
+    /// A transaction took place in the market.
+    Trade {
+        product: Product,
+        quantity: Quantity,
+        price: Option<Price>,
+    },
+    /// The auction would uncross at this volume and price.
+    Indicative {
+        product: Product,
+        volume: Quantity,
+        price: Option<Price>,
+    },
+    /// The batch auction has cleared at this volume and price.
+    Cleared {
+        product: Product,
+        volume: Quantity,
+        price: Option<Price>,
+    },
+    /// The trading phase of the product has changed.
+    Phase { product: Product, phase: Phase },
+    /// The user's order was removed from the book.
+    Cancelled {
+        product: Product,
+        order_id: Option<OrderId>,
+    },
+    /// The user's good-till-date order has expired and was
+    /// removed from the book.
+    Expired { product: Product, order_id: OrderId },
+    /// The session was started or resumed, the number of
+    /// the most recent message is given.
+    Session {
+        session: SessionId,
+        seq: SequenceNumber,
+    },
+    /// The messages in this range were missed and can't be
+    /// sent again.
+    Gap {
+        from: SequenceNumber,
+        to: SequenceNumber,
+    },
+    /// Sent once the connection has been idle for a while,
+    /// so the user knows the server is alive.
+    Heartbeat,
+    /// The quantities shown at the best price levels of the
+    /// product, see `BOOK`.
+    Book {
+        product: Product,
+        bids: Depth,
+        asks: Depth,
+    },
+    /// The user's account so far, see `STATEMENT`.
+    Statement(Statement),
+}
+
+impl std::fmt::Display for Message {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Message::Ack {
+                product,
+                order_id: None,
+            } => write!(f, "ACK:{}", product),
+            Message::Ack {
+                product,
+                order_id: Some(order_id),
+            } => write!(f, "ACK:{}:{}", product, order_id),
+            Message::Error(reason) => write!(f, "ERROR:{}", reason),
+            Message::Fill {
+                order_id,
+                quantity,
+                price,
+                fee: None,
+            } => write!(f, "FILL:{}:{}", order_id, size(*quantity, *price)),
+            Message::Fill {
+                order_id,
+                quantity,
+                price,
+                fee: Some(fee),
+            } => write!(f, "FILL:{}:{}:{}", order_id, size(*quantity, *price), fee),
+            Message::Trade {
+                product,
+                quantity: 1,
+                price: None,
+            } => write!(f, "TRADE:{}", product),
+            Message::Trade {
+                product,
+                quantity,
+                price,
+            } => write!(f, "TRADE:{}:{}", product, size(*quantity, *price)),
+            Message::Indicative {
+                product,
+                volume,
+                price,
+            } => write!(f, "INDICATIVE:{}:{}", product, size(*volume, *price)),
+            Message::Cleared {
+                product,
+                volume,
+                price,
+            } => write!(f, "CLEARED:{}:{}", product, size(*volume, *price)),
+            Message::Phase { product, phase } => write!(f, "PHASE:{}:{}", product, phase),
+            Message::Cancelled {
+                product,
+                order_id: None,
+            } => write!(f, "CANCELLED:{}", product),
+            Message::Cancelled {
+                product,
+                order_id: Some(order_id),
+            } => write!(f, "CANCELLED:{}:{}", product, order_id),
+            Message::Expired { product, order_id } => {
+                write!(f, "EXPIRED:{}:{}", product, order_id)
+            }
+            Message::Session { session, seq } => write!(f, "SESSION:{}:{}", session, seq),
+            Message::Gap { from, to } => write!(f, "GAP:{}:{}", from, to),
+            Message::Heartbeat => write!(f, "HEARTBEAT"),
+            Message::Book {
+                product,
+                bids,
+                asks,
+            } => write!(f, "BOOK:{}:{}:{}", product, levels(bids), levels(asks)),
+            Message::Statement(statement) => write!(f, "{}", statement),
+        }
+    }
+}
+
+impl std::str::FromStr for Message {
+    type Err = String;
+
+    /// Parse the line sent by the server, the inverse of `Display`.
+    fn from_str(input: &str) -> Result<Message, String> {
+        let unknown = || format!("Unknown message: {}", input);
+        if let Some(reason) = input.strip_prefix("ERROR:") {
+            return Ok(Message::Error(reason.to_string()));
+        }
+        if input.starts_with("STATEMENT:") {
+            return input.parse().map(Message::Statement);
+        }
+        let order_id = |id: &str| id.parse().map_err(|_| unknown());
+        let number = |n: &str| n.parse().map_err(|_| unknown());
+        let fields: Vec<&str> = input.split(':').collect();
+        match fields[..] {
+            ["ACK", product] => Ok(Message::Ack {
+                product: product.parse()?,
+                order_id: None,
+            }),
+            ["ACK", product, id] => Ok(Message::Ack {
+                product: product.parse()?,
+                order_id: Some(order_id(id)?),
+            }),
+            ["FILL", id, fill] => {
+                let (quantity, price) = parse_size(fill).ok_or_else(unknown)?;
+                Ok(Message::Fill {
+                    order_id: order_id(id)?,
+                    quantity,
+                    price,
+                    fee: None,
+                })
+            }
+            ["FILL", id, fill, fee] => {
+                let (quantity, price) = parse_size(fill).ok_or_else(unknown)?;
+                Ok(Message::Fill {
+                    order_id: order_id(id)?,
+                    quantity,
+                    price,
+                    fee: Some(fee.parse().map_err(|_| unknown())?),
+                })
+            }
+            ["TRADE", product] => Ok(Message::Trade {
+                product: product.parse()?,
+                quantity: 1,
+                price: None,
+            }),
+            ["TRADE", product, trade] => {
+                let (quantity, price) = parse_size(trade).ok_or_else(unknown)?;
+                Ok(Message::Trade {
+                    product: product.parse()?,
+                    quantity,
+                    price,
+                })
+            }
+            ["INDICATIVE", product, indicative] => {
+                let (volume, price) = parse_size(indicative).ok_or_else(unknown)?;
+                Ok(Message::Indicative {
+                    product: product.parse()?,
+                    volume,
+                    price,
+                })
+            }
+            ["CLEARED", product, cleared] => {
+                let (volume, price) = parse_size(cleared).ok_or_else(unknown)?;
+                Ok(Message::Cleared {
+                    product: product.parse()?,
+                    volume,
+                    price,
+                })
+            }
+            ["PHASE", product, phase] => Ok(Message::Phase {
+                product: product.parse()?,
+                phase: phase.parse()?,
+            }),
+            ["CANCELLED", product] => Ok(Message::Cancelled {
+                product: product.parse()?,
+                order_id: None,
+            }),
+            ["CANCELLED", product, id] => Ok(Message::Cancelled {
+                product: product.parse()?,
+                order_id: Some(order_id(id)?),
+            }),
+            ["EXPIRED", product, id] => Ok(Message::Expired {
+                product: product.parse()?,
+                order_id: order_id(id)?,
+            }),
+            ["SESSION", session, seq] => Ok(Message::Session {
+                session: number(session)?,
+                seq: number(seq)?,
+            }),
+            ["GAP", from, to] => Ok(Message::Gap {
+                from: number(from)?,
+                to: number(to)?,
+            }),
+            ["HEARTBEAT"] => Ok(Message::Heartbeat),
+            ["BOOK", product, bids, asks] => Ok(Message::Book {
+                product: product.parse()?,
+                bids: parse_levels(bids).ok_or_else(unknown)?,
+                asks: parse_levels(asks).ok_or_else(unknown)?,
+            }),
+            _ => Err(unknown()),
+        }
+    }
+}
+
+/// Formats the price levels as `<QUANTITY>@<PRICE>,...`.
+fn levels(levels: &[(Quantity, Price)]) -> String {
+    let levels: Vec<String> = levels
+        .iter()
+        .map(|(quantity, price)| size(*quantity, Some(*price)))
+        .collect();
+    levels.join(",")
+}
+
+/// Parse the price levels formatted by `levels`.
+fn parse_levels(input: &str) -> Option<Depth> {
+    if input.is_empty() {
+        return Some(vec![]);
+    }
+    input
+        .split(',')
+        .map(|level| match parse_size(level)? {
+            (quantity, Some(price)) => Some((quantity, price)),
+            (_, None) => None,
+        })
+        .collect()
+}
+
+/// Splits the sequence number off the line, if it has one.
+pub(crate) fn split_seq(line: &str) -> (Option<SequenceNumber>, &str) {
+    match line.split_once(' ') {
+        Some((seq, message)) => match seq.parse() {
+            Ok(seq) => (Some(seq), message),
+            Err(_) => (None, line),
+        },
+        None => (None, line),
+    }
+}
+
+/// Parse the `<QUANTITY>[@<PRICE>]` part of a message.
+pub(crate) fn parse_size(input: &str) -> Option<(Quantity, Option<Price>)> {
+    match input.split_once('@') {
+        Some((quantity, price)) => Some((quantity.parse().ok()?, Some(price.parse().ok()?))),
+        None => Some((input.parse().ok()?, None)),
+    }
+}
+
+/// Formats the quantity and the optional price as `<QUANTITY>[@<PRICE>]`.
+pub(crate) fn size(quantity: Quantity, price: Option<Price>) -> String {
+    match price {
+        Some(price) => format!("{}@{}", quantity, price),
+        None => quantity.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_round_trip() {
+        let lines = [
+            "ACK:APPLE",
+            "ACK:APPLE:1",
+            "ERROR:APPLE market closed: try again",
+            "FILL:1:5@100",
+            "FILL:2:3",
+            "FILL:2:3@100:-3",
+            "TRADE:PEAR",
+            "TRADE:PEAR:1@99",
+            "INDICATIVE:ONION:0",
+            "INDICATIVE:ONION:5@100",
+            "CLEARED:ONION:5@100",
+            "PHASE:TOMATO:HALTED",
+            "CANCELLED:POTATO",
+            "CANCELLED:POTATO:7",
+            "EXPIRED:POTATO:8",
+            "SESSION:12345678901234:0",
+            "GAP:3:17",
+            "HEARTBEAT",
+            "BOOK:APPLE:5@100,3@99:2@101",
+            "BOOK:APPLE::",
+        ];
+        for line in lines.iter() {
+            let message: Message = line.parse().unwrap();
+            assert_eq!(message.to_string(), *line);
+        }
+        assert!("ACK:BANANA".parse::<Message>().is_err());
+        assert!("FILL:1:x@100".parse::<Message>().is_err());
+        assert!("BOOK:APPLE:5:".parse::<Message>().is_err());
+    }
+}
@@ -3,42 +3,304 @@
 ///
 
 use crate::transaction::Product;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
 
 /// The unique User ID
 ///
 pub type UserId = u16;
 
+/// The unique Order ID, assigned by the server
+/// in the order of arrival.
+///
+pub type OrderId = u64;
+
+/// The price expressed in ticks
+///
+pub type Price = u64;
+
+/// The number of units of a product
+///
+pub type Quantity = u64;
+
+/// The number of milliseconds since the UNIX epoch
+///
+pub type Timestamp = u64;
+
+/// The furthest the good-till-date order may expire, a year
+/// after it's accepted.
+pub const MAX_EXPIRY_HORIZON: Timestamp = 365 * 24 * 60 * 60 * 1000;
+
+/// The side of an order
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Side {
+    Buy,
+    Sell,
+}
+
+impl Side {
+    pub fn opposite(self) -> Side {
+        match self {
+            Side::Buy => Side::Sell,
+            Side::Sell => Side::Buy,
+        }
+    }
+}
+
+impl std::fmt::Display for Side {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Side::Buy => write!(f, "BUY"),
+            Side::Sell => write!(f, "SELL"),
+        }
+    }
+}
+
+/// How much of the resting order is shown in the book's depth,
+/// see `ProductLedger::depth`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    /// The whole order is shown.
+    Visible,
+    /// Only the peak is shown. Once it's filled, the next peak is
+    /// taken from the hidden reserve and the order loses its time
+    /// priority.
+    Iceberg {
+        peak: Quantity,
+        /// What's left of the current peak.
+        shown: Quantity,
+    },
+    /// Nothing is shown, the order is matched like any other.
+    Hidden,
+}
+
 /// A convinient type representing a user single order
 ///
-#[derive(Debug)]
-pub enum Order {
-    Buy(UserId, Product),
-    Sell(UserId, Product),
+/// An order without the limit price accepts any price.
+/// It rests in the ledger like any other order and takes
+/// the precedence over the limit orders.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Order {
+    /// Assigned by the server, `0` until the order is accepted.
+    pub id: OrderId,
+    /// The time the order was accepted by the server.
+    pub timestamp: Timestamp,
+    pub user_id: UserId,
+    pub side: Side,
+    pub product: Product,
+    pub quantity: Quantity,
+    pub limit: Option<Price>,
+    /// The order was sent in the original `BUY:<PRODUCT>` form.
+    /// Such orders are acknowledged without the order ID and
+    /// the user is informed only about the public trades.
+    pub short_form: bool,
+    pub visibility: Visibility,
+    /// The stop price of the order waiting for its trigger,
+    /// see `Order::triggered`. It's cleared once the order
+    /// enters the book.
+    pub stop: Option<Price>,
+    /// The good-till-date order is removed from the book once
+    /// this time comes, at most `MAX_EXPIRY_HORIZON` after it's
+    /// accepted.
+    pub expiry: Option<Timestamp>,
 }
 
 impl Order {
+    /// Parse the order sent by the user.
+    ///
+    /// The accepted format is `<SIDE>:<PRODUCT>[:<QUANTITY>[@<PRICE>]]`,
+    /// e.g. `BUY:APPLE`, `SELL:PEAR:10` or `BUY:ONION:5@120`.
+    /// The quantity defaults to 1. The orders, except the short ones,
+    /// may be followed by the options:
+    /// - `:ICEBERG:<PEAK>` or `:HIDDEN` - the limit orders only, see `Visibility`
+    /// - `:STOP:<STOP_PRICE>` - the stop or stop-limit order, never hidden
+    /// - `:GTD:<TIMESTAMP>` - the order expires at the given time, at most
+    ///   a year ahead
+    ///
+    /// e.g. `SELL:PEAR:100@90:ICEBERG:10` or `SELL:PEAR:10:STOP:95:GTD:1700000000000`.
     pub fn new_order_form_str(user_id: UserId, input: &str) -> Result<Order, String> {
-        match input {
-            "BUY:APPLE" => Ok(Order::Buy(user_id, Product::Apple)),
-            "BUY:PEAR" => Ok(Order::Buy(user_id, Product::Pear)),
-            "BUY:TOMATO" => Ok(Order::Buy(user_id, Product::Tomato)),
-            "BUY:POTATO" => Ok(Order::Buy(user_id, Product::Potato)),
-            "BUY:ONION" => Ok(Order::Buy(user_id, Product::Onion)),
-            "SELL:APPLE" => Ok(Order::Sell(user_id, Product::Apple)),
-            "SELL:PEAR" => Ok(Order::Sell(user_id, Product::Pear)),
-            "SELL:TOMATO" => Ok(Order::Sell(user_id, Product::Tomato)),
-            "SELL:POTATO" => Ok(Order::Sell(user_id, Product::Potato)),
-            "SELL:ONION" => Ok(Order::Sell(user_id, Product::Onion)),
-            _ => Err(format!("Unknown order: {}", input)),
+        let mut fields = input.splitn(4, ':');
+        let side = match fields.next() {
+            Some("BUY") => Side::Buy,
+            Some("SELL") => Side::Sell,
+            _ => return Err(format!("Unknown order: {}", input)),
+        };
+        let product = fields
+            .next()
+            .ok_or_else(|| format!("Unknown order: {}", input))?
+            .parse()?;
+        let size = fields.next();
+        let (quantity, limit) = match size {
+            None => (1, None),
+            Some(size) => parse_size(size)?,
+        };
+        let unknown = || format!("Unknown order: {}", input);
+        let number = |value: Option<&str>| {
+            value
+                .and_then(|value| value.parse().ok())
+                .filter(|value| *value > 0 && *value < u64::MAX)
+                .ok_or_else(unknown)
+        };
+        let mut visibility = Visibility::Visible;
+        let mut stop = None;
+        let mut expiry = None;
+        let mut options = fields.next().into_iter().flat_map(|rest| rest.split(':'));
+        while let Some(option) = options.next() {
+            match option {
+                "HIDDEN" if visibility == Visibility::Visible => visibility = Visibility::Hidden,
+                "ICEBERG" if visibility == Visibility::Visible => {
+                    let peak = number(options.next())?;
+                    visibility = Visibility::Iceberg { peak, shown: 0 };
+                }
+                "STOP" if stop.is_none() => stop = Some(number(options.next())?),
+                "GTD" if expiry.is_none() => expiry = Some(number(options.next())?),
+                _ => return Err(unknown()),
+            }
+        }
+        if visibility != Visibility::Visible && (limit.is_none() || stop.is_some()) {
+            return Err(format!("Only the limit orders may be hidden: {}", input));
+        }
+        Ok(Order {
+            id: 0,
+            timestamp: 0,
+            user_id,
+            side,
+            product,
+            quantity,
+            limit,
+            short_form: size.is_none(),
+            visibility,
+            stop,
+            expiry,
+        })
+    }
+
+    /// The quantity shown in the book's depth.
+    pub fn shown(&self) -> Quantity {
+        match self.visibility {
+            Visibility::Visible => self.quantity,
+            Visibility::Iceberg { shown, .. } => shown,
+            Visibility::Hidden => 0,
         }
     }
+
+    /// The quantity the resting order may be matched with before
+    /// it loses its time priority.
+    pub fn available(&self) -> Quantity {
+        match self.visibility {
+            Visibility::Iceberg { shown, .. } => shown,
+            _ => self.quantity,
+        }
+    }
+
+    /// Fill the resting order with the given quantity.
+    pub fn fill(&mut self, quantity: Quantity) {
+        self.quantity -= quantity;
+        if let Visibility::Iceberg { shown, .. } = &mut self.visibility {
+            *shown = shown.saturating_sub(quantity).min(self.quantity);
+        }
+    }
+
+    /// Whether the iceberg order has to show its next peak.
+    pub fn exhausted(&self) -> bool {
+        self.quantity > 0 && self.available() == 0
+    }
+
+    /// Show the next peak of the iceberg order.
+    pub fn refresh(&mut self) {
+        if let Visibility::Iceberg { peak, shown } = &mut self.visibility {
+            *shown = (*peak).min(self.quantity);
+        }
+    }
+
+    /// Whether the stop order is triggered by the trade at the
+    /// given price, i.e. the buy orders when it reaches their stop
+    /// price or goes above it, the sell ones when it goes below.
+    pub fn triggered(&self, last_price: Price) -> bool {
+        match (self.side, self.stop) {
+            (Side::Buy, Some(stop)) => last_price >= stop,
+            (Side::Sell, Some(stop)) => last_price <= stop,
+            (_, None) => true,
+        }
+    }
+
+    /// The visibility, the stop price and the expiry in the form
+    /// they were sent by the user, e.g. `:ICEBERG:10:GTD:1700000000000`,
+    /// empty for the plain orders.
+    pub fn suffix(&self) -> String {
+        let mut suffix = match self.visibility {
+            Visibility::Visible => String::new(),
+            Visibility::Iceberg { peak, .. } => format!(":ICEBERG:{}", peak),
+            Visibility::Hidden => ":HIDDEN".to_string(),
+        };
+        if let Some(stop) = self.stop {
+            suffix += &format!(":STOP:{}", stop);
+        }
+        if let Some(expiry) = self.expiry {
+            suffix += &format!(":GTD:{}", expiry);
+        }
+        suffix
+    }
+}
+
+/// Assigns the order IDs and remembers the products of the
+/// orders, so the requests referring to an order by its ID
+/// only, e.g. `REPLACE`, reach the ledger of its product.
+///
+/// The IDs are assigned one after another, starting with 1,
+/// so a single byte per order is kept.
+#[derive(Debug, Default)]
+pub(crate) struct OrderIds(Vec<Product>);
+
+impl OrderIds {
+    /// Assign the ID to the next order of the product.
+    pub fn next(&mut self, product: Product) -> OrderId {
+        self.0.push(product);
+        self.0.len() as OrderId
+    }
+
+    /// The product of the order with the given ID.
+    pub fn product(&self, order_id: OrderId) -> Option<Product> {
+        let index = order_id.checked_sub(1)?;
+        self.0.get(usize::try_from(index).ok()?).copied()
+    }
+}
+
+/// Parse the `<QUANTITY>[@<PRICE>]` part of an order.
+pub fn parse_size(input: &str) -> Result<(Quantity, Option<Price>), String> {
+    let (quantity, price) = match input.split_once('@') {
+        Some((quantity, price)) => (quantity, Some(price)),
+        None => (input, None),
+    };
+    let quantity = match quantity.parse() {
+        Ok(0) | Err(_) => return Err(format!("Invalid quantity: {}", quantity)),
+        Ok(quantity) => quantity,
+    };
+    let price = match price.map(str::parse) {
+        None => None,
+        Some(Ok(price)) if price > 0 && price < Price::MAX => Some(price),
+        Some(_) => return Err(format!("Invalid price: {}", price.unwrap_or_default())),
+    };
+    Ok((quantity, price))
 }
 
 impl std::fmt::Display for Order {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Order::Buy(user_id, product) => write!(f, "new buy order ({}, {})", user_id, product),
-            Order::Sell(user_id, product) => write!(f, "new sell order ({}, {})", user_id, product),
+        let side = match self.side {
+            Side::Buy => "buy",
+            Side::Sell => "sell",
+        };
+        write!(
+            f,
+            "new {} order ({}, {} {}",
+            side, self.user_id, self.quantity, self.product
+        )?;
+        match self.limit {
+            Some(price) => write!(f, " @ {})", price),
+            None => write!(f, ")"),
         }
     }
 }
@@ -0,0 +1,135 @@
+/// Author: Tomasz Kulik
+/// 
+///
+
+use crate::json::message_to_json;
+use crate::message::{Message, SequenceNumber};
+use crate::order::UserId;
+use crate::request::Request;
+use std::io::Write;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
+
+/// The longest line of the text and JSON protocols, without its
+/// line break. The connection sending a longer one is closed, like
+/// the binary frames are limited by their length.
+pub(crate) const MAX_LINE: usize = 4096;
+
+/// The encoding of a single connection.
+///
+/// The connections of the text listeners start with the text
+/// protocol, e.g. `BUY:APPLE:5@100` and `ACK:APPLE:1`. The user
+/// may switch it into the JSON lines with the `PROTO:JSON`
+/// greeting, i.e. its first line, see `crate::json`. The binary
+/// protocol is chosen by the listener, see `crate::binary`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Protocol {
+    Text,
+    Json,
+    Binary,
+}
+
+impl Protocol {
+    /// The greeting switching the connection into the protocol.
+    pub fn from_greeting(line: &[u8]) -> Option<Protocol> {
+        match line {
+            b"PROTO:TEXT" => Some(Protocol::Text),
+            b"PROTO:JSON" => Some(Protocol::Json),
+            _ => None,
+        }
+    }
+
+    /// Read the next line or frame sent by the user into the buffer.
+    ///
+    /// Returns false once the connection is closed, and the error
+    /// if the line is longer than `MAX_LINE`.
+    pub async fn read<R>(self, reader: &mut R, buffer: &mut Vec<u8>) -> std::io::Result<bool>
+    where
+        R: AsyncBufRead + Unpin,
+    {
+        if self == Protocol::Binary {
+            return crate::binary::read_frame(reader, buffer).await;
+        }
+        buffer.clear();
+        let limit = MAX_LINE as u64 + 1;
+        if reader.take(limit).read_until(b'\n', buffer).await? == 0 {
+            return Ok(false);
+        }
+        if buffer.len() > MAX_LINE && buffer.last() != Some(&b'\n') {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "line too long",
+            ));
+        }
+        while buffer.last().is_some_and(|c| c.is_ascii_whitespace()) {
+            buffer.pop();
+        }
+        Ok(true)
+    }
+
+    /// Parse the request read by `read`.
+    pub fn decode(self, user_id: UserId, input: &[u8]) -> Result<Request, String> {
+        if self == Protocol::Binary {
+            return crate::binary::decode_request(user_id, input);
+        }
+        let line = std::str::from_utf8(input).map_err(|e| format!("Invalid request: {}", e))?;
+        match self {
+            Protocol::Json => crate::json::decode_request(user_id, line),
+            _ => Request::new_from_str(user_id, line),
+        }
+    }
+
+    /// Append the message, as a single line or frame, to the buffer.
+    pub fn encode(self, seq: Option<SequenceNumber>, message: &Message, buffer: &mut Vec<u8>) {
+        let _ = match (self, seq) {
+            (Protocol::Text, Some(seq)) => writeln!(buffer, "{} {}", seq, message),
+            (Protocol::Text, None) => writeln!(buffer, "{}", message),
+            (Protocol::Json, seq) => writeln!(buffer, "{}", message_to_json(seq, message)),
+            (Protocol::Binary, _) => {
+                crate::binary::encode_message(message, buffer);
+                Ok(())
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_line_length_limit() {
+        let mut buffer = vec![];
+        let line = format!("{}\n{}\n", "A".repeat(MAX_LINE), "B".repeat(MAX_LINE + 1));
+        let mut reader = line.as_bytes();
+        assert!(Protocol::Text.read(&mut reader, &mut buffer).await.unwrap());
+        assert_eq!(buffer.len(), MAX_LINE);
+        let error = Protocol::Json.read(&mut reader, &mut buffer).await;
+        assert_eq!(error.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn test_json_lines_injection() {
+        let lines = concat!(
+            r#"{"type":"order","side":"BUY","product":"APPLE","quantity":5,"price":100}"#,
+            "\n",
+            r#"{"type":"order","side":"BUY","product":"APPLE:5@100:HIDDEN"}"#,
+            "\n",
+            r#"{"type":"order","side":"CANCEL","product":"APPLE","quantity":1}"#,
+            "\n",
+            r#"{"type":"book","product":"APPLE\nSELL:APPLE"}"#,
+            "\n",
+        );
+        let mut reader = lines.as_bytes();
+        let mut buffer = vec![];
+        assert!(Protocol::Json.read(&mut reader, &mut buffer).await.unwrap());
+        assert_eq!(
+            Protocol::Json.decode(1, &buffer),
+            Protocol::Text.decode(1, b"BUY:APPLE:5@100")
+        );
+        for _ in 0..3 {
+            assert!(Protocol::Json.read(&mut reader, &mut buffer).await.unwrap());
+            assert!(Protocol::Json.decode(1, &buffer).is_err());
+        }
+        assert!(!Protocol::Json.read(&mut reader, &mut buffer).await.unwrap());
+    }
+}
@@ -0,0 +1,276 @@
+/// Author: Tomasz Kulik
+/// 
+///
+
+use crate::config::Config;
+use crate::dispatcher::Dispatch;
+use crate::journal::{Entry, Record};
+use crate::ledger::Ledger;
+use crate::order::OrderIds;
+use crate::shard::{Shard, ShardEvent, ShardState};
+use crate::transaction::Product;
+use anyhow::anyhow;
+use std::collections::BTreeMap;
+use std::io::{BufRead, Write};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+
+/// Replays the journal without any networking
+///
+/// Every record is applied in the order of the journal, exactly
+/// like the server applies the sequenced events, so the same
+/// journal always results in the same output. Every message is
+/// written as a single line, prefixed with the ID of the user
+/// it's sent to, or with `*` if it's sent to every user, e.g.
+/// `51234 ACK:APPLE:1` or `* TRADE:APPLE:5@100`.
+///
+/// The settings affecting the matching, e.g. the circuit breaker,
+/// are taken from the configuration. The schedule is ignored, the
+/// phase changes are recorded in the journal. Every `START` of the
+/// server begins with the empty books, like the server itself.
+pub fn replay(config: &Config, input: impl BufRead, mut output: impl Write) -> anyhow::Result<()> {
+    let mut replay = Replay::new(config);
+    for record in records(input) {
+        replay.apply(record?.entry);
+        write_messages(&mut replay.messages, &mut output)?;
+    }
+    output.flush()?;
+    Ok(())
+}
+
+/// Parses the journal, skipping the blank lines and the comments.
+pub(crate) fn records(input: impl BufRead) -> impl Iterator<Item = anyhow::Result<Record>> {
+    input
+        .lines()
+        .enumerate()
+        .filter_map(|(number, line)| match line {
+            Ok(line) if line.trim().is_empty() || line.starts_with('#') => None,
+            Ok(line) => Some(
+                line.parse()
+                    .map_err(|e| anyhow!("line {}: {}", number + 1, e)),
+            ),
+            Err(e) => Some(Err(e.into())),
+        })
+}
+
+/// The shards the journal is applied to, one record at a time.
+pub(crate) struct Replay {
+    config: Config,
+    shards: BTreeMap<Product, Shard>,
+    order_ids: OrderIds,
+    dispatcher: UnboundedSender<Dispatch>,
+    /// What the shards have dispatched so far.
+    pub messages: UnboundedReceiver<Dispatch>,
+}
+
+impl Replay {
+    pub fn new(config: &Config) -> Replay {
+        let (dispatcher, messages) = unbounded_channel();
+        Replay {
+            config: config.clone(),
+            shards: shards(config, &dispatcher),
+            order_ids: OrderIds::default(),
+            dispatcher,
+            messages,
+        }
+    }
+
+    pub fn apply(&mut self, entry: Entry) {
+        match entry {
+            Entry::Start => {
+                self.shards = shards(&self.config, &self.dispatcher);
+                self.order_ids = OrderIds::default();
+            }
+            Entry::Order(mut order) => {
+                order.id = self.order_ids.next(order.product);
+                self.shard(order.product)
+                    .handle_event(ShardEvent::Order(order));
+            }
+            Entry::Cancel(cancel) => {
+                self.shard(cancel.product)
+                    .handle_event(ShardEvent::CancelOrder(cancel));
+            }
+            Entry::Replace(replace) => {
+                // The requests of the unknown orders were not recorded.
+                if let Some(product) = self.order_ids.product(replace.order_id) {
+                    self.shard(product)
+                        .handle_event(ShardEvent::Replace(replace));
+                }
+            }
+            Entry::Phase(phase) => {
+                for shard in self.shards.values_mut() {
+                    shard.handle_event(ShardEvent::Phase(phase, oneshot::channel().0));
+                }
+            }
+            Entry::Halt(product) => {
+                self.shard(product)
+                    .handle_event(ShardEvent::Halt(oneshot::channel().0));
+            }
+            Entry::Resume(product) => {
+                self.shard(product)
+                    .handle_event(ShardEvent::Resume(oneshot::channel().0));
+            }
+            Entry::AuctionOnly(product) => {
+                self.shard(product)
+                    .handle_event(ShardEvent::AuctionOnly(true, oneshot::channel().0));
+            }
+            Entry::Open(product) => {
+                self.shard(product)
+                    .handle_event(ShardEvent::AuctionOnly(false, oneshot::channel().0));
+            }
+            Entry::CancelUserOrders(user_id) => {
+                for shard in self.shards.values_mut() {
+                    shard.handle_event(ShardEvent::Cancel(Some(user_id), oneshot::channel().0));
+                }
+            }
+            Entry::CancelProductOrders(product) => {
+                self.shard(product)
+                    .handle_event(ShardEvent::Cancel(None, oneshot::channel().0));
+            }
+            Entry::ClearBatch(product) => {
+                self.shard(product)
+                    .handle_event(ShardEvent::ClearBatch(oneshot::channel().0));
+            }
+            Entry::Expire(product, order_id) => {
+                self.shard(product)
+                    .handle_event(ShardEvent::Expire(order_id));
+            }
+            // The executions result from the records above.
+            Entry::Execution(_) => {}
+        }
+    }
+
+    /// The state of every shard, in the order of the products.
+    pub fn query(&mut self) -> Vec<ShardState> {
+        self.shards
+            .values_mut()
+            .map(|shard| {
+                let (reply, mut state) = oneshot::channel();
+                shard.handle_event(ShardEvent::Query(reply));
+                state.try_recv().expect("Shards always answer")
+            })
+            .collect()
+    }
+
+    fn shard(&mut self, product: Product) -> &mut Shard {
+        self.shards
+            .get_mut(&product)
+            .expect("Every product has its shard")
+    }
+}
+
+/// The shards of the empty books, as the server starts with.
+fn shards(config: &Config, dispatcher: &UnboundedSender<Dispatch>) -> BTreeMap<Product, Shard> {
+    // The halts are recorded in the journal, along with
+    // the resulting resumes.
+    let (events, _) = unbounded_channel();
+    Ledger::new(config)
+        .into_products()
+        .map(|ledger| {
+            let product = ledger.product();
+            (
+                product,
+                Shard::new(
+                    ledger,
+                    dispatcher.clone(),
+                    events.clone(),
+                    config.fees.get(&product).cloned(),
+                ),
+            )
+        })
+        .collect()
+}
+
+/// Writes the messages the shards have sent to the users.
+fn write_messages(
+    messages: &mut UnboundedReceiver<Dispatch>,
+    output: &mut impl Write,
+) -> std::io::Result<()> {
+    while let Ok(dispatch) = messages.try_recv() {
+        match dispatch {
+            Dispatch::Send(user_id, message) => writeln!(output, "{} {}", user_id, message)?,
+            Dispatch::Broadcast(message) => writeln!(output, "* {}", message)?,
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replay_is_deterministic() {
+        let journal = "\
+            1000 - PHASE:AUCTION\n\
+            1001 1 BUY:APPLE:10@101\n\
+            1002 2 SELL:APPLE:5@100\n\
+            1003 - PHASE:CONTINUOUS\n\
+            1004 2 SELL:APPLE:2\n\
+            1005 3 BUY:PEAR\n\
+            1006 3 SELL:PEAR\n\
+            1007 2 CANCEL:APPLE:1\n\
+            1007 - CANCEL_ALL:USER:1\n\
+            1008 - HALT:ONION\n\
+            1009 3 BUY:ONION\n";
+        let replay_journal = || {
+            let mut output = vec![];
+            replay(&Config::default(), journal.as_bytes(), &mut output).unwrap();
+            String::from_utf8(output).unwrap()
+        };
+        let output = replay_journal();
+        let apple: Vec<&str> = output
+            .lines()
+            .filter(|line| line.contains("APPLE") || line.contains("FILL"))
+            .collect();
+        assert_eq!(
+            apple,
+            [
+                "* PHASE:APPLE:AUCTION",
+                "1 ACK:APPLE:1",
+                "* INDICATIVE:APPLE:0",
+                "2 ACK:APPLE:2",
+                "* INDICATIVE:APPLE:5@100",
+                "1 FILL:1:5@100",
+                "2 FILL:2:5@100",
+                "* TRADE:APPLE:5@100",
+                "* PHASE:APPLE:CONTINUOUS",
+                "2 ACK:APPLE:3",
+                "1 FILL:1:2@101",
+                "2 FILL:3:2@101",
+                "* TRADE:APPLE:2@101",
+                "2 ERROR:APPLE unknown order 1",
+                "1 CANCELLED:APPLE:1",
+            ]
+        );
+        assert!(output.contains("3 ACK:PEAR\n3 ACK:PEAR\n* TRADE:PEAR\n"));
+        assert!(output.ends_with("* PHASE:ONION:HALTED\n3 ERROR:ONION trading halted\n"));
+        assert_eq!(output, replay_journal());
+    }
+
+    #[test]
+    fn test_replay_of_several_runs() {
+        let journal = "\
+            1000 - START\n\
+            1001 1 SELL:APPLE:5@100\n\
+            1002 2 BUY:APPLE:2@100\n\
+            2000 - START\n\
+            2001 3 BUY:APPLE:5@100\n\
+            2002 3 CANCEL:APPLE:1\n";
+        let mut output = vec![];
+        replay(&Config::default(), journal.as_bytes(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        // The second run starts with the empty book and numbers
+        // the orders from 1 again.
+        assert!(output.ends_with("* TRADE:APPLE:2@100\n3 ACK:APPLE:1\n3 CANCELLED:APPLE:1\n"));
+
+        let mut replay = Replay::new(&Config::default());
+        for record in records(journal.as_bytes()) {
+            replay.apply(record.unwrap().entry);
+        }
+        let states = replay.query();
+        assert!(states.iter().all(|state| state.snapshot.bids.is_empty()));
+        assert!(states.iter().all(|state| state.snapshot.asks.is_empty()));
+    }
+}
@@ -0,0 +1,240 @@
+/// Author: Tomasz Kulik
+/// 
+///
+
+use crate::config::Config;
+use crate::dispatcher::Dispatch;
+use crate::journal::Entry;
+use crate::order::{Order, Price, Quantity, Side, Timestamp};
+use crate::replay::{records, Replay};
+use crate::shard::UserStats;
+use crate::transaction::{Party, Product, Transaction};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufWriter, Write};
+use std::path::Path;
+
+/// Writes the end-of-day reports from the day's journal
+///
+/// The journal is replayed like by `replay`, and the CSV files
+/// with the header line are written to the directory. Only the last
+/// run of the server is reported, i.e. the records following the
+/// last `START`, the journal is appended to by every run.
+/// - `trades.csv` - every user's trades, in the order of the users
+///   and then the journal
+/// - `products.csv` - the volume and the VWAP of every product, the
+///   VWAP is left empty if no trade had the price
+/// - `open_orders.csv` - the orders carried over to the next day,
+///   including the stop orders waiting for their trigger
+/// - `rejects.csv` - the orders and the rejected ones of every user
+pub fn report(config: &Config, input: impl BufRead, dir: &Path) -> anyhow::Result<()> {
+    let mut replay = Replay::new(config);
+    let mut trades = vec![];
+    for record in records(input) {
+        let record = record?;
+        if record.entry == Entry::Start {
+            trades.clear();
+        }
+        replay.apply(record.entry);
+        while let Ok(dispatch) = replay.messages.try_recv() {
+            if let Dispatch::Execution(transaction) = dispatch {
+                trades.push((record.timestamp, transaction));
+            }
+        }
+    }
+    let states = replay.query();
+
+    std::fs::create_dir_all(dir)?;
+    write_trades(&trades, create(dir, "trades.csv")?)?;
+    write_products(&trades, create(dir, "products.csv")?)?;
+
+    let mut output = create(dir, "open_orders.csv")?;
+    writeln!(
+        output,
+        "product,order_id,user_id,side,quantity,price,stop,timestamp"
+    )?;
+    for state in states.iter() {
+        let snapshot = &state.snapshot;
+        let orders = snapshot
+            .bids
+            .iter()
+            .chain(snapshot.asks.iter())
+            .chain(snapshot.stops.iter());
+        for order in orders {
+            let Order {
+                id,
+                timestamp,
+                user_id,
+                side,
+                product,
+                quantity,
+                limit,
+                stop,
+                ..
+            } = order;
+            writeln!(
+                output,
+                "{},{},{},{},{},{},{},{}",
+                product,
+                id,
+                user_id,
+                side,
+                quantity,
+                optional(*limit),
+                optional(*stop),
+                timestamp
+            )?;
+        }
+    }
+    output.flush()?;
+
+    let mut users: BTreeMap<_, UserStats> = BTreeMap::new();
+    for state in states {
+        for (user_id, stats) in state.stats {
+            *users.entry(user_id).or_default() += stats;
+        }
+    }
+    let mut output = create(dir, "rejects.csv")?;
+    writeln!(output, "user_id,orders,rejected")?;
+    for (user_id, stats) in users {
+        writeln!(output, "{},{},{}", user_id, stats.orders, stats.rejected)?;
+    }
+    output.flush()?;
+    Ok(())
+}
+
+fn create(dir: &Path, name: &str) -> std::io::Result<BufWriter<File>> {
+    File::create(dir.join(name)).map(BufWriter::new)
+}
+
+fn optional(price: Option<Price>) -> String {
+    price.map(|price| price.to_string()).unwrap_or_default()
+}
+
+fn write_trades(
+    trades: &[(Timestamp, Transaction)],
+    mut output: impl Write,
+) -> std::io::Result<()> {
+    let mut rows: Vec<(Timestamp, Side, &Party, &Transaction)> = trades
+        .iter()
+        .flat_map(|(timestamp, transaction)| {
+            vec![
+                (*timestamp, Side::Buy, &transaction.buy, transaction),
+                (*timestamp, Side::Sell, &transaction.sell, transaction),
+            ]
+        })
+        .collect();
+    // The sort is stable, so the journal order is kept per user.
+    rows.sort_by_key(|(_, _, party, _)| party.user_id);
+    writeln!(
+        output,
+        "user_id,timestamp,product,side,order_id,quantity,price"
+    )?;
+    for (timestamp, side, party, transaction) in rows {
+        writeln!(
+            output,
+            "{},{},{},{},{},{},{}",
+            party.user_id,
+            timestamp,
+            transaction.product,
+            side,
+            party.order_id,
+            transaction.quantity,
+            optional(transaction.price)
+        )?;
+    }
+    output.flush()
+}
+
+fn write_products(
+    trades: &[(Timestamp, Transaction)],
+    mut output: impl Write,
+) -> std::io::Result<()> {
+    writeln!(output, "product,trades,volume,vwap")?;
+    for product in Product::ALL.iter() {
+        let (mut count, mut volume) = (0, 0);
+        // The quantity and the value of the trades with the price.
+        let (mut priced, mut value): (Quantity, u128) = (0, 0);
+        for (_, transaction) in trades.iter().filter(|(_, t)| t.product == *product) {
+            count += 1;
+            volume += transaction.quantity;
+            if let Some(price) = transaction.price {
+                priced += transaction.quantity;
+                value += transaction.quantity as u128 * price as u128;
+            }
+        }
+        let vwap = match priced {
+            0 => String::new(),
+            priced => format!("{:.2}", value as f64 / priced as f64),
+        };
+        writeln!(output, "{},{},{},{}", product, count, volume, vwap)?;
+    }
+    output.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_end_of_day_reports() {
+        // The previous day's run is left out.
+        let journal = "\
+            1 - START\n\
+            2 1 SELL:APPLE:10@90\n\
+            3 2 BUY:APPLE:5@90\n\
+            4 2 BUY:PEAR:5@90\n\
+            999 - START\n\
+            1000 1 SELL:APPLE:10@100\n\
+            1001 2 BUY:APPLE:4@100\n\
+            1002 3 BUY:APPLE:3@101\n\
+            1003 1 SELL:APPLE:5@102\n\
+            1004 3 BUY:APPLE:8@102\n\
+            1005 2 BUY:PEAR:1@10:GTD:500\n\
+            1006 2 BUY:PEAR:2:STOP:50\n\
+            1007 4 BUY:PEAR\n\
+            1008 4 SELL:PEAR\n\
+            1009 3 REPLACE:7:1\n";
+        let dir = std::env::temp_dir().join(format!("trading-report-{}", std::process::id()));
+        report(&Config::default(), journal.as_bytes(), &dir).unwrap();
+        let read = |name| std::fs::read_to_string(dir.join(name)).unwrap();
+
+        assert_eq!(
+            read("trades.csv"),
+            "user_id,timestamp,product,side,order_id,quantity,price\n\
+             1,1001,APPLE,SELL,1,4,100\n\
+             1,1002,APPLE,SELL,1,3,100\n\
+             1,1004,APPLE,SELL,1,3,100\n\
+             1,1004,APPLE,SELL,4,5,102\n\
+             2,1001,APPLE,BUY,2,4,100\n\
+             3,1002,APPLE,BUY,3,3,100\n\
+             3,1004,APPLE,BUY,5,3,100\n\
+             3,1004,APPLE,BUY,5,5,102\n\
+             4,1008,PEAR,BUY,8,1,\n\
+             4,1008,PEAR,SELL,9,1,\n"
+        );
+        assert_eq!(
+            read("products.csv"),
+            "product,trades,volume,vwap\n\
+             APPLE,4,15,100.67\n\
+             PEAR,1,1,\n\
+             TOMATO,0,0,\n\
+             POTATO,0,0,\n\
+             ONION,0,0,\n"
+        );
+        assert_eq!(
+            read("open_orders.csv"),
+            "product,order_id,user_id,side,quantity,price,stop,timestamp\n\
+             PEAR,7,2,BUY,2,,50,1006\n"
+        );
+        assert_eq!(
+            read("rejects.csv"),
+            "user_id,orders,rejected\n\
+             1,2,0\n\
+             2,3,1\n\
+             3,3,1\n\
+             4,2,0\n"
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
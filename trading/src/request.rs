@@ -0,0 +1,139 @@
+/// Author: Tomasz Kulik
+/// 
+///
+
+use crate::message::{size, SequenceNumber, SessionId};
+use crate::order::{parse_size, Order, OrderId, Price, Quantity, Timestamp, UserId};
+use crate::transaction::Product;
+
+/// A single line sent by the user.
+///
+/// The accepted requests are:
+/// - `<SIDE>:<PRODUCT>[:<QUANTITY>[@<PRICE>]]` - a new order,
+///   see `Order::new_order_form_str`
+/// - `CANCEL:<PRODUCT>:<ORDER_ID>` - cancel the user's resting order
+/// - `REPLACE:<ORDER_ID>:<QUANTITY>[@<PRICE>]` - amend the user's
+///   resting order, see `Replace`
+/// - `BOOK:<PRODUCT>` - ask for the depth of the product's book,
+///   see `ProductLedger::depth`
+/// - `SESSION` - start the session, every message is sent with
+///   its sequence number from now on
+/// - `RESUME:<SESSION>:<LAST_SEQ>` - continue the session after
+///   reconnecting, the messages after `LAST_SEQ` are sent again
+/// - `HEARTBEAT` - keep the idle connection open
+/// - `CANCEL_ON_DISCONNECT:<ON|OFF>` - whether the user's orders
+///   are cancelled once its connection is lost
+/// - `STATEMENT` - ask for the user's gross, fees and net so far,
+///   see `Statement`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    Order(Order),
+    Cancel(Cancel),
+    Replace(Replace),
+    Book(Product),
+    Session,
+    Resume {
+        session: SessionId,
+        last_seq: SequenceNumber,
+    },
+    Heartbeat,
+    CancelOnDisconnect(bool),
+    Statement,
+}
+
+/// The user's request to remove its order from the book.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancel {
+    pub user_id: UserId,
+    pub product: Product,
+    pub order_id: OrderId,
+}
+
+/// The user's request to amend its resting order.
+///
+/// The quantity is the new open quantity of the order. The order
+/// keeps its time priority only if its quantity is decreased,
+/// otherwise it's sent to the back of the queue, see
+/// `ProductLedger::replace`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Replace {
+    pub user_id: UserId,
+    pub order_id: OrderId,
+    pub quantity: Quantity,
+    /// The new limit, the order keeps its own if none.
+    pub limit: Option<Price>,
+    /// The time the request was accepted by the server.
+    pub timestamp: Timestamp,
+}
+
+impl Request {
+    /// Parse the line sent by the user.
+    pub fn new_from_str(user_id: UserId, input: &str) -> Result<Request, String> {
+        match input {
+            "SESSION" => return Ok(Request::Session),
+            "HEARTBEAT" => return Ok(Request::Heartbeat),
+            "STATEMENT" => return Ok(Request::Statement),
+            "CANCEL_ON_DISCONNECT:ON" => return Ok(Request::CancelOnDisconnect(true)),
+            "CANCEL_ON_DISCONNECT:OFF" => return Ok(Request::CancelOnDisconnect(false)),
+            _ => {}
+        }
+        if let Some(resume) = input.strip_prefix("RESUME:") {
+            let invalid = || format!("Unknown resume: {}", input);
+            let (session, last_seq) = resume.split_once(':').ok_or_else(invalid)?;
+            return Ok(Request::Resume {
+                session: session.parse().map_err(|_| invalid())?,
+                last_seq: last_seq.parse().map_err(|_| invalid())?,
+            });
+        }
+        if let Some(product) = input.strip_prefix("BOOK:") {
+            return Ok(Request::Book(product.parse()?));
+        }
+        if let Some(replace) = input.strip_prefix("REPLACE:") {
+            let (order_id, size) = replace
+                .split_once(':')
+                .ok_or_else(|| format!("Unknown replace: {}", input))?;
+            let (quantity, limit) = parse_size(size)?;
+            return Ok(Request::Replace(Replace {
+                user_id,
+                order_id: order_id
+                    .parse()
+                    .map_err(|_| format!("Invalid order ID: {}", order_id))?,
+                quantity,
+                limit,
+                timestamp: 0,
+            }));
+        }
+        match input.strip_prefix("CANCEL:") {
+            Some(cancel) => {
+                let (product, order_id) = cancel
+                    .split_once(':')
+                    .ok_or_else(|| format!("Unknown cancel: {}", input))?;
+                Ok(Request::Cancel(Cancel {
+                    user_id,
+                    product: product.parse()?,
+                    order_id: order_id
+                        .parse()
+                        .map_err(|_| format!("Invalid order ID: {}", order_id))?,
+                }))
+            }
+            None => Ok(Request::Order(Order::new_order_form_str(user_id, input)?)),
+        }
+    }
+}
+
+impl std::fmt::Display for Cancel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "CANCEL:{}:{}", self.product, self.order_id)
+    }
+}
+
+impl std::fmt::Display for Replace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "REPLACE:{}:{}",
+            self.order_id,
+            size(self.quantity, self.limit)
+        )
+    }
+}
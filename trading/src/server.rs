@@ -2,198 +2,766 @@
 /// 
 /// 
 
-use crate::ledger::Ledger;
-use crate::order::{Order, UserId};
-use crate::transaction::{Product, Transaction};
-use std::collections::HashMap;
-use tokio::io::{AsyncReadExt, AsyncWriteExt, WriteHalf};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::mpsc::{channel, Receiver, Sender};
+use crate::admin::{AdminCommand, AdminReply, Snapshot};
+use crate::config::{Config, HeartbeatConfig, ListenerConfig, ListenerProtocol, ScheduledPhase};
+use crate::dispatcher::{Dispatch, User};
+use crate::fees::Statement;
+use crate::journal::{Entry, Journal};
+use crate::ledger::{Ledger, Phase};
+use crate::listener::{ConnectedUser, Listener, Peer, UserIds};
+use crate::message::Message;
+use crate::order::{Order, OrderId, OrderIds, Timestamp, UserId, MAX_EXPIRY_HORIZON};
+use crate::protocol::Protocol;
+use crate::request::{Cancel, Replace, Request};
+use crate::shard::{Shard, ShardEvent, ShardState, UserStats};
+use crate::transaction::Product;
+use futures::StreamExt;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite, BufReader};
+use tokio::sync::mpsc::{
+    channel, unbounded_channel, Receiver, Sender, UnboundedReceiver, UnboundedSender,
+};
+use tokio::sync::{oneshot, watch};
+use tokio::time::Instant;
+use tokio_util::time::{delay_queue, DelayQueue};
+use tracing::{error, info, info_span, warn, Instrument};
 
 /// This structure represents a single event that may occure
-/// in the system. There are two possible event types:
+/// in the system. There are ten possible event types:
 /// - Order - created by the user
-/// - UserLogin - created once a new user have logged in
+/// - Cancel - created by the user
+/// - Replace - created by the user
+/// - Book - created by the user asking for the depth
+/// - Connection - created by the user's connection, e.g. once a new
+///   user have logged in, passed to the dispatcher
+/// - Phase - created by the scheduler once the trading phase changes
+/// - Timer - created by the event handler itself, once a timer expires
+/// - Admin - created by the operator, answered through the attached channel
+/// - CircuitBreaker - created by the shard once its product is halted
+/// - Disconnected - created by the dispatcher once the user with
+///   cancel-on-disconnect has lost its connection
 #[derive(Debug)]
-enum Event {
+pub(crate) enum Event {
     Order(Order),
-    UserLogin(UserId, WriteHalf<TcpStream>),
+    Cancel(Cancel),
+    Replace(Replace),
+    Book(UserId, Product),
+    Statement(UserId),
+    Connection(Dispatch),
+    Phase(Phase),
+    Timer(Timer),
+    Admin(AdminCommand, oneshot::Sender<Result<AdminReply, String>>),
+    CircuitBreaker(Product),
+    Disconnected(UserId),
 }
 
-/// The server handles the incoming connections and notifies
-/// every user about the transaction that took place in
-/// the market.
+/// An action the event handler postpones.
+#[derive(Debug)]
+pub(crate) enum Timer {
+    /// Resume the product halted by the circuit breaker.
+    Resume(Product),
+    /// Clear the batch auction of the product.
+    Batch(Product),
+    /// Remove the good-till-date order from the book.
+    Expire(Product, OrderId),
+}
+
+/// The server handles the incoming connections and sequences
+/// every event before it's passed to the shard of its product.
+///
+/// Each product is matched by its own shard and the users are
+/// notified by the dispatcher, so the independent products are
+/// matched in parallel on the multi-threaded runtime.
 pub struct Server {
-    users: HashMap<UserId, WriteHalf<TcpStream>>,
+    order_ids: OrderIds,
+    timers: DelayQueue<Timer>,
+    /// The timers resuming the products halted by the circuit breaker.
+    resume_timers: HashMap<Product, delay_queue::Key>,
+    /// How long a product stays halted by the circuit breaker.
+    halt_duration: Option<Duration>,
+    /// The intervals of the products traded in the batch auctions.
+    batch_intervals: BTreeMap<Product, Duration>,
+    /// The products which might have something to clear, i.e.
+    /// anything has changed since their last clearing.
+    pending_batches: HashSet<Product>,
+    shards: BTreeMap<Product, Sender<ShardEvent>>,
+    dispatcher: UnboundedSender<Dispatch>,
+    journal: Option<Journal>,
 }
 
 impl Server {
-    /// Create an async server.
+    /// Generates the server's future.
     ///
-    pub fn new() -> Server {
-        Server {
-            users: HashMap::new(),
+    pub async fn start(ledger: Ledger, config: &Config) -> anyhow::Result<()> {
+        // Bind to the given interface and the other listeners.
+        let mut listeners = vec![ListenerConfig {
+            listen: config.interface.clone(),
+            protocol: ListenerProtocol::Text,
+            tls: config.tls.clone(),
+        }];
+        listeners.extend(config.listeners.iter().cloned());
+        let listeners =
+            futures::future::try_join_all(listeners.into_iter().map(Listener::bind)).await?;
+
+        // The drop-copy feed carries on with the executions
+        // of the previous runs.
+        let (journal, last_execution) = match &config.journal {
+            Some(path) => (
+                Some(Journal::open(path)?),
+                crate::journal::last_execution(path)?,
+            ),
+            None => (None, 0),
+        };
+
+        // The certificates' IDs are never given to the other users.
+        let certified = config
+            .tls
+            .iter()
+            .chain(config.listeners.iter().filter_map(|l| l.tls.as_ref()))
+            .flat_map(|tls| tls.users.iter().map(|user| user.user_id))
+            .collect();
+        let ids = Arc::new(UserIds::new(certified));
+
+        // Every product's ledger is moved to its own shard.
+        let (internal_sender, internal_receiver) = unbounded_channel();
+        let (dispatcher, dispatcher_receiver) = unbounded_channel();
+        tokio::spawn(crate::dispatcher::dispatcher(
+            dispatcher_receiver,
+            ids.clone(),
+            config.session.clone(),
+            config
+                .heartbeat
+                .as_ref()
+                .is_some_and(|heartbeat| heartbeat.cancel_on_disconnect),
+            internal_sender.clone(),
+            journal.clone(),
+            last_execution,
+        ));
+        let shards = ledger
+            .into_products()
+            .map(|ledger| {
+                let product = ledger.product();
+                let shard = Shard::new(
+                    ledger,
+                    dispatcher.clone(),
+                    internal_sender.clone(),
+                    config.fees.get(&product).cloned(),
+                )
+                .spawn();
+                (product, shard)
+            })
+            .collect();
+        let mut server = Server {
+            order_ids: OrderIds::default(),
+            timers: DelayQueue::new(),
+            resume_timers: HashMap::new(),
+            halt_duration: config.circuit_breaker.as_ref().map(|cb| cb.halt),
+            batch_intervals: config
+                .batch_auctions
+                .iter()
+                .map(|(product, batch)| (*product, batch.interval))
+                .collect(),
+            pending_batches: HashSet::new(),
+            shards,
+            dispatcher,
+            journal,
+        };
+
+        for (product, interval) in server.batch_intervals.iter() {
+            server.timers.insert(Timer::Batch(*product), *interval);
         }
-    }
 
-    /// Generates the server's future.
-    ///
-    pub async fn start(&mut self, ledger: &mut Ledger, interface: String) -> anyhow::Result<()> {
-        // Bind to the given interface
-        let listener = TcpListener::bind(interface.clone()).await?;
-        println!("Listening on: {}", interface);
+        // The first phase of the schedule applies to the very first order.
+        if let Some(first) = config.schedule.first() {
+            server.handle_phase(first.phase).await;
+        }
 
         // Create a channel to establish a communication between the user handler
         // and the event handler.
         let (event_notification_sender, event_notification_receiver) = channel::<Event>(10000); // Maybe channel buffer size 1 is sufficient?
 
+        let listeners = listeners.into_iter().map(|listener| {
+            listener.run(
+                ids.clone(),
+                config.heartbeat.clone(),
+                event_notification_sender.clone(),
+            )
+        });
         futures::try_join!(
-            Server::user_handler(listener, event_notification_sender),
-            self.event_handler(event_notification_receiver, ledger)
+            futures::future::try_join_all(listeners),
+            crate::admin::admin_handler(config.admin.clone(), event_notification_sender.clone()),
+            crate::drop_copy::drop_copy_handler(
+                config.drop_copy.clone(),
+                config.journal.clone(),
+                event_notification_sender.clone()
+            ),
+            Server::scheduler(config.schedule.clone(), event_notification_sender),
+            server.event_handler(event_notification_receiver, internal_receiver)
         )?;
         Ok(())
     }
 
-    /// Handles the user's input
+    /// Logs in the user connected through the given stream and
+    /// spawns the task reading its requests.
+    pub(crate) async fn connect<S>(
+        stream: S,
+        peer: Peer,
+        user: ConnectedUser,
+        protocol: Protocol,
+        heartbeat: Option<&HeartbeatConfig>,
+        event_notification_sender: &Sender<Event>,
+    ) -> anyhow::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let user_id = user.user_id;
+        let span = info_span!("connection", user_id, %peer);
+        span.in_scope(|| info!("user connected"));
+
+        // Split the stream into the reader and the writer.
+        // Reader is sent to the new async task and will be
+        // used to receive the orders from the user.
+        // Writer is used by the dispatcher to inform
+        // the user about the transactions and sending ACK msgs.
+        let (reader, writer) = tokio::io::split(stream);
+
+        // The dispatcher closes the connection by dropping
+        // the sender, e.g. once the operator disconnects the user.
+        let (connection, disconnected) = oneshot::channel();
+
+        // The text protocol might be switched by the user's greeting.
+        let (protocol, protocol_receiver) = watch::channel(protocol);
+
+        // Store the writer using notification handler.
+        let writer = User::new(
+            writer,
+            peer,
+            connection,
+            heartbeat.map(|heartbeat| heartbeat.interval),
+            protocol_receiver,
+        );
+        event_notification_sender
+            .send(Event::Connection(Dispatch::Login(user_id, writer)))
+            .await?;
+
+        // Handle users' input within the async loop
+        tokio::spawn(
+            Server::read_requests(
+                reader,
+                user,
+                peer,
+                heartbeat.map(|heartbeat| heartbeat.timeout),
+                protocol,
+                disconnected,
+                event_notification_sender.clone(),
+            )
+            .instrument(span),
+        );
+        Ok(())
+    }
+
+    /// Parses the user's input into the requests and sends
+    /// them to the event handler, until the connection is closed
+    /// or the user stays silent for longer than the timeout, if any.
     ///
-    /// The goals of this method are:
-    /// - accepting every incoming connections
-    /// - parsing the user's raw input into the sequence
-    ///   of orders and sending them to the event handler.
-    async fn user_handler(
-        listener: TcpListener,
+    /// The first line may switch the protocol of the connection,
+    /// see `Protocol`. The user's ID is given back once it's done.
+    async fn read_requests<R>(
+        reader: R,
+        user: ConnectedUser,
+        peer: Peer,
+        timeout: Option<Duration>,
+        protocol: watch::Sender<Protocol>,
+        mut disconnected: oneshot::Receiver<()>,
         event_notification_sender: Sender<Event>,
-    ) -> anyhow::Result<()> {
+    ) where
+        R: AsyncRead + Unpin,
+    {
+        let mut user_id = user.user_id;
+        let mut reader = BufReader::new(reader);
+        let mut input = vec![];
+        let mut greeting = *protocol.borrow() == Protocol::Text;
         loop {
-            let (stream, addr) = listener.accept().await?;
-            println!("User connected ({})", addr);
-
-            // This should be a unique for every user.
-            // It's ok to use the port number for this purpose.
-            let user_id = addr.port();
-
-            // Split the stream into the reader and the writer.
-            // Reader is sent to the new async task and will be
-            // used to receive the orders from the user.
-            // Writer is used by the event handler to inform
-            // the user about the transactions and sending ACK msgs.
-            let (mut reader, writer) = tokio::io::split(stream);
-
-            // Store the writer using notification handler.
-            event_notification_sender
-                .send(Event::UserLogin(user_id, writer))
-                .await?;
-
-            // Handle users' input within the async loop
-            let event_notification_sender = event_notification_sender.clone();
-            tokio::spawn(async move {
-                loop {
-                    let mut buf = [0; 1024];
-                    let n = match reader.read(&mut buf).await {
-                        // socket closed
-                        Ok(n) if n == 0 => return,
-                        Ok(n) => n,
-                        Err(e) => {
-                            println!("Error occured: {}", e);
-                            return;
-                        }
+            let current = *protocol.borrow();
+            let read = async {
+                let read = current.read(&mut reader, &mut input);
+                match timeout {
+                    Some(timeout) => tokio::time::timeout(timeout, read).await,
+                    None => Ok(read.await),
+                }
+            };
+            let read = tokio::select! {
+                read = read => match read {
+                    Ok(read) => read,
+                    Err(_) => {
+                        info!("user timed out");
+                        break;
+                    }
+                },
+                _ = &mut disconnected => {
+                    info!("user disconnected by the server");
+                    return;
+                }
+            };
+            match read {
+                Ok(true) => {}
+                // socket closed
+                Ok(false) => {
+                    info!("user disconnected");
+                    break;
+                }
+                Err(e) => {
+                    warn!(error = %e, "connection error");
+                    break;
+                }
+            }
+            if std::mem::take(&mut greeting) {
+                if let Some(chosen) = Protocol::from_greeting(&input) {
+                    info!(protocol = ?chosen, "protocol chosen");
+                    let _ = protocol.send(chosen);
+                    continue;
+                }
+            }
+            let request = match current.decode(user_id, &input) {
+                Ok(request) => request,
+                // The JSON and the binary users are told about
+                // the malformed requests.
+                Err(e) if current != Protocol::Text => {
+                    let error = Dispatch::Send(user_id, Message::Error(e));
+                    if event_notification_sender
+                        .send(Event::Connection(error))
+                        .await
+                        .is_err()
+                    {
+                        return;
+                    }
+                    continue;
+                }
+                Err(e) => {
+                    warn!(error = %e, "unknown user command");
+                    continue;
+                }
+            };
+            let event = match request {
+                Request::Order(order) => Event::Order(order),
+                Request::Cancel(cancel) => Event::Cancel(cancel),
+                Request::Replace(replace) => Event::Replace(replace),
+                Request::Book(product) => Event::Book(user_id, product),
+                Request::Statement => Event::Statement(user_id),
+                Request::Session => Event::Connection(Dispatch::StartSession(user_id)),
+                Request::CancelOnDisconnect(enabled) => {
+                    Event::Connection(Dispatch::CancelOnDisconnect(user_id, enabled))
+                }
+                // Receiving anything keeps the connection open.
+                Request::Heartbeat => continue,
+                Request::Resume { session, last_seq } => {
+                    let (reply, owner) = oneshot::channel();
+                    let resume = Dispatch::Resume {
+                        user_id,
+                        session,
+                        last_seq,
+                        reply,
                     };
-
-                    // Parse the entire user input
-                    // Iterate over all the lines and parse the orders.
-                    let parsed_orders = std::str::from_utf8(&buf[0..n])
-                        .map_err(|e| println!("The user input format is not a valid UTF-8: {}", e))
-                        .map(|input| {
-                            input.lines().filter_map(|input| {
-                                Order::new_order_form_str(user_id, input)
-                                    .map_err(|e| println!("Unknown user command: {}", e))
-                                    .ok()
-                            })
-                        })
-                        .ok();
-
-                    // If there are new orders, send them to the event handler.
-                    if let Some(orders) = parsed_orders {
-                        for order in orders {
-                            event_notification_sender
-                                .send(Event::Order(order))
-                                .await
-                                .expect("There is a problem with the event notification channel");
-                        }
+                    if event_notification_sender
+                        .send(Event::Connection(resume))
+                        .await
+                        .is_err()
+                    {
+                        return;
+                    }
+                    // From now on the connection acts on behalf
+                    // of the session's user.
+                    if let Ok(Ok(owner)) = owner.await {
+                        user_id = owner;
                     }
+                    continue;
                 }
-            });
+            };
+            if event_notification_sender.send(event).await.is_err() {
+                return;
+            }
         }
+        let _ = event_notification_sender
+            .send(Event::Connection(Dispatch::Logout(user_id, peer)))
+            .await;
     }
 
-    /// Receives all events sent by the user handler
+    /// Switches the trading phases according to the schedule
     ///
-    /// This method handles the system events i.e.:
-    /// - updates the ledger accordingly to the new orders
-    /// - notifies users about new transactions
-    /// - sends ACK messages in response to the orders
+    /// The phase changes go through the event handler, so
+    /// they are ordered consistently with the users' orders.
+    async fn scheduler(
+        schedule: Vec<ScheduledPhase>,
+        event_notification_sender: Sender<Event>,
+    ) -> anyhow::Result<()> {
+        let mut phase_end = Instant::now();
+        for (i, scheduled) in schedule.iter().enumerate() {
+            phase_end += match scheduled.duration {
+                Some(duration) => duration,
+                None => return Ok(()),
+            };
+            tokio::time::sleep_until(phase_end).await;
+            let next = schedule.get(i + 1).map_or(Phase::Closed, |next| next.phase);
+            event_notification_sender.send(Event::Phase(next)).await?;
+        }
+        Ok(())
+    }
+
+    /// Receives all events sent by the user handler,
+    /// the scheduler and the shards
+    ///
+    /// This method sequences the system events i.e.:
+    /// - assigns the IDs to the new orders and passes them
+    ///   to the shards of their products
+    /// - switches the trading phases
+    /// - resumes the products halted by the circuit breaker
+    /// - expires the good-till-date orders
+    /// - clears the batch auctions
+    /// - executes the operators' commands
     async fn event_handler(
         &mut self,
         mut event_notification_receiver: Receiver<Event>,
-        ledger: &mut Ledger,
+        mut internal_receiver: UnboundedReceiver<Event>,
     ) -> anyhow::Result<()> {
         loop {
-            let event = event_notification_receiver.recv().await;
+            let event = tokio::select! {
+                event = event_notification_receiver.recv() => event,
+                event = internal_receiver.recv() => event,
+                Some(timer) = self.timers.next() => Some(Event::Timer(timer.into_inner())),
+            };
             match event {
-                Some(Event::Order(order)) => {
-                    println!("{}", order);
-                    let (user_id, product) = match &order {
-                        Order::Buy(user_id, product) => (user_id, product),
-                        Order::Sell(user_id, product) => (user_id, product),
+                Some(Event::Order(mut order)) => {
+                    order.id = self.order_ids.next(order.product);
+                    order.timestamp = timestamp();
+                    self.record(order.timestamp, Entry::Order(order.clone()));
+                    self.pending_batches.insert(order.product);
+                    // The expiry goes through this loop, so it's ordered
+                    // with the other events. The ledger rejects the orders
+                    // expired already or expiring beyond the horizon.
+                    if let Some(delay) = order
+                        .expiry
+                        .and_then(|expiry| expiry.checked_sub(order.timestamp))
+                        .filter(|delay| (1..=MAX_EXPIRY_HORIZON).contains(delay))
+                    {
+                        let delay = Duration::from_millis(delay);
+                        self.timers
+                            .insert(Timer::Expire(order.product, order.id), delay);
+                    }
+                    self.shard(order.product)
+                        .send(ShardEvent::Order(order))
+                        .await?;
+                }
+                Some(Event::Cancel(cancel)) => {
+                    self.record(timestamp(), Entry::Cancel(cancel));
+                    self.shard(cancel.product)
+                        .send(ShardEvent::CancelOrder(cancel))
+                        .await?;
+                }
+                Some(Event::Replace(mut replace)) => {
+                    replace.timestamp = timestamp();
+                    match self.order_ids.product(replace.order_id) {
+                        Some(product) => {
+                            self.record(replace.timestamp, Entry::Replace(replace));
+                            self.pending_batches.insert(product);
+                            self.shard(product)
+                                .send(ShardEvent::Replace(replace))
+                                .await?;
+                        }
+                        None => {
+                            let reason = format!("Unknown order {}", replace.order_id);
+                            self.dispatch(Dispatch::Send(replace.user_id, Message::Error(reason)));
+                        }
+                    }
+                }
+                // Nothing changes, so it's not recorded.
+                Some(Event::Book(user_id, product)) => {
+                    self.shard(product).send(ShardEvent::Book(user_id)).await?;
+                }
+                Some(Event::Statement(user_id)) => {
+                    let mut stats = UserStats::default();
+                    for state in self.query().await {
+                        stats += state.stats.get(&user_id).copied().unwrap_or_default();
+                    }
+                    let statement = Statement {
+                        user_id,
+                        gross: stats.gross,
+                        fees: stats.fees,
                     };
-                    if let Some(writer) = self.users.get_mut(&user_id) {
-                        if let Err(_) = writer
-                            .write_all(format!("ACK:{}\n", product).as_bytes())
-                            .await
-                        {
-                            println!("Removing user with ID: {}", user_id);
-                            self.users.remove(&user_id);
+                    self.dispatch(Dispatch::Send(user_id, Message::Statement(statement)));
+                }
+                Some(Event::Connection(dispatch)) => {
+                    self.dispatch(dispatch);
+                }
+                Some(Event::Phase(phase)) => {
+                    let span = info_span!("phase", %phase);
+                    self.handle_phase(phase).instrument(span).await;
+                }
+                Some(Event::Timer(Timer::Resume(product))) => {
+                    self.resume_timers.remove(&product);
+                    self.record(timestamp(), Entry::Resume(product));
+                    self.pending_batches.insert(product);
+                    // The product might have been resumed by the operator.
+                    let _ = self.request(product, ShardEvent::Resume).await;
+                }
+                Some(Event::Timer(Timer::Batch(product))) => {
+                    self.timers
+                        .insert(Timer::Batch(product), self.batch_intervals[&product]);
+                    // The unchanged book has nothing to clear, so
+                    // the clearing is left out of the journal.
+                    if self.pending_batches.remove(&product) {
+                        self.record(timestamp(), Entry::ClearBatch(product));
+                        if self.request(product, ShardEvent::ClearBatch).await {
+                            // The triggered stop orders might cross.
+                            self.pending_batches.insert(product);
                         }
                     }
-                    let maybe_transaction = ledger.handle_user_order(order);
-                    if let Some(transaction) = maybe_transaction {
-                        let Transaction(product) = &transaction;
-                        println!("trade ({})", product);
-                        self.notify_all_users_about_transaction(transaction).await;
+                }
+                Some(Event::Timer(Timer::Expire(product, order_id))) => {
+                    self.record(timestamp(), Entry::Expire(product, order_id));
+                    self.shard(product)
+                        .send(ShardEvent::Expire(order_id))
+                        .await?;
+                }
+                Some(Event::CircuitBreaker(product)) => {
+                    if let Some(halt_duration) = self.halt_duration {
+                        let key = self.timers.insert(Timer::Resume(product), halt_duration);
+                        self.resume_timers.insert(product, key);
                     }
                 }
-                Some(Event::UserLogin(user_id, writer)) => {
-                    self.users.insert(user_id, writer);
+                Some(Event::Disconnected(user_id)) => {
+                    let span = info_span!("cancel_on_disconnect", user_id);
+                    let cancelled = self.cancel_user_orders(user_id).instrument(span).await;
+                    info!(
+                        user_id,
+                        cancelled = cancelled.len(),
+                        "orders cancelled on disconnect"
+                    );
+                }
+                Some(Event::Admin(command, reply)) => {
+                    let span = info_span!("admin", ?command);
+                    let result = self.handle_admin(command).instrument(span).await;
+                    // The operator might have disconnected in the meantime.
+                    let _ = reply.send(result);
                 }
                 _ => {
-                    println!("There is a problem with the event notification channel!");
+                    error!("There is a problem with the event notification channel!");
                 }
             };
         }
     }
 
-    /// Sends a transaction notification to each stored users
+    /// Cancels the user's orders of every product, returns
+    /// them in the order they were placed.
+    async fn cancel_user_orders(&mut self, user_id: UserId) -> Vec<Order> {
+        self.record(timestamp(), Entry::CancelUserOrders(user_id));
+        let mut cancelled = vec![];
+        for product in Product::ALL.iter() {
+            cancelled.extend(
+                self.request(*product, |reply| ShardEvent::Cancel(Some(user_id), reply))
+                    .await,
+            );
+        }
+        cancelled.sort_by_key(|order| order.id);
+        cancelled
+    }
+
+    /// Switches every product to the new trading phase
     ///
-    /// If the user is not reachable anymore - the method
-    /// removes it from the set.
-    async fn notify_all_users_about_transaction(&mut self, transaction: Transaction) {
-        let mut users_to_remove: Vec<UserId> = vec![];
-        for (user_id, writer) in &mut self.users {
-            if let Err(_) = match transaction {
-                Transaction(Product::Apple) => writer.write_all(b"TRADE:APPLE\n"),
-                Transaction(Product::Pear) => writer.write_all(b"TRADE:PEAR\n"),
-                Transaction(Product::Tomato) => writer.write_all(b"TRADE:TOMATO\n"),
-                Transaction(Product::Potato) => writer.write_all(b"TRADE:POTATO\n"),
-                Transaction(Product::Onion) => writer.write_all(b"TRADE:ONION\n"),
+    /// The shards are switched one after another, so the users
+    /// are notified about the products in a deterministic order.
+    async fn handle_phase(&mut self, phase: Phase) {
+        self.record(timestamp(), Entry::Phase(phase));
+        self.pending_batches.extend(Product::ALL.iter());
+        for product in Product::ALL.iter() {
+            self.request(*product, |done| ShardEvent::Phase(phase, done))
+                .await;
+        }
+    }
+
+    /// Executes the operator's command
+    async fn handle_admin(&mut self, command: AdminCommand) -> Result<AdminReply, String> {
+        match command {
+            AdminCommand::Users => {
+                let (reply, users) = oneshot::channel();
+                self.dispatch(Dispatch::Users(reply));
+                let users = users.await.map_err(|e| e.to_string())?;
+                let states = self.query().await;
+                let lines = users
+                    .into_iter()
+                    .map(|(user_id, peer)| {
+                        let mut stats = UserStats::default();
+                        let mut open = 0;
+                        for state in states.iter() {
+                            stats += state.stats.get(&user_id).copied().unwrap_or_default();
+                            open += state
+                                .snapshot
+                                .bids
+                                .iter()
+                                .chain(state.snapshot.asks.iter())
+                                .filter(|order| order.user_id == user_id)
+                                .count();
+                        }
+                        format!(
+                            "USER:{} addr={} orders={} rejected={} fills={} volume={} open={}",
+                            user_id,
+                            peer,
+                            stats.orders,
+                            stats.rejected,
+                            stats.fills,
+                            stats.volume,
+                            open
+                        )
+                    })
+                    .collect();
+                Ok(AdminReply::Lines(lines))
             }
-            .await
-            {
-                users_to_remove.push(*user_id);
+            AdminCommand::Disconnect(user_id) => {
+                let (reply, connected) = oneshot::channel();
+                self.dispatch(Dispatch::Disconnect(user_id, reply));
+                match connected.await {
+                    Ok(true) => Ok(AdminReply::Lines(vec![])),
+                    _ => Err(format!("Unknown user: {}", user_id)),
+                }
+            }
+            AdminCommand::CancelUserOrders(user_id) => {
+                let cancelled = self.cancel_user_orders(user_id).await;
+                Ok(AdminReply::Lines(cancelled_lines(&cancelled)))
+            }
+            AdminCommand::CancelProductOrders(product) => {
+                self.record(timestamp(), Entry::CancelProductOrders(product));
+                let cancelled = self
+                    .request(product, |reply| ShardEvent::Cancel(None, reply))
+                    .await;
+                Ok(AdminReply::Lines(cancelled_lines(&cancelled)))
+            }
+            AdminCommand::Halt(product) => {
+                self.record(timestamp(), Entry::Halt(product));
+                if let Some(key) = self.resume_timers.remove(&product) {
+                    // Halted by the circuit breaker, now until
+                    // the operator resumes it.
+                    self.timers.remove(&key);
+                    return Ok(AdminReply::Lines(vec![]));
+                }
+                self.request(product, ShardEvent::Halt).await?;
+                Ok(AdminReply::Lines(vec![]))
+            }
+            AdminCommand::Resume(product) => {
+                self.record(timestamp(), Entry::Resume(product));
+                self.pending_batches.insert(product);
+                self.request(product, ShardEvent::Resume).await?;
+                if let Some(key) = self.resume_timers.remove(&product) {
+                    self.timers.remove(&key);
+                }
+                Ok(AdminReply::Lines(vec![]))
+            }
+            AdminCommand::AuctionOnly(product) => {
+                self.record(timestamp(), Entry::AuctionOnly(product));
+                self.request(product, |reply| ShardEvent::AuctionOnly(true, reply))
+                    .await?;
+                Ok(AdminReply::Lines(vec![]))
+            }
+            AdminCommand::Open(product) => {
+                self.record(timestamp(), Entry::Open(product));
+                self.pending_batches.insert(product);
+                self.request(product, |reply| ShardEvent::AuctionOnly(false, reply))
+                    .await?;
+                Ok(AdminReply::Lines(vec![]))
+            }
+            AdminCommand::Snapshot => {
+                let timestamp = timestamp();
+                let products = self
+                    .query()
+                    .await
+                    .into_iter()
+                    .map(|state| state.snapshot)
+                    .collect();
+                Ok(AdminReply::Snapshot(Snapshot {
+                    timestamp,
+                    products,
+                }))
+            }
+            AdminCommand::Statements => {
+                let mut accounts: BTreeMap<UserId, UserStats> = BTreeMap::new();
+                for state in self.query().await {
+                    for (user_id, stats) in state.stats {
+                        if stats.fills > 0 {
+                            *accounts.entry(user_id).or_default() += stats;
+                        }
+                    }
+                }
+                let lines = accounts
+                    .into_iter()
+                    .map(|(user_id, stats)| {
+                        Statement {
+                            user_id,
+                            gross: stats.gross,
+                            fees: stats.fees,
+                        }
+                        .to_string()
+                    })
+                    .collect();
+                Ok(AdminReply::Lines(lines))
             }
         }
-        for user_id in users_to_remove {
-            println!("Removing user with ID: {}", user_id);
-            self.users.remove(&user_id);
+    }
+
+    /// The state of every shard, in the order of the products.
+    async fn query(&self) -> Vec<ShardState> {
+        let mut states = vec![];
+        for product in Product::ALL.iter() {
+            states.push(self.request(*product, ShardEvent::Query).await);
         }
+        states
     }
+
+    /// Sends the event to the shard and waits for its answer.
+    ///
+    /// The shard answers only once all the events sent
+    /// before are handled.
+    async fn request<T>(
+        &self,
+        product: Product,
+        event: impl FnOnce(oneshot::Sender<T>) -> ShardEvent,
+    ) -> T {
+        let (reply, answer) = oneshot::channel();
+        self.shard(product)
+            .send(event(reply))
+            .await
+            .expect("Shards run as long as the server");
+        answer.await.expect("Shards always answer")
+    }
+
+    fn shard(&self, product: Product) -> &Sender<ShardEvent> {
+        &self.shards[&product]
+    }
+
+    /// Appends the sequenced event to the journal, if enabled.
+    fn record(&self, timestamp: Timestamp, entry: Entry) {
+        if let Some(journal) = &self.journal {
+            journal.write(timestamp, entry);
+        }
+    }
+
+    fn dispatch(&self, dispatch: Dispatch) {
+        if self.dispatcher.send(dispatch).is_err() {
+            warn!("The dispatcher is gone");
+        }
+    }
+}
+
+/// Describes every cancelled order for the operator.
+fn cancelled_lines(cancelled: &[Order]) -> Vec<String> {
+    cancelled
+        .iter()
+        .map(|order| format!("CANCELLED:{}:{}", order.product, order.id))
+        .collect()
+}
+
+/// The current time as the number of milliseconds since the UNIX epoch.
+pub(crate) fn timestamp() -> Timestamp {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_millis() as Timestamp)
 }
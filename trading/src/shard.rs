@@ -0,0 +1,492 @@
+/// Author: Tomasz Kulik
+/// 
+///
+
+use crate::config::FeeSchedule;
+use crate::dispatcher::Dispatch;
+use crate::fees::Amount;
+use crate::ledger::{Phase, ProductLedger, ProductSnapshot};
+use crate::message::Message;
+use crate::order::{Order, OrderId, Price, Quantity, Side, UserId};
+use crate::request::{Cancel, Replace};
+use crate::server::Event;
+use crate::transaction::Transaction;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use tokio::sync::mpsc::{channel, Receiver, Sender, UnboundedSender};
+use tokio::sync::oneshot;
+use tracing::{debug, info, info_span, warn};
+
+/// An event handled by the shard of a single product.
+///
+/// The events reach the shard in the order they were
+/// sequenced by the event handler.
+#[derive(Debug)]
+pub(crate) enum ShardEvent {
+    /// A new order with the ID and the timestamp assigned.
+    Order(Order),
+    /// The market phase has changed, answered once the
+    /// users have been notified.
+    Phase(Phase, oneshot::Sender<()>),
+    /// Stop trading in the product.
+    Halt(oneshot::Sender<Result<(), String>>),
+    /// Resume trading in the halted product.
+    Resume(oneshot::Sender<Result<(), String>>),
+    /// Switch the product into or out of the auction-only phase.
+    AuctionOnly(bool, oneshot::Sender<Result<(), String>>),
+    /// Cancel the user's order.
+    CancelOrder(Cancel),
+    /// Amend the user's order, with the timestamp assigned.
+    Replace(Replace),
+    /// Send the depth of the book to the user.
+    Book(UserId),
+    /// Clear the batch auction, answered whether anything traded.
+    ClearBatch(oneshot::Sender<bool>),
+    /// Remove the good-till-date order, its time has come.
+    Expire(OrderId),
+    /// Cancel the orders of the given user, or all of them.
+    Cancel(Option<UserId>, oneshot::Sender<Vec<Order>>),
+    /// Report the state of the shard.
+    Query(oneshot::Sender<ShardState>),
+}
+
+/// The state of a shard at a given point in time.
+#[derive(Debug)]
+pub(crate) struct ShardState {
+    pub snapshot: ProductSnapshot,
+    pub stats: HashMap<UserId, UserStats>,
+}
+
+/// The activity of a user in a single product.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct UserStats {
+    /// The new orders and the replaces.
+    pub orders: u64,
+    /// The rejected ones of the `orders`.
+    pub rejected: u64,
+    pub fills: u64,
+    pub volume: Quantity,
+    /// See `Statement::gross`.
+    pub gross: Amount,
+    pub fees: Amount,
+}
+
+impl std::ops::AddAssign for UserStats {
+    fn add_assign(&mut self, other: UserStats) {
+        self.orders += other.orders;
+        self.rejected += other.rejected;
+        self.fills += other.fills;
+        self.volume += other.volume;
+        self.gross = self.gross.saturating_add(other.gross);
+        self.fees = self.fees.saturating_add(other.fees);
+    }
+}
+
+/// The shard owns the ledger of a single product and
+/// matches its orders independently of other products.
+///
+/// The messages for the users are handed over to the
+/// dispatcher, so the matching never waits for the users.
+pub(crate) struct Shard {
+    ledger: ProductLedger,
+    dispatcher: UnboundedSender<Dispatch>,
+    /// Notifies the event handler about the circuit breaker.
+    events: UnboundedSender<Event>,
+    /// The indicative volume and price most recently sent
+    /// to the users during the auction.
+    indicative: Option<(Quantity, Option<Price>)>,
+    stats: HashMap<UserId, UserStats>,
+    fees: Option<FeeSchedule>,
+}
+
+impl Shard {
+    pub fn new(
+        ledger: ProductLedger,
+        dispatcher: UnboundedSender<Dispatch>,
+        events: UnboundedSender<Event>,
+        fees: Option<FeeSchedule>,
+    ) -> Shard {
+        Shard {
+            ledger,
+            dispatcher,
+            events,
+            indicative: None,
+            stats: HashMap::new(),
+            fees,
+        }
+    }
+
+    /// Start the shard's task and return its event channel.
+    pub fn spawn(self) -> Sender<ShardEvent> {
+        let (sender, receiver) = channel(10000);
+        tokio::spawn(self.run(receiver));
+        sender
+    }
+
+    async fn run(mut self, mut receiver: Receiver<ShardEvent>) {
+        while let Some(event) = receiver.recv().await {
+            self.handle_event(event);
+        }
+    }
+
+    /// Handles a single event, the messages for the users
+    /// are sent to the dispatcher before it returns.
+    pub fn handle_event(&mut self, event: ShardEvent) {
+        let product = self.ledger.product();
+        match event {
+            ShardEvent::Order(order) => {
+                let span = info_span!(
+                    "order",
+                    user_id = order.user_id,
+                    order_id = order.id,
+                    product = %order.product,
+                    side = %order.side
+                );
+                span.in_scope(|| self.handle_order(order));
+            }
+            ShardEvent::Phase(phase, done) => {
+                info_span!("phase", %phase, %product).in_scope(|| self.handle_phase(phase));
+                let _ = done.send(());
+            }
+            ShardEvent::Halt(reply) => {
+                let _ = reply.send(self.handle_halt());
+            }
+            ShardEvent::Resume(reply) => {
+                let result = info_span!("resume", %product).in_scope(|| self.handle_resume());
+                let _ = reply.send(result);
+            }
+            ShardEvent::AuctionOnly(auction_only, reply) => {
+                let result = info_span!("auction_only", %product)
+                    .in_scope(|| self.handle_auction_only(auction_only));
+                let _ = reply.send(result);
+            }
+            ShardEvent::CancelOrder(cancel) => {
+                let span = info_span!(
+                    "cancel",
+                    user_id = cancel.user_id,
+                    order_id = cancel.order_id,
+                    %product
+                );
+                span.in_scope(|| self.handle_cancel(cancel));
+            }
+            ShardEvent::Replace(replace) => {
+                let span = info_span!(
+                    "replace",
+                    user_id = replace.user_id,
+                    order_id = replace.order_id,
+                    %product
+                );
+                span.in_scope(|| self.handle_replace(replace));
+            }
+            ShardEvent::Book(user_id) => {
+                let (bids, asks) = self.ledger.depth();
+                self.send(
+                    user_id,
+                    Message::Book {
+                        product,
+                        bids,
+                        asks,
+                    },
+                );
+            }
+            ShardEvent::ClearBatch(reply) => {
+                let traded = info_span!("batch", %product).in_scope(|| self.handle_clear_batch());
+                let _ = reply.send(traded);
+            }
+            ShardEvent::Expire(order_id) => {
+                info_span!("expire", order_id, %product).in_scope(|| self.handle_expire(order_id));
+            }
+            ShardEvent::Cancel(user_id, reply) => {
+                let cancelled = self
+                    .ledger
+                    .cancel_orders(|order| user_id.is_none_or(|user_id| order.user_id == user_id));
+                self.notify_about_cancelled(&cancelled);
+                let _ = reply.send(cancelled);
+            }
+            ShardEvent::Query(reply) => {
+                let _ = reply.send(ShardState {
+                    snapshot: self.ledger.snapshot(),
+                    stats: self.stats.clone(),
+                });
+            }
+        }
+    }
+
+    /// Applies the order to the ledger and acknowledges it
+    fn handle_order(&mut self, order: Order) {
+        debug!(%order, "order received");
+        let user_id = order.user_id;
+        let ack = Message::Ack {
+            product: order.product,
+            order_id: (!order.short_form).then_some(order.id),
+        };
+        let phase = self.ledger.phase();
+        self.stats.entry(user_id).or_default().orders += 1;
+        match self.ledger.handle_user_order(order) {
+            Ok(transactions) => self.notify_about_accepted(user_id, ack, phase, transactions),
+            Err(reason) => {
+                warn!(%reason, "order rejected");
+                self.stats.entry(user_id).or_default().rejected += 1;
+                self.send(user_id, Message::Error(reason));
+            }
+        }
+    }
+
+    /// Amends the user's order and acknowledges it
+    ///
+    /// Only the owner may amend the order.
+    fn handle_replace(&mut self, replace: Replace) {
+        let user_id = replace.user_id;
+        let ack = Message::Ack {
+            product: self.ledger.product(),
+            order_id: Some(replace.order_id),
+        };
+        let phase = self.ledger.phase();
+        self.stats.entry(user_id).or_default().orders += 1;
+        match self.ledger.replace(replace) {
+            Ok(transactions) => self.notify_about_accepted(user_id, ack, phase, transactions),
+            Err(reason) => {
+                warn!(%reason, "replace rejected");
+                self.stats.entry(user_id).or_default().rejected += 1;
+                self.send(user_id, Message::Error(reason));
+            }
+        }
+    }
+
+    /// Acknowledges the accepted request and notifies the users
+    /// about the resulting transactions, trading halted by the
+    /// circuit breaker included, and about the orders it cancelled.
+    fn notify_about_accepted(
+        &mut self,
+        user_id: UserId,
+        ack: Message,
+        phase: Phase,
+        transactions: Vec<Transaction>,
+    ) {
+        self.send(user_id, ack);
+        for transaction in transactions {
+            self.notify_all_users_about_transaction(transaction);
+        }
+        self.notify_all_users_about_indicative();
+        if phase != Phase::Halted && self.ledger.phase() == Phase::Halted {
+            warn!("circuit breaker tripped, trading halted");
+            let _ = self
+                .events
+                .send(Event::CircuitBreaker(self.ledger.product()));
+            self.notify_all_users_about_phase();
+        }
+        let cancelled = self.ledger.take_cancelled();
+        self.notify_about_cancelled(&cancelled);
+    }
+
+    /// Removes the user's order from the book
+    ///
+    /// Only the owner may cancel the order.
+    fn handle_cancel(&mut self, cancel: Cancel) {
+        let cancelled = self
+            .ledger
+            .cancel_orders(|order| order.id == cancel.order_id && order.user_id == cancel.user_id);
+        if cancelled.is_empty() {
+            let reason = format!("{} unknown order {}", cancel.product, cancel.order_id);
+            warn!(%reason, "cancel rejected");
+            self.send(cancel.user_id, Message::Error(reason));
+        }
+        self.notify_about_cancelled(&cancelled);
+    }
+
+    /// Clears the batch auction and notifies the users about the
+    /// transactions, followed by the clearing volume and price
+    fn handle_clear_batch(&mut self) -> bool {
+        let transactions = self.ledger.clear_batch();
+        let volume = transactions.iter().map(|t| t.quantity).sum();
+        let price = match transactions.first() {
+            Some(transaction) => transaction.price,
+            None => return false,
+        };
+        debug!(volume, ?price, "batch cleared");
+        for transaction in transactions {
+            self.notify_all_users_about_transaction(transaction);
+        }
+        self.broadcast(Message::Cleared {
+            product: self.ledger.product(),
+            volume,
+            price,
+        });
+        self.notify_all_users_about_indicative();
+        true
+    }
+
+    /// Removes the expired order from the book, unless it's
+    /// already filled or cancelled, and informs its owner
+    fn handle_expire(&mut self, order_id: OrderId) {
+        for order in self.ledger.cancel_orders(|order| order.id == order_id) {
+            info!(user_id = order.user_id, "order expired");
+            let message = Message::Expired {
+                product: order.product,
+                order_id: order.id,
+            };
+            self.send(order.user_id, message);
+            self.notify_all_users_about_indicative();
+        }
+    }
+
+    /// Switches the product to the new market phase
+    ///
+    /// Once the auction is over, the users are notified about
+    /// the transactions resulting from uncrossing the ledger.
+    fn handle_phase(&mut self, phase: Phase) {
+        info!("trading phase changed");
+        for transaction in self.ledger.set_market_phase(phase) {
+            self.notify_all_users_about_transaction(transaction);
+        }
+        self.indicative = None;
+        self.notify_all_users_about_phase();
+    }
+
+    fn handle_halt(&mut self) -> Result<(), String> {
+        if self.ledger.phase() == Phase::Halted {
+            return Err(format!("{} is already halted", self.ledger.product()));
+        }
+        info!(product = %self.ledger.product(), "trading halted by the operator");
+        self.ledger.halt();
+        self.notify_all_users_about_phase();
+        Ok(())
+    }
+
+    /// Brings the halted product back to trading
+    fn handle_resume(&mut self) -> Result<(), String> {
+        if self.ledger.phase() != Phase::Halted {
+            return Err(format!("{} is not halted", self.ledger.product()));
+        }
+        info!("trading resumed");
+        for transaction in self.ledger.resume() {
+            self.notify_all_users_about_transaction(transaction);
+        }
+        self.notify_all_users_about_phase();
+        Ok(())
+    }
+
+    /// Switches the product into or out of the auction-only phase
+    ///
+    /// Once the product is opened, the users are notified about
+    /// the transactions resulting from uncrossing the ledger.
+    fn handle_auction_only(&mut self, auction_only: bool) -> Result<(), String> {
+        let product = self.ledger.product();
+        match (self.ledger.auction_only(), auction_only) {
+            (true, true) => return Err(format!("{} is already auction-only", product)),
+            (false, false) => return Err(format!("{} is not auction-only", product)),
+            _ => {}
+        }
+        info!(auction_only, "auction-only phase changed");
+        for transaction in self.ledger.set_auction_only(auction_only) {
+            self.notify_all_users_about_transaction(transaction);
+        }
+        self.indicative = None;
+        self.notify_all_users_about_phase();
+        Ok(())
+    }
+
+    /// Informs the owners about their cancelled orders
+    fn notify_about_cancelled(&mut self, cancelled: &[Order]) {
+        for order in cancelled {
+            info!(
+                order_id = order.id,
+                user_id = order.user_id,
+                "order cancelled"
+            );
+            let message = Message::Cancelled {
+                product: order.product,
+                order_id: (!order.short_form).then_some(order.id),
+            };
+            self.send(order.user_id, message);
+            self.notify_all_users_about_indicative();
+        }
+    }
+
+    /// Sends the trading phase of the product to each user
+    fn notify_all_users_about_phase(&mut self) {
+        self.broadcast(Message::Phase {
+            product: self.ledger.product(),
+            phase: self.ledger.phase(),
+        });
+    }
+
+    /// Sends the private fills to both parties of the transaction,
+    /// along with their fees, the trade notification to each user
+    /// and the execution to the drop-copy users
+    fn notify_all_users_about_transaction(&mut self, transaction: Transaction) {
+        self.dispatch(Dispatch::Execution(transaction.clone()));
+        let Transaction {
+            product,
+            quantity,
+            price,
+            buy,
+            sell,
+            aggressor,
+        } = transaction;
+        info!(%product, quantity, ?price, buy_order_id = buy.order_id, sell_order_id = sell.order_id, "trade");
+        // What the seller receives and the buyer pays.
+        let notional = price.map_or(0, |price| {
+            Amount::try_from(quantity as u128 * price as u128).unwrap_or(Amount::MAX)
+        });
+        for (party, side, amount) in
+            [(buy, Side::Buy, -notional), (sell, Side::Sell, notional)].iter()
+        {
+            let stats = self.stats.entry(party.user_id).or_default();
+            let fee = self
+                .fees
+                .as_ref()
+                .map(|fees| fees.fee(stats.volume, aggressor != Some(*side), quantity));
+            stats.fills += 1;
+            stats.volume += quantity;
+            stats.gross = stats.gross.saturating_add(*amount);
+            stats.fees = stats.fees.saturating_add(fee.unwrap_or(0));
+            if !party.short_form {
+                let fill = Message::Fill {
+                    order_id: party.order_id,
+                    quantity,
+                    price,
+                    fee,
+                };
+                self.send(party.user_id, fill);
+            }
+        }
+        self.broadcast(Message::Trade {
+            product,
+            quantity,
+            price,
+        });
+    }
+
+    /// Sends the indicative auction volume and price to each
+    /// user, if it has changed
+    fn notify_all_users_about_indicative(&mut self) {
+        if self.ledger.phase() != Phase::Auction {
+            return;
+        }
+        let (volume, price) = self.ledger.indicative();
+        if self.indicative.replace((volume, price)) != Some((volume, price)) {
+            let product = self.ledger.product();
+            debug!(%product, volume, ?price, "indicative price changed");
+            self.broadcast(Message::Indicative {
+                product,
+                volume,
+                price,
+            });
+        }
+    }
+
+    fn send(&self, user_id: UserId, message: Message) {
+        self.dispatch(Dispatch::Send(user_id, message));
+    }
+
+    fn broadcast(&self, message: Message) {
+        self.dispatch(Dispatch::Broadcast(message));
+    }
+
+    fn dispatch(&self, dispatch: Dispatch) {
+        if self.dispatcher.send(dispatch).is_err() {
+            warn!("The dispatcher is gone");
+        }
+    }
+}
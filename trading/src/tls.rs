@@ -0,0 +1,103 @@
+/// Author: Tomasz Kulik
+/// 
+///
+
+use crate::config::TlsConfig;
+use crate::order::UserId;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::rustls::crypto::ring::default_provider;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::server::TlsStream;
+
+/// Accepts the TLS connections of a listener.
+///
+/// Once the client certificates are required, every user has
+/// to present one of the certificates listed in the config, and
+/// is identified by the user ID assigned to it, so no token has
+/// to be sent.
+pub(crate) struct TlsAcceptor {
+    acceptor: tokio_rustls::TlsAcceptor,
+    /// The users by their certificates, in the DER form.
+    users: Option<HashMap<Vec<u8>, UserId>>,
+}
+
+impl TlsAcceptor {
+    pub fn new(config: &TlsConfig) -> anyhow::Result<TlsAcceptor> {
+        let provider = Arc::new(default_provider());
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+        let builder = match &config.client_ca {
+            Some(client_ca) => {
+                let mut roots = RootCertStore::empty();
+                for cert in read_certs(client_ca)? {
+                    roots.add(cert)?;
+                }
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                        .build()?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let key = PrivateKeyDer::from_pem_file(&config.key)
+            .map_err(|e| anyhow::anyhow!("Unable to read {}: {}", config.key.display(), e))?;
+        let server_config = builder.with_single_cert(read_certs(&config.cert)?, key)?;
+
+        let users = match config.client_ca {
+            Some(_) => {
+                let mut users = HashMap::new();
+                for user in config.users.iter() {
+                    for cert in read_certs(&user.cert)? {
+                        users.insert(cert.to_vec(), user.user_id);
+                    }
+                }
+                Some(users)
+            }
+            None => None,
+        };
+        Ok(TlsAcceptor {
+            acceptor: tokio_rustls::TlsAcceptor::from(Arc::new(server_config)),
+            users,
+        })
+    }
+
+    /// Complete the handshake, returns the stream along with the
+    /// user identified by the client certificate, if required.
+    pub async fn accept<S>(&self, stream: S) -> anyhow::Result<(TlsStream<S>, Option<UserId>)>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let stream = self.acceptor.accept(stream).await?;
+        let users = match &self.users {
+            Some(users) => users,
+            None => return Ok((stream, None)),
+        };
+        // The certificate was already verified by the handshake.
+        let user_id = stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .and_then(|cert| users.get(cert.as_ref()));
+        match user_id {
+            Some(user_id) => Ok((stream, Some(*user_id))),
+            None => anyhow::bail!("Unknown client certificate"),
+        }
+    }
+}
+
+fn read_certs(path: &Path) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| anyhow::anyhow!("Unable to read {}: {}", path.display(), e))?;
+    if certs.is_empty() {
+        anyhow::bail!("No certificate in {}", path.display());
+    }
+    Ok(certs)
+}
@@ -2,9 +2,13 @@
 /// 
 ///
 
+use crate::order::{Order, OrderId, Price, Quantity, Side, UserId};
+use serde::{Deserialize, Serialize};
+
 /// The kind of a product that any user can buy or sell in
 /// the market.
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
 pub enum Product {
     Apple,
     Pear,
@@ -13,6 +17,17 @@ pub enum Product {
     Onion,
 }
 
+impl Product {
+    /// All the products traded in the market.
+    pub const ALL: [Product; 5] = [
+        Product::Apple,
+        Product::Pear,
+        Product::Tomato,
+        Product::Potato,
+        Product::Onion,
+    ];
+}
+
 impl std::fmt::Display for Product {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -25,9 +40,72 @@ impl std::fmt::Display for Product {
     }
 }
 
+impl std::str::FromStr for Product {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Product, String> {
+        match input {
+            "APPLE" => Ok(Product::Apple),
+            "PEAR" => Ok(Product::Pear),
+            "TOMATO" => Ok(Product::Tomato),
+            "POTATO" => Ok(Product::Potato),
+            "ONION" => Ok(Product::Onion),
+            _ => Err(format!("Unknown product: {}", input)),
+        }
+    }
+}
+
+/// One of the two orders taking part in a transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Party {
+    pub user_id: UserId,
+    pub order_id: OrderId,
+    /// See `Order::short_form`.
+    pub short_form: bool,
+}
+
+impl From<&Order> for Party {
+    fn from(order: &Order) -> Party {
+        Party {
+            user_id: order.user_id,
+            order_id: order.id,
+            short_form: order.short_form,
+        }
+    }
+}
+
 /// The structure representing a single transaction
-/// between two users in the system. Since the system
-/// does not need to indicate the pair of users taking
-/// part in the transaction, it is sufficient to
-/// keep only the information about the product.
-pub struct Transaction(pub Product);
+/// between two users in the system.
+///
+/// The price is unknown only if two orders without
+/// the limit price were matched.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transaction {
+    pub product: Product,
+    pub quantity: Quantity,
+    pub price: Option<Price>,
+    pub buy: Party,
+    pub sell: Party,
+    /// The side of the incoming order, which has matched the
+    /// resting one. Unknown for the auction's transactions.
+    pub aggressor: Option<Side>,
+}
+
+impl Transaction {
+    /// Create a transaction between the incoming order
+    /// and the matching resting one.
+    pub fn new(a: &Order, b: &Order, quantity: Quantity, price: Option<Price>) -> Transaction {
+        let (buy, sell) = match a.side {
+            Side::Buy => (a, b),
+            Side::Sell => (b, a),
+        };
+        Transaction {
+            product: a.product,
+            quantity,
+            price,
+            buy: buy.into(),
+            sell: sell.into(),
+            aggressor: Some(a.side),
+        }
+    }
+}
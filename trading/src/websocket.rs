@@ -0,0 +1,76 @@
+/// Author: Tomasz Kulik
+/// 
+///
+
+use crate::config::HeartbeatConfig;
+use crate::listener::{ConnectedUser, Peer};
+use crate::protocol::Protocol;
+use crate::server::{Event, Server};
+use futures::{SinkExt, StreamExt};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::mpsc::Sender;
+use tokio_tungstenite::tungstenite::Message as Frame;
+use tracing::warn;
+
+/// Passes the frames of a single WebSocket connection, e.g. of
+/// a browser dashboard, to the server's connection and back.
+///
+/// Every text frame carries a single JSON request and every
+/// message is pushed as a JSON text frame, see `crate::json`.
+/// The frames are passed as the lines of a connection of the
+/// JSON protocol, so the WebSocket users are handled exactly like
+/// the TCP ones and trade in the same books.
+pub(crate) async fn gateway<S>(
+    stream: S,
+    peer: Peer,
+    user: ConnectedUser,
+    heartbeat: Option<HeartbeatConfig>,
+    event_notification_sender: Sender<Event>,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let websocket = tokio_tungstenite::accept_async(stream).await?;
+    let (mut frames_writer, mut frames) = websocket.split();
+
+    let (local, remote) = tokio::io::duplex(64 * 1024);
+    Server::connect(
+        remote,
+        peer,
+        user,
+        Protocol::Json,
+        heartbeat.as_ref(),
+        &event_notification_sender,
+    )
+    .await?;
+    let (reader, mut writer) = tokio::io::split(local);
+    let mut lines = BufReader::new(reader).lines();
+
+    loop {
+        tokio::select! {
+            frame = frames.next() => match frame {
+                Some(Ok(Frame::Text(text))) => {
+                    // The line breaks outside of the JSON strings
+                    // are just the whitespace.
+                    let line = text.replace(['\r', '\n'], " ");
+                    writer.write_all(format!("{}\n", line).as_bytes()).await?;
+                }
+                // The pings are answered by the WebSocket itself,
+                // they keep the connection open like the heartbeats.
+                Some(Ok(Frame::Ping(_))) | Some(Ok(Frame::Pong(_))) => {
+                    writer.write_all(b"{\"type\":\"heartbeat\"}\n").await?;
+                }
+                Some(Ok(Frame::Close(_))) | None => break,
+                Some(Ok(_)) => warn!(%peer, "Unsupported WebSocket frame"),
+                Some(Err(e)) => return Err(e.into()),
+            },
+            line = lines.next_line() => match line? {
+                Some(line) => frames_writer.send(Frame::text(line)).await?,
+                // Disconnected by the server.
+                None => break,
+            },
+        }
+    }
+    let _ = frames_writer.close().await;
+    Ok(())
+}